@@ -0,0 +1,378 @@
+//! Audit log of the processes spawned by remote peers.
+//!
+//! Records are written in JSON lines to the file and/or the syslog socket configured in the
+//! `[audit]` section of the config file:
+//!
+//! ```toml
+//! [audit]
+//! file = "/var/log/rsrs/audit.jsonl"
+//! syslog = "/dev/log"
+//! ```
+
+use crate::{
+    config::{self, AuditConfig},
+    prelude::*,
+    protocol, Error,
+};
+use once_cell::sync::OnceCell;
+use std::{
+    env,
+    ffi::OsString,
+    fmt,
+    fs::OpenOptions,
+    io::Write as _,
+    os::unix::{fs::OpenOptionsExt as _, net::UnixDatagram},
+    pin::Pin,
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::mpsc, task, time};
+
+/// syslog priority of the records (`LOG_AUTH | LOG_INFO`)
+const SYSLOG_PRIORITY: u8 = 4 * 8 + 6;
+
+/// Maximum time to wait for the output of the exited process to be read
+const OUTPUT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+static PEER: OnceCell<String> = OnceCell::new();
+
+/// Sets the identity of the peer of this process.
+pub(crate) fn set_peer(peer: String) {
+    let _ = PEER.set(peer);
+}
+
+/// Returns the identity of the peer of this process.
+///
+/// The client of `rsrs remote` is identified by the certificate of the TLS connection or
+/// `SSH_CONNECTION` set by sshd.
+pub(crate) fn peer() -> &'static str {
+    PEER.get_or_init(|| match env::var("SSH_CONNECTION") {
+        Ok(conn) => {
+            let mut addr = conn.split_whitespace();
+            match (addr.next(), addr.next()) {
+                (Some(host), Some(port)) => format!("ssh:{}:{}", host, port),
+                _ => format!("ssh:{}", conn),
+            }
+        }
+        Err(_) => "local".into(),
+    })
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Record<'a> {
+    /// Seconds since the UNIX epoch
+    time: f64,
+    peer: &'a str,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    SetEnv {
+        env: &'a [String],
+    },
+    Spawn {
+        session: &'a str,
+        command: &'a Command,
+        env: &'a [String],
+        pty: bool,
+    },
+    SpawnFailed {
+        session: &'a str,
+        command: &'a Command,
+        env: &'a [String],
+        pty: bool,
+        error: String,
+    },
+    Exit {
+        session: &'a str,
+        duration_secs: f64,
+        status: Option<protocol::ExitStatus>,
+        bytes_in: u64,
+        bytes_out: u64,
+    },
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Command {
+    LoginShell,
+    Program { argv: Vec<String> },
+    Subsystem { name: String },
+}
+
+impl From<&protocol::SpawnCommand> for Command {
+    fn from(command: &protocol::SpawnCommand) -> Self {
+        match command {
+            protocol::SpawnCommand::LoginShell => Command::LoginShell,
+            protocol::SpawnCommand::Program(program, args) => Command::Program {
+                argv: std::iter::once(program)
+                    .chain(args)
+                    .map(|arg| arg.to_string_lossy().into_owned())
+                    .collect(),
+            },
+            protocol::SpawnCommand::Subsystem(name) => Command::Subsystem { name: name.clone() },
+        }
+    }
+}
+
+fn env_strings(env_vars: &[(OsString, OsString)]) -> Vec<String> {
+    env_vars
+        .iter()
+        .map(|(key, value)| format!("{}={}", key.to_string_lossy(), value.to_string_lossy()))
+        .collect()
+}
+
+fn write(peer: &str, event: Event<'_>) {
+    let config = &config::get().audit;
+    if config.file.is_none() && config.syslog.is_none() {
+        return;
+    }
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let line = match serde_json::to_string(&Record { time, peer, event }) {
+        Ok(line) => line,
+        Err(e) => {
+            warn!(error = %e, "failed to serialize audit record");
+            return;
+        }
+    };
+
+    // the records are written on a blocking thread, since the file and the socket may be slow
+    task::spawn_blocking(move || write_line(config, &line));
+}
+
+fn write_line(config: &AuditConfig, line: &str) {
+    if let Some(path) = &config.file {
+        // the file is opened for each record, so that it can be rotated by other tools
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut file| file.write_all(format!("{}\n", line).as_bytes()));
+        if let Err(e) = res {
+            warn!(error = %e, path = %path.display(), "failed to write audit record");
+        }
+    }
+    if let Some(path) = &config.syslog {
+        let message = format!("<{}>rsrs[{}]: {}", SYSLOG_PRIORITY, process::id(), line);
+        let res =
+            UnixDatagram::unbound().and_then(|socket| socket.send_to(message.as_bytes(), path));
+        if let Err(e) = res {
+            warn!(error = %e, path = %path.display(), "failed to send audit record to syslog");
+        }
+    }
+}
+
+/// Records the environment variables set by the peer.
+pub(crate) fn set_env(peer: &str, env_vars: &[(OsString, OsString)]) {
+    write(
+        peer,
+        Event::SetEnv {
+            env: &env_strings(env_vars),
+        },
+    );
+}
+
+/// Spawn request from the peer.
+#[derive(Debug)]
+pub(crate) struct Spawn {
+    peer: String,
+    session: String,
+    command: Command,
+    env: Vec<String>,
+    pty: bool,
+}
+
+impl Spawn {
+    /// Keeps the request as it is received, before the policy is applied.
+    ///
+    /// The command is recorded as it is received unless `authorized` is called.
+    pub(crate) fn new(
+        peer: impl Into<String>,
+        session: impl fmt::Display,
+        command: &protocol::SpawnCommand,
+        env_vars: &[(OsString, OsString)],
+        pty: bool,
+    ) -> Self {
+        Self {
+            peer: peer.into(),
+            session: session.to_string(),
+            command: command.into(),
+            env: env_strings(env_vars),
+            pty,
+        }
+    }
+
+    /// Replaces the requested command with the one authorized by the policy (e.g. `force_command`).
+    pub(crate) fn authorized(&mut self, command: &protocol::SpawnCommand) {
+        self.command = command.into();
+    }
+
+    /// Records that the request is denied or the process cannot be spawned.
+    pub(crate) fn failed(self, error: &Error) {
+        write(
+            &self.peer,
+            Event::SpawnFailed {
+                session: &self.session,
+                command: &self.command,
+                env: &self.env,
+                pty: self.pty,
+                error: format!("{:#}", error),
+            },
+        );
+    }
+
+    /// Records that the process is spawned.
+    pub(crate) fn started(self) -> Session {
+        write(
+            &self.peer,
+            Event::Spawn {
+                session: &self.session,
+                command: &self.command,
+                env: &self.env,
+                pty: self.pty,
+            },
+        );
+        let (output_open_tx, output_open_rx) = mpsc::channel(1);
+        Session {
+            peer: self.peer,
+            session: self.session,
+            started_at: Instant::now(),
+            bytes_in: Counter::default(),
+            bytes_out: Counter::default(),
+            output_open_tx,
+            output_open_rx,
+        }
+    }
+}
+
+/// Process spawned by the peer.
+#[derive(Debug)]
+pub(crate) struct Session {
+    peer: String,
+    session: String,
+    started_at: Instant,
+    bytes_in: Counter,
+    bytes_out: Counter,
+    /// Closed when all the output streams are dropped
+    output_open_tx: mpsc::Sender<()>,
+    output_open_rx: mpsc::Receiver<()>,
+}
+
+impl Session {
+    /// Counts the bytes written to the process.
+    pub(crate) fn count_input<W>(&self, writer: W) -> Counted<W> {
+        Counted {
+            inner: writer,
+            counter: self.bytes_in.clone(),
+            _open: None,
+        }
+    }
+
+    /// Counts the bytes read from the process.
+    pub(crate) fn count_output<R>(&self, reader: R) -> Counted<R> {
+        Counted {
+            inner: reader,
+            counter: self.bytes_out.clone(),
+            _open: Some(self.output_open_tx.clone()),
+        }
+    }
+
+    /// Records that the process exited.
+    ///
+    /// The record is written after the output of the process is read, so that all bytes are counted.
+    pub(crate) async fn exited(self, status: Option<protocol::ExitStatus>) {
+        let duration = self.started_at.elapsed();
+        let Self {
+            peer,
+            session,
+            bytes_in,
+            bytes_out,
+            output_open_tx,
+            mut output_open_rx,
+            ..
+        } = self;
+        drop(output_open_tx);
+        let _ = time::timeout(OUTPUT_CLOSE_TIMEOUT, output_open_rx.recv()).await;
+
+        write(
+            &peer,
+            Event::Exit {
+                session: &session,
+                duration_secs: duration.as_secs_f64(),
+                status,
+                bytes_in: bytes_in.get(),
+                bytes_out: bytes_out.get(),
+            },
+        );
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    fn add(&self, n: usize) {
+        let _ = self.0.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Stream which counts the bytes passing through it.
+#[derive(Debug)]
+pub(crate) struct Counted<T> {
+    inner: T,
+    counter: Counter,
+    _open: Option<mpsc::Sender<()>>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.counter.add(n);
+        }
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.counter.add(n);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,36 @@
+use super::GlobalOpts;
+use crate::{
+    common, config,
+    prelude::*,
+    protocol::cli::{self, Request, Response},
+    Result,
+};
+
+/// Close the connection to a node and terminate its daemon
+#[derive(Debug, clap::Clap)]
+pub(super) struct Opts {
+    /// Node name or host alias
+    #[clap(name = "node")]
+    node: String,
+}
+
+#[tracing::instrument(skip(global, local), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+pub(super) async fn run(global: GlobalOpts, local: Opts) -> Result<()> {
+    let mut stream = global.connect_root_daemon().await?;
+    let (in_stream, out_stream) = stream.split();
+    let mut writer = common::new_writer::<Request, _>(out_stream);
+    let mut reader = common::new_reader::<Response, _>(in_stream);
+
+    let (node, _) = config::get().resolve_node(&local.node);
+    writer
+        .send(Request::Close(cli::Close {
+            node: node.to_owned().into(),
+        }))
+        .await?;
+    super::recv_ok(&mut reader).await?;
+
+    debug!("close completed");
+
+    Ok(())
+}
@@ -1,17 +1,36 @@
 use super::GlobalOpts;
-use crate::{daemon, prelude::*, Result};
+use crate::{config, daemon, metrics, prelude::*, Result};
 
 /// Launch RSRS daemon
 #[derive(Debug, clap::Clap)]
 pub(super) struct Opts {
     #[clap(long = "as-leaf")]
     is_leaf: bool,
+    /// Serve metrics in the Prometheus text format at `http://<addr>/metrics`
+    #[clap(name = "metrics-addr", long)]
+    metrics_addr: Option<String>,
+}
+
+impl Opts {
+    pub(super) fn is_leaf(&self) -> bool {
+        self.is_leaf
+    }
+
+    /// `metrics_addr` in the config file only applies to the root daemon.
+    fn metrics_addr(&self) -> Option<&str> {
+        self.metrics_addr
+            .as_deref()
+            .or_else(|| (!self.is_leaf).then(|| config::get().metrics_addr.as_deref())?)
+    }
 }
 
 #[tracing::instrument(skip(global, local), err)]
 #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
 pub(super) async fn run(global: GlobalOpts, local: Opts) -> Result<()> {
     let sock_path = global.sock_path(local.is_leaf);
+    if let Some(addr) = local.metrics_addr() {
+        metrics::serve(addr)?;
+    }
 
     daemon::run(sock_path, local.is_leaf)
         .await
@@ -0,0 +1,65 @@
+use super::GlobalOpts;
+use crate::{
+    common,
+    prelude::*,
+    protocol::cli::{Event, Request, Response},
+    Result,
+};
+
+/// Print events of the daemon network as they happen
+#[derive(Debug, clap::Clap)]
+pub(super) struct Opts {
+    /// Output in JSON lines format
+    #[clap(name = "json", long)]
+    json: bool,
+}
+
+#[tracing::instrument(skip(global, local), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+pub(super) async fn run(global: GlobalOpts, local: Opts) -> Result<()> {
+    let mut stream = global.connect_root_daemon().await?;
+    let (in_stream, out_stream) = stream.split();
+    let mut writer = common::new_writer::<Request, _>(out_stream);
+    let mut reader = common::new_reader::<Response, _>(in_stream);
+
+    writer.send(Request::Subscribe).await?;
+    super::recv_ok(&mut reader).await?;
+
+    while let Some(res) = reader.next().await.transpose()? {
+        let event = match res {
+            Response::Event(event) => event,
+            Response::Err(msg) => bail!("error received from server: {}", msg),
+            res => bail!("unexpected response received: resp = {:?}", res),
+        };
+        if local.json {
+            println!("{}", serde_json::to_string(&event)?);
+        } else {
+            println!("{}", format_event(&event));
+        }
+    }
+
+    Ok(())
+}
+
+fn format_event(event: &Event) -> String {
+    match event {
+        Event::NodeConnecting { name } => format!("node-connecting {}", name),
+        Event::NodeConnected(info) => match &info.parent {
+            Some(parent) => format!("node-connected {} parent={}", info.name, parent),
+            None => format!("node-connected {}", info.name),
+        },
+        Event::NodeLost { name } => format!("node-lost {}", name),
+        Event::NodeRemoved { name } => format!("node-removed {}", name),
+        Event::SessionStarted { node, session } => {
+            format!("session-started {} session={:016x}", node, session)
+        }
+        Event::SessionExited {
+            node,
+            session,
+            status,
+        } => format!(
+            "session-exited {} session={:016x} status={:?}",
+            node, session, status
+        ),
+    }
+}
@@ -0,0 +1,160 @@
+use super::GlobalOpts;
+use crate::{
+    common, config,
+    prelude::*,
+    protocol::{
+        self,
+        cli::{self, Request, Response},
+        network::NodeName,
+    },
+    terminal::{self, RawMode},
+    Result,
+};
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    libc, unistd,
+};
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    os::unix::io::RawFd,
+    process,
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Execute a command on a node connected to the daemon
+#[derive(Debug, clap::Clap)]
+pub(super) struct Opts {
+    /// Force pseudo-terminal allocation.
+    #[clap(name = "force-enable-pty", short = 't')]
+    force_enable_pty: bool,
+    /// Node name or host alias
+    #[clap(name = "node")]
+    node: String,
+    /// Command to execute on the node
+    #[clap(name = "command", required = true)]
+    command: Vec<OsString>,
+}
+
+pub(super) async fn run(global: GlobalOpts, opts: Opts) -> Result<()> {
+    let allocate_pty = opts.force_enable_pty && unistd::isatty(libc::STDIN_FILENO)?;
+    let command =
+        protocol::SpawnCommand::Program(opts.command[0].clone(), opts.command[1..].into());
+    let status = run_session(&global, opts.node.into(), command, allocate_pty).await?;
+    process::exit(exit_code(status));
+}
+
+/// Returns the exit code of this process which reports the exit status of the remote process.
+pub(super) fn exit_code(status: protocol::ExitStatus) -> i32 {
+    match status {
+        protocol::ExitStatus::Code(code) => code,
+        protocol::ExitStatus::Signal(signal) => 128 + signal,
+    }
+}
+
+/// Runs the command on the node through the daemon, and relays the stdio of this process.
+pub(super) async fn run_session(
+    global: &GlobalOpts,
+    node: NodeName,
+    command: protocol::SpawnCommand,
+    allocate_pty: bool,
+) -> Result<protocol::ExitStatus> {
+    let (node, host) = config::get().resolve_node(&node);
+    let node = NodeName::from(node.to_owned());
+
+    let has_local_tty = unistd::isatty(libc::STDIN_FILENO)?;
+    let mut env_vars = vec![];
+    for key in host.iter().flat_map(|host| &host.env) {
+        if let Some(value) = env::var_os(key) {
+            env_vars.push((key.into(), value));
+        }
+    }
+    let pty = if allocate_pty {
+        if let Some(term) = env::var_os("TERM") {
+            env_vars.push((OsStr::new("TERM").to_owned(), term));
+        }
+        let (width, height) = if has_local_tty {
+            terminal::get_window_size(libc::STDIN_FILENO)?
+        } else {
+            (80, 24)
+        };
+        Some(protocol::PtyParam { width, height })
+    } else {
+        None
+    };
+
+    let mut stream = global.connect_root_daemon().await?;
+    let (in_stream, out_stream) = stream.split();
+    let mut writer = common::new_writer::<Request, _>(out_stream);
+    let mut reader = common::new_reader::<Response, _>(in_stream);
+
+    // the daemon makes our stdio non-blocking
+    let _flags =
+        FileStatusFlags::save(&[libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO])?;
+
+    trace!("sending exec request");
+    writer
+        .send(Request::Exec(cli::Exec {
+            node,
+            command,
+            env_vars,
+            pty,
+        }))
+        .await?;
+    super::recv_ok(&mut reader).await?;
+
+    super::send_fd("stdin", &std::io::stdin(), &mut reader, &mut writer).await?;
+    super::send_fd("stdout", &std::io::stdout(), &mut reader, &mut writer).await?;
+    super::send_fd("stderr", &std::io::stderr(), &mut reader, &mut writer).await?;
+
+    trace!("waiting for the process to be spawned");
+    super::recv_ok(&mut reader).await?;
+
+    let mut raw = RawMode::new(libc::STDIN_FILENO);
+    if allocate_pty && has_local_tty {
+        trace!("entering raw mode");
+        let _ = raw.enter()?;
+    }
+
+    let mut window_change = signal(SignalKind::window_change())?;
+    let status = loop {
+        tokio::select! {
+            Some(()) = window_change.next(), if allocate_pty && has_local_tty => {
+                let (width, height) = terminal::get_window_size(libc::STDIN_FILENO)?;
+                writer.send(Request::WindowSizeChange(width, height)).await?;
+            }
+            res = reader.next() => match res.transpose()? {
+                Some(Response::Exit(status)) => break status,
+                Some(Response::Err(msg)) => bail!("error received from server: {}", msg),
+                Some(res) => bail!("unexpected response received: resp = {:?}", res),
+                None => bail!("connection closed by server"),
+            },
+        }
+    };
+
+    let _ = raw.leave()?;
+    debug!(?status, "remote process exited");
+    Ok(status)
+}
+
+/// Restores file status flags of the file descriptors on drop.
+#[derive(Debug)]
+struct FileStatusFlags(Vec<(RawFd, OFlag)>);
+
+impl FileStatusFlags {
+    fn save(fds: &[RawFd]) -> Result<Self> {
+        let flags = fds
+            .iter()
+            .map(|&fd| Ok((fd, OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?))))
+            .collect::<nix::Result<_>>()?;
+        Ok(Self(flags))
+    }
+}
+
+impl Drop for FileStatusFlags {
+    fn drop(&mut self) {
+        for (fd, flags) in &self.0 {
+            let _ = fcntl(*fd, FcntlArg::F_SETFL(*flags));
+        }
+    }
+}
@@ -0,0 +1,392 @@
+use super::GlobalOpts;
+use crate::{
+    common, config, metrics,
+    prelude::*,
+    protocol::{
+        self,
+        fs::{DirEntry, FileKind, Op, Reply, Stat},
+    },
+    router,
+    transport::Transport,
+    Error, Result,
+};
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+use tokio::fs::{File, OpenOptions};
+
+/// Size of data transferred by a single read/write request.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Operate files on a remote machine
+#[derive(Debug, clap::Clap)]
+pub(super) struct Opts {
+    /// Remote machine: a transport URL (e.g. `ssh://host`, `docker://container`) or a host alias
+    /// defined in the config file whose command is a transport URL
+    #[clap(name = "destination")]
+    destination: String,
+    #[clap(subcommand)]
+    op: FsCommand,
+}
+
+#[derive(Debug, clap::Clap)]
+enum FsCommand {
+    /// List directory contents
+    Ls {
+        /// Use a long listing format
+        #[clap(short = 'l')]
+        long: bool,
+        /// Files or directories to list
+        #[clap(name = "path", default_value = ".")]
+        paths: Vec<PathBuf>,
+    },
+    /// Print file contents to stdout
+    Cat {
+        /// Files to print
+        #[clap(name = "path", required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Upload a local file
+    Put {
+        /// Local file to upload
+        #[clap(name = "local-path")]
+        local_path: PathBuf,
+        /// Destination path on the remote machine
+        #[clap(name = "remote-path")]
+        remote_path: PathBuf,
+    },
+    /// Remove files
+    Rm {
+        /// Files to remove
+        #[clap(name = "path", required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Rename a file
+    Mv {
+        /// Source path
+        #[clap(name = "from")]
+        from: PathBuf,
+        /// Destination path
+        #[clap(name = "to")]
+        to: PathBuf,
+    },
+    /// Display file status
+    Stat {
+        /// Files to display
+        #[clap(name = "path", required = true)]
+        paths: Vec<PathBuf>,
+    },
+}
+
+pub(super) async fn run(_: GlobalOpts, opts: Opts) -> Result<()> {
+    let (transport, bootstrap) = resolve_destination(&opts.destination)?;
+    let remote = super::spawn_remote(Some(&transport), bootstrap).await?;
+
+    let remote_stdin = remote.stdin;
+    let remote_stdout = remote.stdout;
+    let mut remote_stderr = remote.stderr;
+
+    let reader = common::new_reader(remote_stdout).err_into::<Error>();
+    let writer = common::new_writer(remote_stdin).sink_map_err(Error::from);
+
+    let _router = router::spawn(protocol::ProcessKind::Local, reader, writer);
+    let mut handler_tx = router::lock().handler_tx();
+
+    // logs of the remote endpoint are received by the router, so the rest is copied as it is
+    let mut local_stderr = OpenOptions::new().write(true).open("/dev/stderr").await?;
+    tokio::spawn(async move {
+        if let Err(e) = io::copy(&mut remote_stderr, &mut local_stderr).await {
+            debug!(error = %e, "failed to copy stderr of remote endpoint");
+        }
+    });
+
+    let mut client = Client {
+        handler_tx: handler_tx.clone(),
+    };
+    let res = match opts.op {
+        FsCommand::Ls { long, paths } => ls(&mut client, long, &paths).await,
+        FsCommand::Cat { paths } => cat(&mut client, &paths).await,
+        FsCommand::Put {
+            local_path,
+            remote_path,
+        } => put(&mut client, &local_path, &remote_path).await,
+        FsCommand::Rm { paths } => rm(&mut client, &paths).await,
+        FsCommand::Mv { from, to } => client
+            .rename(&from, &to)
+            .await
+            .wrap_err_with(|| format!("cannot move {} to {}", from.display(), to.display())),
+        FsCommand::Stat { paths } => stat(&mut client, &paths).await,
+    };
+
+    handler_tx
+        .send(protocol::Command::Send(protocol::RemoteCommand::Exit))
+        .map_err(|_| eyre!("send failed"))
+        .await?;
+    handler_tx
+        .send(protocol::Command::Recv(protocol::RemoteCommand::Exit))
+        .map_err(|_| eyre!("send failed"))
+        .await?;
+
+    if let Some(child) = remote.child {
+        let status = child.await?;
+        debug!(status = ?protocol::ExitStatus::from(status), "local process exited");
+    }
+
+    res
+}
+
+/// Returns the transport to the destination, and whether rsrs should be installed through it.
+fn resolve_destination(destination: &str) -> Result<(Transport, bool)> {
+    if let Some(transport) = Transport::parse(destination)? {
+        return Ok((transport, false));
+    }
+    let host = config::get()
+        .hosts
+        .get(destination)
+        .ok_or_else(|| eyre!("not a transport URL or a host alias: {}", destination))?;
+    match &host.command[..] {
+        [url] => match Transport::parse(url)? {
+            Some(transport) => Ok((transport, host.bootstrap)),
+            None => bail!("command of host {} is not a transport URL", destination),
+        },
+        _ => bail!("command of host {} is not a transport URL", destination),
+    }
+}
+
+async fn ls(client: &mut Client, long: bool, paths: &[PathBuf]) -> Result<()> {
+    for (i, path) in paths.iter().enumerate() {
+        let stat = client
+            .stat(path)
+            .await
+            .wrap_err_with(|| format!("cannot access {}", path.display()))?;
+        if stat.kind != FileKind::Dir {
+            print_entry(long, path.as_os_str(), &stat);
+            continue;
+        }
+
+        let mut entries = client
+            .read_dir(path)
+            .await
+            .wrap_err_with(|| format!("cannot open directory {}", path.display()))?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        if paths.len() > 1 {
+            if i > 0 {
+                println!();
+            }
+            println!("{}:", path.display());
+        }
+        for entry in &entries {
+            print_entry(long, &entry.name, &entry.stat);
+        }
+    }
+    Ok(())
+}
+
+fn print_entry(long: bool, name: &OsStr, stat: &Stat) {
+    let name = Path::new(name).display();
+    if !long {
+        println!("{}", name);
+        return;
+    }
+    println!(
+        "{} {:>3} {:>5} {:>5} {:>10} {}",
+        format_mode(stat),
+        stat.nlink,
+        stat.uid,
+        stat.gid,
+        stat.size,
+        name
+    );
+}
+
+fn format_mode(stat: &Stat) -> String {
+    let kind = match stat.kind {
+        FileKind::File => '-',
+        FileKind::Dir => 'd',
+        FileKind::Symlink => 'l',
+        FileKind::CharDevice => 'c',
+        FileKind::BlockDevice => 'b',
+        FileKind::Fifo => 'p',
+        FileKind::Socket => 's',
+    };
+    let mut mode = String::with_capacity(10);
+    mode.push(kind);
+    for (i, ch) in "rwxrwxrwx".chars().enumerate() {
+        mode.push(if stat.mode & (0o400 >> i) != 0 {
+            ch
+        } else {
+            '-'
+        });
+    }
+    mode
+}
+
+async fn cat(client: &mut Client, paths: &[PathBuf]) -> Result<()> {
+    let mut stdout = File::create("/dev/stdout").await?;
+    for path in paths {
+        let mut offset = 0;
+        loop {
+            let data = client
+                .read(path, offset, CHUNK_SIZE as u64)
+                .await
+                .wrap_err_with(|| format!("cannot read {}", path.display()))?;
+            if data.is_empty() {
+                break;
+            }
+            offset += data.len() as u64;
+            stdout.write_all(&data).await?;
+        }
+    }
+    stdout.flush().await?;
+    Ok(())
+}
+
+async fn put(client: &mut Client, local_path: &Path, remote_path: &Path) -> Result<()> {
+    let mut file = File::open(local_path)
+        .await
+        .wrap_err_with(|| format!("cannot open {}", local_path.display()))?;
+    let context = || format!("cannot write {}", remote_path.display());
+
+    // create or truncate the destination file first
+    client
+        .write(remote_path, 0, vec![], true)
+        .await
+        .wrap_err_with(context)?;
+
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        client
+            .write(remote_path, offset, buf[..n].to_vec(), false)
+            .await
+            .wrap_err_with(context)?;
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+async fn rm(client: &mut Client, paths: &[PathBuf]) -> Result<()> {
+    for path in paths {
+        client
+            .unlink(path)
+            .await
+            .wrap_err_with(|| format!("cannot remove {}", path.display()))?;
+    }
+    Ok(())
+}
+
+async fn stat(client: &mut Client, paths: &[PathBuf]) -> Result<()> {
+    for path in paths {
+        let stat = client
+            .stat(path)
+            .await
+            .wrap_err_with(|| format!("cannot stat {}", path.display()))?;
+        println!("  File: {}", path.display());
+        println!("  Size: {:<10} Type: {:?}", stat.size, stat.kind);
+        println!(
+            "Access: ({:04o}/{})  Uid: {:>5}  Gid: {:>5}",
+            stat.mode & 0o7777,
+            format_mode(&stat),
+            stat.uid,
+            stat.gid
+        );
+        println!(" Links: {:<10} Modify: {}", stat.nlink, stat.mtime);
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Client {
+    handler_tx: metrics::Sender<protocol::Command>,
+}
+
+impl Client {
+    async fn request(&mut self, op: Op) -> Result<Reply> {
+        let (id, res_rx) = {
+            // scope for lock guard
+            let mut router = router::lock();
+            let id = router.new_id();
+            let res_rx = router
+                .insert_fs_notifier(id)
+                .ok_or_else(|| eyre!("request id already used: {}", id))?;
+            (id, res_rx)
+        }; // lock ends here
+        self.handler_tx
+            .send(protocol::Command::Send(protocol::RemoteCommand::FsRequest(
+                protocol::fs::Request { id, op },
+            )))
+            .map_err(|_| eyre!("send failed"))
+            .await?;
+        let res = res_rx.await?;
+        res.result.map_err(|e| eyre!(e.message))
+    }
+
+    async fn stat(&mut self, path: &Path) -> Result<Stat> {
+        match self.request(Op::Stat(path.into())).await? {
+            Reply::Stat(stat) => Ok(stat),
+            reply => bail!("unexpected reply: {:?}", reply),
+        }
+    }
+
+    async fn read_dir(&mut self, path: &Path) -> Result<Vec<DirEntry>> {
+        match self.request(Op::ReadDir(path.into())).await? {
+            Reply::ReadDir(entries) => Ok(entries),
+            reply => bail!("unexpected reply: {:?}", reply),
+        }
+    }
+
+    async fn read(&mut self, path: &Path, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let op = Op::Read {
+            path: path.into(),
+            offset,
+            len,
+        };
+        match self.request(op).await? {
+            Reply::Read(data) => Ok(data),
+            reply => bail!("unexpected reply: {:?}", reply),
+        }
+    }
+
+    async fn write(
+        &mut self,
+        path: &Path,
+        offset: u64,
+        data: Vec<u8>,
+        truncate: bool,
+    ) -> Result<u64> {
+        let op = Op::Write {
+            path: path.into(),
+            offset,
+            data,
+            truncate,
+        };
+        match self.request(op).await? {
+            Reply::Write(n) => Ok(n),
+            reply => bail!("unexpected reply: {:?}", reply),
+        }
+    }
+
+    async fn rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        let op = Op::Rename {
+            from: from.into(),
+            to: to.into(),
+        };
+        match self.request(op).await? {
+            Reply::Done => Ok(()),
+            reply => bail!("unexpected reply: {:?}", reply),
+        }
+    }
+
+    async fn unlink(&mut self, path: &Path) -> Result<()> {
+        match self.request(Op::Unlink(path.into())).await? {
+            Reply::Done => Ok(()),
+            reply => bail!("unexpected reply: {:?}", reply),
+        }
+    }
+}
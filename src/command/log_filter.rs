@@ -0,0 +1,33 @@
+use super::GlobalOpts;
+use crate::{
+    common,
+    prelude::*,
+    protocol::cli::{self, Request, Response},
+    Result,
+};
+
+/// Change the log filter of the running daemon
+#[derive(Debug, clap::Clap)]
+pub(super) struct Opts {
+    /// Log directive (e.g. `rsrs=debug`)
+    #[clap(name = "DIRECTIVE")]
+    directive: String,
+}
+
+#[tracing::instrument(skip(global, local), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+pub(super) async fn run(global: GlobalOpts, local: Opts) -> Result<()> {
+    let mut stream = global.connect_root_daemon().await?;
+    let (in_stream, out_stream) = stream.split();
+    let mut writer = common::new_writer::<Request, _>(out_stream);
+    let mut reader = common::new_reader::<Response, _>(in_stream);
+
+    writer
+        .send(Request::SetLogFilter(cli::SetLogFilter {
+            directive: local.directive,
+        }))
+        .await?;
+    super::recv_ok(&mut reader).await?;
+
+    Ok(())
+}
@@ -2,8 +2,10 @@ use super::GlobalOpts;
 use crate::{
     common,
     prelude::*,
-    protocol, router,
+    protocol::{self, network::NodeName},
+    router,
     terminal::{self, RawMode},
+    transport::Transport,
     Error, Result,
 };
 use nix::{libc, unistd};
@@ -11,14 +13,11 @@ use parking_lot::Mutex;
 use std::{
     env,
     ffi::{OsStr, OsString},
-    panic,
-    process::Stdio,
+    panic, process,
     sync::Arc,
 };
 use tokio::{
-    fs::File,
-    io::BufReader,
-    process::Command,
+    fs::{File, OpenOptions},
     signal::unix::{signal, SignalKind},
 };
 
@@ -49,7 +48,33 @@ pub(super) struct Opts {
     #[clap(name = "no-remote-command", short = 'N')]
     no_remote_command: bool,
 
+    /// Request invocation of a subsystem on the remote system.
+    ///
+    /// Subsystems (e.g. `sftp`) are served by `rsrs remote` itself.
+    /// The subsystem name is specified as the remote command.
+    #[clap(name = "subsystem", short = 's')]
+    subsystem: bool,
+
+    /// Install the rsrs binary on the remote machine if it is not present.
+    #[clap(name = "bootstrap", long)]
+    bootstrap: bool,
+
+    /// Connect to `rsrs remote --listen` on the address (`host:port`) with TLS.
+    ///
+    /// The certificate of the server must be issued for the host name.
+    #[clap(name = "tls", long, conflicts_with = "bootstrap")]
+    tls: Option<String>,
+
+    #[clap(flatten)]
+    certs: super::TlsOpts,
+
     /// Commands to executed on a remote machine.
+    ///
+    /// If the first argument is `@<node>`, the command is executed on the node connected to
+    /// the daemon instead of a new remote machine. A host alias can be used as `<node>`.
+    ///
+    /// If the first argument is a transport URL (e.g. `ssh://host`, `docker://container`), the
+    /// remote machine is reached through it.
     #[clap(name = "command")]
     command: Vec<OsString>,
 }
@@ -61,9 +86,38 @@ enum PtyMode {
     Enable,
 }
 
-pub(super) async fn run(_: GlobalOpts, opts: Opts) -> Result<()> {
+pub(super) async fn run(global: GlobalOpts, mut opts: Opts) -> Result<()> {
+    let node = match opts.command.first().and_then(|arg| arg.to_str()) {
+        Some(arg) if arg.starts_with('@') => {
+            let node = NodeName::from(arg[1..].to_owned());
+            let _ = opts.command.remove(0);
+            Some(node)
+        }
+        _ => None,
+    };
+    let transport = match opts.command.first().and_then(|arg| arg.to_str()) {
+        Some(arg) if node.is_none() => Transport::parse(arg)?.map(|t| (arg.to_owned(), t)),
+        _ => None,
+    };
+    let (remote_name, transport) = match transport {
+        Some((url, transport)) => {
+            let _ = opts.command.remove(0);
+            (url, Some(transport))
+        }
+        None => (opts.tls.clone().unwrap_or_else(|| "localhost".into()), None),
+    };
+
     let spawn_command = if opts.no_remote_command {
         None
+    } else if opts.subsystem {
+        ensure!(
+            opts.command.len() == 1,
+            "exactly one subsystem name must be specified"
+        );
+        let name = opts.command[0]
+            .to_str()
+            .ok_or_else(|| eyre!("invalid subsystem name: {:?}", opts.command[0]))?;
+        Some(protocol::SpawnCommand::Subsystem(name.into()))
     } else if opts.command.is_empty() {
         Some(protocol::SpawnCommand::LoginShell)
     } else {
@@ -94,16 +148,28 @@ pub(super) async fn run(_: GlobalOpts, opts: Opts) -> Result<()> {
         allocate_pty = false;
     }
 
-    let exe = env::current_exe()?.canonicalize()?;
-    let mut child = Command::new("ssh")
-        .arg("-T")
-        .arg("localhost")
-        .arg(exe)
-        .arg("remote")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
+    if let Some(node) = node {
+        ensure!(
+            opts.tls.is_none(),
+            "`--tls` cannot be used with a node connected to the daemon"
+        );
+        let command = spawn_command
+            .ok_or_else(|| eyre!("`-N` cannot be used with a node connected to the daemon"))?;
+        let status = super::exec::run_session(&global, node, command, allocate_pty).await?;
+        info!(?status, "remote process exited");
+        process::exit(super::exec::exit_code(status));
+    }
+
+    let remote = match &opts.tls {
+        Some(addr) => {
+            ensure!(
+                transport.is_none(),
+                "`--tls` cannot be used with a transport URL"
+            );
+            super::connect_tls(addr, &opts.certs).await?
+        }
+        None => super::spawn_remote(transport.as_ref(), opts.bootstrap).await?,
+    };
 
     let raw = Arc::new(Mutex::new(RawMode::new(libc::STDIN_FILENO)));
     {
@@ -118,34 +184,28 @@ pub(super) async fn run(_: GlobalOpts, opts: Opts) -> Result<()> {
         }));
     }
 
-    let remote_stdin = child.stdin.take().unwrap();
-    let remote_stdout = child.stdout.take().unwrap();
-    let remote_stderr = child.stderr.take().unwrap();
+    let remote_stdin = remote.stdin;
+    let remote_stdout = remote.stdout;
+    let mut remote_stderr = remote.stderr;
     let local_stdin = File::open("/dev/stdin").await?;
     let local_stdout = File::create("/dev/stdout").await?;
-    let status = child;
 
     let reader = common::new_reader(remote_stdout).err_into::<Error>();
     let writer = common::new_writer(remote_stdin).sink_map_err(Error::from);
 
-    let router = router::spawn(protocol::ProcessKind::Local, reader, writer);
+    // logs of the remote endpoint are emitted in this span
+    let router = tracing::info_span!("remote", node = %remote_name)
+        .in_scope(|| router::spawn(protocol::ProcessKind::Local, reader, writer));
 
     let mut handler_tx = router::lock().handler_tx();
 
-    {
-        let raw = raw.clone();
-        tokio::spawn(async move {
-            let mut lines = BufReader::new(remote_stderr).lines();
-            while let Some(line) = lines.next_line().await.unwrap() {
-                let is_raw_mode = raw.lock().is_raw_mode();
-                if is_raw_mode {
-                    eprintln!("{}\r", line);
-                } else {
-                    eprintln!("{}", line);
-                }
-            }
-        });
-    }
+    // logs of the remote endpoint are received by the router, so the rest is copied as it is
+    let mut local_stderr = OpenOptions::new().write(true).open("/dev/stderr").await?;
+    tokio::spawn(async move {
+        if let Err(e) = io::copy(&mut remote_stderr, &mut local_stderr).await {
+            debug!(error = %e, "failed to copy stderr of remote endpoint");
+        }
+    });
 
     // forward special env vars
     let mut env_vars = vec![];
@@ -168,6 +228,7 @@ pub(super) async fn run(_: GlobalOpts, opts: Opts) -> Result<()> {
         .await?;
 
     // Spawn command
+    let mut spawn_error = None;
     if let Some(command) = spawn_command {
         if has_local_tty && allocate_pty {
             trace!("entering raw mode");
@@ -240,7 +301,10 @@ pub(super) async fn run(_: GlobalOpts, opts: Opts) -> Result<()> {
 
         let remote_status = status_rx.await?;
         raw.lock().leave()?;
-        info!(status = ?remote_status.status, "remote process exited");
+        match remote_status {
+            Ok(status) => info!(status = ?status.status, "remote process exited"),
+            Err(e) => spawn_error = Some(e.message),
+        }
     } else {
         router.await?;
     }
@@ -254,8 +318,14 @@ pub(super) async fn run(_: GlobalOpts, opts: Opts) -> Result<()> {
         .map_err(|_| eyre!("send failed"))
         .await?;
 
-    let status = status.await?;
-    debug!(status = ?protocol::ExitStatus::from(status), "local process exited");
+    if let Some(child) = remote.child {
+        let status = child.await?;
+        debug!(status = ?protocol::ExitStatus::from(status), "local process exited");
+    }
+
+    if let Some(message) = spawn_error {
+        bail!("failed to spawn remote process: {}", message);
+    }
 
     Ok(())
 }
@@ -1,16 +1,50 @@
-use crate::{prelude::*, Result};
+use crate::{
+    common, config,
+    prelude::*,
+    protocol::{
+        self,
+        cli::{Request, Response},
+    },
+    transport::{self, Transport},
+    Result,
+};
 use futures_util::future::BoxFuture;
+use nix::unistd;
+use passfd::tokio_02::FdPassingExt;
 use std::{
     borrow::Cow,
     env,
+    fmt::Debug,
+    os::unix::io::AsRawFd,
     path::{Path, PathBuf},
-    process,
+    process::{self, Stdio},
+    time::Duration,
+};
+use tokio::{
+    fs::OpenOptions,
+    net::{
+        unix::{ReadHalf, WriteHalf},
+        UnixStream,
+    },
+    process::{Child, Command},
+    time,
 };
+use tokio_rustls::webpki::DNSNameRef;
 
+mod close;
 mod daemon;
+mod events;
+mod exec;
+mod fs;
+mod log_filter;
 mod login;
+mod nodes;
 mod open;
 mod remote;
+mod shutdown;
+mod stats;
+
+const DAEMON_LAUNCH_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, clap::Clap)]
 #[clap(name = clap::crate_name!(), version = clap::crate_version!(), author = clap::crate_authors!(), about = clap::crate_description!())]
@@ -23,33 +57,178 @@ pub(super) struct Opts {
 
 impl Opts {
     pub(super) fn log_directive(&self) -> Option<&str> {
-        self.global.log_directive.as_deref()
+        self.global
+            .log_directive
+            .as_deref()
+            .or_else(|| config::get().log.as_deref())
+    }
+
+    pub(super) fn log_format(&self) -> config::LogFormat {
+        self.global
+            .log_format
+            .or_else(|| self.is_root_daemon().then(|| config::get().log_format)?)
+            .unwrap_or_default()
+    }
+
+    pub(super) fn log_file(&self) -> Option<&Path> {
+        self.global.log_file.as_deref().or_else(|| {
+            self.is_root_daemon()
+                .then(|| config::get().log_file.as_deref())?
+        })
+    }
+
+    /// The log settings in the config file only apply to the root daemon, since other commands
+    /// are short-lived or print their logs to the client.
+    fn is_root_daemon(&self) -> bool {
+        matches!(&self.sub_command, SubCommand::Daemon(opts) if !opts.is_leaf())
+    }
+
+    pub(super) fn config_path(&self) -> Option<&Path> {
+        self.global.config_path.as_deref()
     }
 }
 
 #[derive(Debug, clap::Clap)]
 struct GlobalOpts {
-    /// Socket path
+    /// Socket path [default: $XDG_RUNTIME_DIR/rsrs/root.sock]
     #[clap(name = "sock-path", long)]
     sock_path: Option<PathBuf>,
     /// Log directive
     #[clap(name = "log", long)]
     log_directive: Option<String>,
+    /// Log format
+    #[clap(name = "log-format", long, possible_values = &["text", "json"])]
+    log_format: Option<config::LogFormat>,
+    /// Write logs to the file instead of stderr. The file is rotated as configured by `log_rotation`
+    #[clap(name = "log-file", long)]
+    log_file: Option<PathBuf>,
+    /// Configuration file [default: ~/.config/rsrs/config.toml]
+    #[clap(name = "config", long)]
+    config_path: Option<PathBuf>,
+}
+
+// Certificates for mutual TLS authentication (not a doc comment, since clap would use it as the
+// description of the subcommands which flatten this)
+#[derive(Debug, clap::Clap)]
+struct TlsOpts {
+    /// Certificate of this host in PEM format [default: ~/.config/rsrs/tls/cert.pem]
+    #[clap(name = "cert", long)]
+    cert: Option<PathBuf>,
+    /// Private key of the certificate in PEM format [default: ~/.config/rsrs/tls/key.pem]
+    #[clap(name = "key", long)]
+    key: Option<PathBuf>,
+    /// CA certificates which verify the peer in PEM format [default: ~/.config/rsrs/tls/ca.pem]
+    #[clap(name = "ca", long)]
+    ca: Option<PathBuf>,
+}
+
+impl TlsOpts {
+    fn files(&self) -> Result<common::TlsFiles> {
+        let default_path = |name: &str| {
+            config::dir()
+                .map(|dir| dir.join("tls").join(name))
+                .ok_or_else(|| eyre!("cannot determine the path of {}", name))
+        };
+        Ok(common::TlsFiles {
+            cert: self
+                .cert
+                .clone()
+                .map_or_else(|| default_path("cert.pem"), Ok)?,
+            key: self
+                .key
+                .clone()
+                .map_or_else(|| default_path("key.pem"), Ok)?,
+            ca: self.ca.clone().map_or_else(|| default_path("ca.pem"), Ok)?,
+        })
+    }
 }
 
 impl GlobalOpts {
-    fn sock_path(&self, is_leaf_daemon: bool) -> Cow<Path> {
+    fn sock_path(&self, is_leaf_daemon: bool) -> Cow<'_, Path> {
         if let Some(path) = &self.sock_path {
             return path.as_path().into();
         }
-        let mut tmp = env::temp_dir();
+        if !is_leaf_daemon {
+            if let Some(path) = &config::get().sock_path {
+                return path.as_path().into();
+            }
+        }
+        let mut path = common::runtime_dir();
         if is_leaf_daemon {
             let pid = process::id();
-            tmp.push(format!("rsrs.{}.sock", pid));
+            path.push(format!("{}.sock", pid));
         } else {
-            tmp.push("rsrs.root.sock");
+            path.push("root.sock");
+        }
+        path.into()
+    }
+}
+
+impl GlobalOpts {
+    /// Connects to the root daemon, launching it if it is not running.
+    #[tracing::instrument(skip(self), err)]
+    #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+    async fn connect_root_daemon(&self) -> Result<UnixStream> {
+        let sock_path = self.sock_path(false);
+        debug!(sock_path = %sock_path.display());
+
+        match UnixStream::connect(&sock_path).await {
+            Ok(stream) => return Ok(stream),
+            Err(e)
+                if e.kind() == io::ErrorKind::NotFound
+                    || e.kind() == io::ErrorKind::ConnectionRefused =>
+            {
+                info!(sock_path = %sock_path.display(), "root daemon is not running. launching");
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let exe = env::current_exe()?;
+        let mut cmd = Command::new(exe);
+        cmd.arg("--sock-path").arg(&*sock_path);
+        if let Some(config_path) = &self.config_path {
+            cmd.arg("--config").arg(config_path);
+        }
+        cmd.arg("daemon")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        unsafe {
+            cmd.pre_exec(|| {
+                // become a session leader to detach controlling terminal
+                unistd::setsid().map_err(common::nix2io)?;
+                Ok(())
+            })
+        };
+        let mut child = cmd.spawn()?;
+
+        let connect = async {
+            loop {
+                if let Ok(stream) = UnixStream::connect(&sock_path).await {
+                    return stream;
+                }
+                time::delay_for(Duration::from_millis(100)).await;
+            }
+        };
+        tokio::select! {
+            stream = connect => {
+                debug!(pid = child.id(), "root daemon launched");
+                Ok(stream)
+            }
+            status = &mut child => {
+                // another client may have launched the daemon at the same time
+                if let Ok(stream) = UnixStream::connect(&sock_path).await {
+                    return Ok(stream);
+                }
+                bail!(
+                    "root daemon exited unexpectedly ({}). run `rsrs daemon` to see the error",
+                    status?
+                )
+            }
+            () = time::delay_for(DAEMON_LAUNCH_TIMEOUT) => {
+                bail!("timed out waiting for the root daemon to accept connections")
+            }
         }
-        tmp.into()
     }
 }
 
@@ -63,6 +242,22 @@ enum SubCommand {
     Remote(remote::Opts),
     #[clap(version = clap::crate_version!(), author = clap::crate_authors!())]
     Daemon(daemon::Opts),
+    #[clap(version = clap::crate_version!(), author = clap::crate_authors!())]
+    Fs(fs::Opts),
+    #[clap(version = clap::crate_version!(), author = clap::crate_authors!())]
+    Nodes(nodes::Opts),
+    #[clap(version = clap::crate_version!(), author = clap::crate_authors!())]
+    Exec(exec::Opts),
+    #[clap(version = clap::crate_version!(), author = clap::crate_authors!())]
+    Close(close::Opts),
+    #[clap(version = clap::crate_version!(), author = clap::crate_authors!())]
+    Shutdown(shutdown::Opts),
+    #[clap(version = clap::crate_version!(), author = clap::crate_authors!())]
+    Events(events::Opts),
+    #[clap(version = clap::crate_version!(), author = clap::crate_authors!())]
+    LogFilter(log_filter::Opts),
+    #[clap(version = clap::crate_version!(), author = clap::crate_authors!())]
+    Stats(stats::Opts),
 }
 
 pub(crate) fn run(opts: Opts) -> BoxFuture<'static, Result<()>> {
@@ -71,5 +266,141 @@ pub(crate) fn run(opts: Opts) -> BoxFuture<'static, Result<()>> {
         SubCommand::Remote(local) => remote::run(opts.global, local).boxed(),
         SubCommand::Open(local) => open::run(opts.global, local).boxed(),
         SubCommand::Daemon(local) => daemon::run(opts.global, local).boxed(),
+        SubCommand::Fs(local) => fs::run(opts.global, local).boxed(),
+        SubCommand::Nodes(local) => nodes::run(opts.global, local).boxed(),
+        SubCommand::Exec(local) => exec::run(opts.global, local).boxed(),
+        SubCommand::Close(local) => close::run(opts.global, local).boxed(),
+        SubCommand::Shutdown(local) => shutdown::run(opts.global, local).boxed(),
+        SubCommand::Events(local) => events::run(opts.global, local).boxed(),
+        SubCommand::LogFilter(local) => log_filter::run(opts.global, local).boxed(),
+        SubCommand::Stats(local) => stats::run(opts.global, local).boxed(),
     }
 }
+
+/// Streams of `rsrs remote` running on a remote machine.
+struct Remote {
+    stdin: Box<dyn AsyncWrite + Send + Unpin>,
+    stdout: Box<dyn AsyncRead + Send + Unpin>,
+    stderr: Box<dyn AsyncRead + Send + Unpin>,
+    /// The transport command, if any
+    child: Option<Child>,
+}
+
+/// Spawns `rsrs remote` on a remote machine.
+///
+/// If `transport` is `None`, `rsrs remote` is spawned with `ssh localhost`.
+/// If `bootstrap` is `true`, the binary is installed on the remote machine before launching it.
+async fn spawn_remote(transport: Option<&Transport>, bootstrap: bool) -> Result<Remote> {
+    let command_line = match transport {
+        Some(Transport::Tcp { addr }) => {
+            ensure!(!bootstrap, "bootstrap is not supported by tcp transport");
+            let socket = transport::connect_tcp(addr).await?;
+            let (reader, writer) = io::split(socket);
+            return Ok(Remote {
+                stdin: Box::new(writer),
+                stdout: Box::new(reader),
+                stderr: Box::new(io::empty()),
+                child: None,
+            });
+        }
+        Some(transport) => transport.command(&["remote"], bootstrap)?,
+        None if bootstrap => vec!["ssh".into(), "-T".into(), "localhost".into(), "sh".into()],
+        None => {
+            let exe = env::current_exe()?.canonicalize()?;
+            vec![
+                "ssh".into(),
+                "-T".into(),
+                "localhost".into(),
+                exe.into(),
+                "remote".into(),
+            ]
+        }
+    };
+    let mut child = Command::new(&command_line[0])
+        .args(&command_line[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut remote = Remote {
+        stdin: Box::new(child.stdin.take().unwrap()),
+        stdout: Box::new(child.stdout.take().unwrap()),
+        stderr: Box::new(child.stderr.take().unwrap()),
+        child: Some(child),
+    };
+    if bootstrap {
+        // `rsrs remote` does not send the magic number, so output before it is written to stderr
+        let mut local_stdout = OpenOptions::new().write(true).open("/dev/stderr").await?;
+        let mut local_stderr = OpenOptions::new().write(true).open("/dev/stderr").await?;
+        let mut output = common::TransportOutput::new(
+            &mut remote.stdout,
+            &mut remote.stderr,
+            &mut local_stdout,
+            &mut local_stderr,
+            protocol::DEFAULT_HANDSHAKE_TIMEOUT,
+        );
+        common::bootstrap(&mut remote.stdin, &mut output, &["remote"]).await?;
+    }
+    Ok(remote)
+}
+
+/// Connects to `rsrs remote --listen` with mutual TLS authentication.
+///
+/// The certificate of the server must be issued for the host name in `addr`.
+async fn connect_tls(addr: &str, tls: &TlsOpts) -> Result<Remote> {
+    let connector = tls.files()?.connector()?;
+    let host = match addr.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => bail!("port is not specified: {}", addr),
+    };
+    let domain = DNSNameRef::try_from_ascii_str(host).map_err(|_| {
+        eyre!(
+            "invalid host name (IP addresses are not supported): {}",
+            host
+        )
+    })?;
+
+    let socket = transport::connect_tcp(addr).await?;
+    let stream = connector
+        .connect(domain, socket)
+        .await
+        .wrap_err_with(|| format!("TLS handshake with {} failed", addr))?;
+    let (reader, writer) = io::split(stream);
+    Ok(Remote {
+        stdin: Box::new(writer),
+        stdout: Box::new(reader),
+        stderr: Box::new(io::empty()),
+        child: None,
+    })
+}
+
+#[tracing::instrument(skip(reader), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn recv_ok(reader: &mut common::FramedRead<Response, ReadHalf<'_>>) -> Result<()> {
+    let resp = reader
+        .next()
+        .await
+        .unwrap_or_else(|| Err(io::Error::from(io::ErrorKind::UnexpectedEof)))?;
+    match resp {
+        Response::Ok => Ok(()),
+        Response::Err(msg) => Err(eyre!("error received from server: {}", msg)),
+        resp => Err(eyre!("unexpected response received: resp = {:?}", resp)),
+    }
+}
+
+#[tracing::instrument(skip(reader, writer, fd), fields(fd = fd.as_raw_fd()), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn send_fd(
+    kind: &str,
+    fd: &(impl AsRawFd + Debug),
+    reader: &mut common::FramedRead<Response, ReadHalf<'_>>,
+    writer: &mut common::FramedWrite<Request, WriteHalf<'_>>,
+) -> Result<()> {
+    let fd = fd.as_raw_fd();
+    trace!("sending file descriptor");
+    writer.get_ref().get_ref().as_ref().send_fd(fd).await?;
+    trace!("waiting response");
+    recv_ok(reader).await?;
+    trace!("completed");
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use super::GlobalOpts;
+use crate::{
+    common,
+    prelude::*,
+    protocol::{
+        cli::{Request, Response},
+        network::{NodeInfo, NodeName, NodeState},
+    },
+    Result,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// List nodes in the daemon network
+#[derive(Debug, clap::Clap)]
+pub(super) struct Opts {
+    /// Output in JSON format
+    #[clap(name = "json", long)]
+    json: bool,
+}
+
+#[tracing::instrument(skip(global, local), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+pub(super) async fn run(global: GlobalOpts, local: Opts) -> Result<()> {
+    let mut stream = global.connect_root_daemon().await?;
+    let (in_stream, out_stream) = stream.split();
+    let mut writer = common::new_writer::<Request, _>(out_stream);
+    let mut reader = common::new_reader::<Response, _>(in_stream);
+
+    writer.send(Request::ListNodes).await?;
+    let nodes = match reader.next().await.transpose()? {
+        Some(Response::Nodes(nodes)) => nodes,
+        Some(Response::Err(msg)) => bail!("error received from server: {}", msg),
+        Some(resp) => bail!("unexpected response received: resp = {:?}", resp),
+        None => bail!("connection closed by server"),
+    };
+
+    let nodes = tree_order(nodes);
+    if local.json {
+        println!("{}", serde_json::to_string_pretty(&nodes)?);
+        return Ok(());
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    println!(
+        "{:<24} {:<24} {:<12} {:>9} {:>7}  {:<16}  COMMAND",
+        "NAME", "PARENT", "STATE", "CONNECTED", "PID", "KEY"
+    );
+    for node in &nodes {
+        let state = match node.state {
+            NodeState::Local => "local",
+            NodeState::Handshake => "handshake",
+            NodeState::Connected => "connected",
+            NodeState::Disconnected => "disconnected",
+        };
+        let connected = node
+            .connected_at
+            .filter(|_| node.state != NodeState::Disconnected)
+            .map(|t| format_duration(Duration::from_secs(now.saturating_sub(t))))
+            .unwrap_or_else(|| "-".into());
+        let pid = node
+            .pid
+            .map(|pid| pid.to_string())
+            .unwrap_or_else(|| "-".into());
+        let key = node
+            .public_key
+            .map(|key| key.fingerprint())
+            .unwrap_or_else(|| "-".into());
+        println!(
+            "{:<24} {:<24} {:<12} {:>9} {:>7}  {:<16}  {}",
+            node.name,
+            node.parent.as_deref().unwrap_or("-"),
+            state,
+            connected,
+            pid,
+            key,
+            node.command.join(" ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Sorts nodes so that every node is followed by its descendants.
+fn tree_order(nodes: Vec<NodeInfo>) -> Vec<NodeInfo> {
+    let names = nodes
+        .iter()
+        .map(|node| node.name.clone())
+        .collect::<HashSet<_>>();
+    let mut children = HashMap::<Option<NodeName>, Vec<NodeInfo>>::new();
+    for node in nodes {
+        // nodes whose parent is unknown are placed at the top level
+        let parent = node.parent.clone().filter(|parent| names.contains(parent));
+        children.entry(parent).or_default().push(node);
+    }
+
+    let mut sorted = vec![];
+    push_subtree(None, &mut children, &mut sorted);
+    sorted
+}
+
+fn push_subtree(
+    parent: Option<NodeName>,
+    children: &mut HashMap<Option<NodeName>, Vec<NodeInfo>>,
+    sorted: &mut Vec<NodeInfo>,
+) {
+    let mut nodes = children.remove(&parent).unwrap_or_default();
+    nodes.sort_by(|a, b| a.name.cmp(&b.name));
+    for node in nodes {
+        let name = node.name.clone();
+        sorted.push(node);
+        push_subtree(Some(name), children, sorted);
+    }
+}
+
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d{:02}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
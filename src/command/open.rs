@@ -1,29 +1,59 @@
 use super::GlobalOpts;
 use crate::{
-    common,
+    common, config,
     prelude::*,
-    protocol,
-    protocol::cli::{self, Request, Response},
+    protocol::{
+        cli::{self, Request, Response},
+        network::{LinkOptions, NodeName},
+    },
+    terminal::RawMode,
+    transport::{self, Transport},
     Result,
 };
-use nix::unistd;
-use passfd::tokio_02::FdPassingExt;
-use std::{ffi::OsString, fmt::Debug, os::unix::io::AsRawFd, process::Stdio};
+use nix::{libc, unistd};
+use std::{
+    ffi::OsString,
+    fmt::Debug,
+    fs, iter,
+    os::unix::{
+        fs::OpenOptionsExt as _,
+        io::{AsRawFd, FromRawFd},
+    },
+    process::Stdio,
+};
 use tokio::{
     fs::File,
-    net::{
-        unix::{ReadHalf, WriteHalf},
-        UnixStream,
-    },
+    net::{TcpStream, UnixStream},
     process::{Child, Command},
     sync::watch,
 };
+use tokio_pty_command::{CommandExt as _, PtyMaster};
 use tracing_futures::Instrument;
 
 /// Launch RSRS daemon
 #[derive(Debug, clap::Clap)]
 pub(super) struct Opts {
-    /// Command to open a remote session
+    /// Name of the node. A generated name is used if omitted
+    #[clap(name = "name", long)]
+    name: Option<String>,
+    /// Run the command on the specified node instead of the local machine
+    #[clap(name = "via", long)]
+    via: Option<String>,
+    /// Re-run the command when the connection to the node is lost
+    #[clap(name = "reconnect", long)]
+    reconnect: bool,
+    /// Seconds to wait for the leaf daemon to start [default: 30]
+    #[clap(name = "handshake-timeout", long)]
+    handshake_timeout: Option<u64>,
+    /// Install the rsrs binary on the remote host if it is not present.
+    ///
+    /// The command must run a POSIX shell which reads commands from stdin (e.g. `ssh -T host sh`).
+    /// The leaf daemon is launched by the shell.
+    #[clap(name = "bootstrap", long)]
+    bootstrap: bool,
+    /// Command to open a remote session, a host alias defined in the config file, or a transport
+    /// URL (`ssh://[user@]host[:port]`, `docker://container`, `k8s://[namespace/]pod[/container]`,
+    /// `exec://` or `tcp://host:port`)
     #[clap(name = "command")]
     command: OsString,
     /// Arguments to command
@@ -33,39 +63,184 @@ pub(super) struct Opts {
 
 #[tracing::instrument(skip(global, local), err)]
 #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
-pub(super) async fn run(global: GlobalOpts, local: Opts) -> Result<()> {
-    let sock_path = global.sock_path(false);
-    debug!(sock_path = %sock_path.display());
+pub(super) async fn run(global: GlobalOpts, mut local: Opts) -> Result<()> {
+    let options = resolve_host(&mut local)?;
+    let transport = match local.command.to_str() {
+        Some(url) if local.args.is_empty() => Transport::parse(url)?,
+        _ => None,
+    };
 
-    let mut stream = UnixStream::connect(&sock_path).await?;
+    let mut stream = global.connect_root_daemon().await?;
     debug!(peer_cred = ?stream.peer_cred(), "connected to server");
 
-    let mut cmd = Command::new(&local.command);
-    cmd.args(&local.args)
+    if let Some(via) = local.via {
+        // the transport URL is resolved on the node
+        return open_via(
+            local.name.map(Into::into),
+            via.into(),
+            local.command,
+            local.args,
+            options,
+            &mut stream,
+        )
+        .await;
+    }
+
+    let command_line = match transport {
+        Some(Transport::Tcp { addr }) => {
+            let (socket, stderr) = connect_tcp(&addr, &options).await?;
+            let fds = (&socket, &socket, &stderr);
+            delegate_fd(local, options, &mut stream, None, fds, None).await?;
+            debug!("open completed");
+            return Ok(());
+        }
+        Some(transport) => transport.command(common::LEAF_DAEMON_ARGS, options.bootstrap)?,
+        None => iter::once(&local.command)
+            .chain(&local.args)
+            .cloned()
+            .collect(),
+    };
+
+    let mut cmd = Command::new(&command_line[0]);
+    cmd.args(&command_line[1..])
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped());
-    unsafe {
-        cmd.pre_exec(|| {
-            // become a session leader to detach controlling terminal
-            unistd::setsid().map_err(common::nix2io)?;
-            Ok(())
-        })
+    // the transport command may ask for passwords on its controlling terminal
+    let pty = if unistd::isatty(libc::STDIN_FILENO)? {
+        let pty = PtyMaster::open()?;
+        let _ = cmd.controlling_terminal(&pty)?;
+        Some(pty)
+    } else {
+        unsafe {
+            cmd.pre_exec(|| {
+                // become a session leader to detach controlling terminal
+                unistd::setsid().map_err(common::nix2io)?;
+                Ok(())
+            })
+        };
+        None
     };
 
     let mut child = cmd.spawn()?;
 
-    launch_remote(&mut child).await?;
-    delegate_fd(local, &mut stream, &mut child).await?;
+    let pty = match launch_remote(&mut child, &options, pty).await {
+        Ok(pty) => pty,
+        Err(e) => {
+            let _ = child.kill();
+            return Err(e);
+        }
+    };
+    let fds = (
+        child.stdin.as_ref().unwrap(),
+        child.stdout.as_ref().unwrap(),
+        child.stderr.as_ref().unwrap(),
+    );
+    delegate_fd(
+        local,
+        options,
+        &mut stream,
+        Some(child.id()),
+        fds,
+        pty.as_ref(),
+    )
+    .await?;
+
+    debug!("open completed");
+
+    Ok(())
+}
+
+/// Replaces the host alias with the transport command defined in the config file.
+///
+/// Options given from the command line take precedence over the config file.
+fn resolve_host(local: &mut Opts) -> Result<LinkOptions> {
+    let mut options = LinkOptions {
+        reconnect: local.reconnect,
+        keepalive: None,
+        bootstrap: local.bootstrap,
+        handshake_timeout_secs: local.handshake_timeout,
+    };
+    if !local.args.is_empty() {
+        return Ok(options);
+    }
+    let (alias, host) = match local
+        .command
+        .to_str()
+        .and_then(|alias| config::get().hosts.get_key_value(alias))
+    {
+        Some(host) => host,
+        None => return Ok(options),
+    };
+    debug!(%alias, ?host, "host alias found");
+
+    ensure!(
+        !host.command.is_empty(),
+        "transport command of host {} is empty",
+        alias
+    );
+
+    local.name = local
+        .name
+        .take()
+        .or_else(|| Some(host.node_name(alias).to_owned()));
+    local.command = host.command[0].clone().into();
+    local.args = host.command[1..].iter().map(Into::into).collect();
+
+    let host_options = host.link_options();
+    options.reconnect |= host_options.reconnect;
+    options.keepalive = host_options.keepalive;
+    options.bootstrap |= host_options.bootstrap;
+    options.handshake_timeout_secs = options
+        .handshake_timeout_secs
+        .or(host_options.handshake_timeout_secs);
+    Ok(options)
+}
+
+#[tracing::instrument(skip(stream), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn open_via(
+    name: Option<NodeName>,
+    via: NodeName,
+    command: OsString,
+    args: Vec<OsString>,
+    options: LinkOptions,
+    stream: &mut UnixStream,
+) -> Result<()> {
+    let (in_stream, out_stream) = stream.split();
+    let mut writer = common::new_writer::<Request, _>(out_stream);
+    let mut reader = common::new_reader::<Response, _>(in_stream);
+
+    trace!("sending open request");
+    writer
+        .send(Request::OpenVia(cli::OpenVia {
+            name,
+            via,
+            command,
+            args,
+            options,
+        }))
+        .await?;
+
+    trace!("waiting response");
+    super::recv_ok(&mut reader).await?;
 
     debug!("open completed");
 
     Ok(())
 }
 
-#[tracing::instrument(skip(child), err)]
+/// Forwards the input and output of the transport command until the leaf daemon starts.
+///
+/// If `pty` is given, the user input is sent to the controlling terminal of the transport command
+/// instead of its stdin, so that the user can answer the prompts of it.
+#[tracing::instrument(skip(child, pty), err)]
 #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
-async fn launch_remote(child: &mut Child) -> Result<()> {
+async fn launch_remote(
+    child: &mut Child,
+    options: &LinkOptions,
+    pty: Option<PtyMaster>,
+) -> Result<Option<PtyMaster>> {
     let mut remote_stdin = child.stdin.take().unwrap();
     let mut remote_stdout = child.stdout.take().unwrap();
     let mut remote_stderr = child.stderr.take().unwrap();
@@ -75,45 +250,133 @@ async fn launch_remote(child: &mut Child) -> Result<()> {
     let mut local_stdout = File::create("/dev/stdout").await?;
     let mut local_stderr = File::create("/dev/stderr").await?;
 
-    let stdin_handler = tokio::spawn({
-        let rx = rx.clone();
-        async move {
-            forward_until_interrupted(&mut local_stdin, &mut remote_stdin, rx)
+    let mut output = common::TransportOutput::new(
+        &mut remote_stdout,
+        &mut remote_stderr,
+        &mut local_stdout,
+        &mut local_stderr,
+        options.handshake_timeout(),
+    );
+    if options.bootstrap {
+        common::bootstrap(&mut remote_stdin, &mut output, common::LEAF_DAEMON_ARGS).await?;
+    }
+
+    let mut raw_mode = RawMode::new(libc::STDIN_FILENO);
+    let mut stdin_handler = None;
+    let mut pty_handlers = None;
+    if let Some(pty) = pty {
+        // input is sent to the terminal as is, and echoed back by it
+        let _ = raw_mode.enter()?;
+        let (mut pty_reader, mut pty_writer) = io::split(pty);
+        let mut local_stdout = File::create("/dev/stdout").await?;
+        let input_handler = tokio::spawn({
+            let rx = rx.clone();
+            async move {
+                let res = forward_until_interrupted(&mut local_stdin, &mut pty_writer, rx)
+                    .instrument(tracing::info_span!("pty_input"))
+                    .await;
+                if let Err(e) = res {
+                    debug!(error = %e, "failed to forward input to terminal");
+                }
+                pty_writer
+            }
+        });
+        let output_handler = tokio::spawn(async move {
+            // reading from the pty master fails with EIO after the transport command exits
+            let res = forward_until_interrupted(&mut pty_reader, &mut local_stdout, rx)
+                .instrument(tracing::info_span!("pty_output"))
+                .await;
+            if let Err(e) = res {
+                debug!(error = %e, "failed to forward output of terminal");
+            }
+            pty_reader
+        });
+        pty_handlers = Some((input_handler, output_handler));
+        child.stdin = Some(remote_stdin);
+    } else {
+        stdin_handler = Some(tokio::spawn(async move {
+            let res = forward_until_interrupted(&mut local_stdin, &mut remote_stdin, rx)
                 .instrument(tracing::info_span!("stdin"))
-                .await
-                .unwrap();
+                .await;
+            if let Err(e) = res {
+                debug!(error = %e, "failed to forward stdin");
+            }
             remote_stdin
-        }
-    });
-    let stderr_handler = tokio::spawn({
-        let rx = rx.clone();
-        async move {
-            forward_until_interrupted(&mut remote_stderr, &mut local_stderr, rx)
-                .instrument(tracing::info_span!("stderr"))
-                .await
-                .unwrap();
-            remote_stderr
-        }
-    });
-    let stdout_handler = tokio::spawn(async move {
-        forward_until_magic(&mut remote_stdout, &mut local_stdout, protocol::MAGIC)
-            .instrument(tracing::info_span!("stdout"))
-            .await
-            .unwrap();
-        remote_stdout
-    });
-
-    child.stdout = Some(stdout_handler.await?);
+        }));
+    }
+
+    let res = output.wait_handshake().await;
     let _ = tx.broadcast(true);
-    child.stdin = Some(stdin_handler.await?);
-    child.stderr = Some(stderr_handler.await?);
+    if let Some(stdin_handler) = stdin_handler {
+        child.stdin = Some(stdin_handler.await?);
+    }
+    let pty = match pty_handlers {
+        Some((input_handler, output_handler)) => {
+            let pty_writer = input_handler.await?;
+            let pty_reader = output_handler.await?;
+            Some(pty_reader.unsplit(pty_writer))
+        }
+        None => None,
+    };
+    let _ = raw_mode.leave()?;
+    res?;
 
-    Ok(())
+    child.stdout = Some(remote_stdout);
+    child.stderr = Some(remote_stderr);
+
+    Ok(pty)
 }
 
-#[tracing::instrument(skip(local, stream, child), err)]
+/// Connects to the leaf daemon served on a TCP socket.
+///
+/// Returns the socket and a stream passed as the stderr of the transport, which is always empty.
+#[tracing::instrument(skip(options), err)]
 #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
-async fn delegate_fd(local: Opts, stream: &mut UnixStream, child: &mut Child) -> Result<()> {
+async fn connect_tcp(addr: &str, options: &LinkOptions) -> Result<(TcpStream, fs::File)> {
+    ensure!(
+        !options.bootstrap,
+        "bootstrap is not supported by tcp transport"
+    );
+    let mut socket = transport::connect_tcp(addr).await?;
+
+    let mut remote_stderr = io::empty();
+    let mut local_stdout = File::create("/dev/stdout").await?;
+    let mut local_stderr = File::create("/dev/stderr").await?;
+    {
+        let (mut remote_stdout, _) = socket.split();
+        common::TransportOutput::new(
+            &mut remote_stdout,
+            &mut remote_stderr,
+            &mut local_stdout,
+            &mut local_stderr,
+            options.handshake_timeout(),
+        )
+        .wait_handshake()
+        .await?;
+    }
+
+    // the daemon requires a pollable file descriptor, so a pipe is used instead of `/dev/null`
+    let (stderr, writer) = unistd::pipe()?;
+    unistd::close(writer)?;
+    let stderr = unsafe { fs::File::from_raw_fd(stderr) };
+
+    Ok((socket, stderr))
+}
+
+#[tracing::instrument(skip(local, stream, fds, pty), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn delegate_fd(
+    local: Opts,
+    options: LinkOptions,
+    stream: &mut UnixStream,
+    pid: Option<u32>,
+    fds: (
+        &(impl AsRawFd + Debug),
+        &(impl AsRawFd + Debug),
+        &(impl AsRawFd + Debug),
+    ),
+    pty: Option<&PtyMaster>,
+) -> Result<()> {
     let (in_stream, out_stream) = stream.split();
     let mut writer = common::new_writer::<Request, _>(out_stream);
     let mut reader = common::new_reader::<Response, _>(in_stream);
@@ -121,66 +384,41 @@ async fn delegate_fd(local: Opts, stream: &mut UnixStream, child: &mut Child) ->
     trace!("sending open request");
     writer
         .send(Request::Open(cli::Open {
-            pid: child.id(),
+            name: local.name.map(Into::into),
+            pid,
             command: local.command,
             args: local.args,
+            options,
+            pty: pty.is_some(),
         }))
         .await?;
 
     trace!("waiting response");
-    recv_ok(&mut reader).await?;
-
-    let stdin = child.stdin.as_ref().unwrap();
-    let stdout = child.stdout.as_ref().unwrap();
-    let stderr = child.stderr.as_ref().unwrap();
+    super::recv_ok(&mut reader).await?;
 
-    send_fd("stdin", stdin, &mut reader, &mut writer).await?;
-    send_fd("stdout", stdout, &mut reader, &mut writer).await?;
-    send_fd("stderr", stderr, &mut reader, &mut writer).await?;
+    let (stdin, stdout, stderr) = fds;
+    super::send_fd("stdin", stdin, &mut reader, &mut writer).await?;
+    super::send_fd("stdout", stdout, &mut reader, &mut writer).await?;
+    super::send_fd("stderr", stderr, &mut reader, &mut writer).await?;
+    if let Some(pty) = pty {
+        super::send_fd("pty", pty, &mut reader, &mut writer).await?;
+        // the daemon keeps the slave open so that reading the master does not fail with EIO
+        let slave = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(pty.slave_name())?;
+        super::send_fd("pty slave", &slave, &mut reader, &mut writer).await?;
+    }
 
     trace!("waiting response");
-    recv_ok(&mut reader).await?;
-
-    child.stdin = None;
-    child.stdout = None;
-    child.stderr = None;
+    super::recv_ok(&mut reader).await?;
 
     trace!("completed");
 
     Ok(())
 }
 
-#[tracing::instrument(skip(reader, writer, fd), fields(fd = fd.as_raw_fd()), err)]
-#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
-async fn send_fd(
-    kind: &str,
-    fd: &(impl AsRawFd + Debug),
-    reader: &mut common::FramedRead<Response, ReadHalf<'_>>,
-    writer: &mut common::FramedWrite<Request, WriteHalf<'_>>,
-) -> Result<()> {
-    let fd = fd.as_raw_fd();
-    trace!("sending file descriptor");
-    writer.get_ref().get_ref().as_ref().send_fd(fd).await?;
-    trace!("waiting response");
-    recv_ok(reader).await?;
-    trace!("completed");
-    Ok(())
-}
-
-#[tracing::instrument(skip(reader), err)]
-#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
-async fn recv_ok(reader: &mut common::FramedRead<Response, ReadHalf<'_>>) -> Result<()> {
-    let resp = reader
-        .next()
-        .await
-        .unwrap_or_else(|| Err(io::Error::from(io::ErrorKind::UnexpectedEof)))?;
-    match resp {
-        Response::Ok => Ok(()),
-        Response::Err(msg) => Err(eyre!("error received from server: {}", msg)),
-        //resp => Err(eyre!("unexpected response received: resp = {:?}", resp)),
-    }
-}
-
 #[tracing::instrument(skip(in_stream, out_stream, rx), err)]
 #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
 async fn forward_until_interrupted(
@@ -208,51 +446,3 @@ async fn forward_until_interrupted(
     trace!("forward completed: notified");
     Ok(())
 }
-
-#[tracing::instrument(skip(in_stream, out_stream, magic), err)]
-#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
-async fn forward_until_magic(
-    mut in_stream: impl AsyncRead + Unpin,
-    mut out_stream: impl AsyncWrite + Unpin,
-    magic: &[u8],
-) -> Result<()> {
-    let mut whole_buf = vec![0u8; magic.len()];
-    let mut matched_len = 0;
-    while matched_len < magic.len() {
-        let n = in_stream.read(&mut whole_buf[matched_len..]).await?;
-        if n == 0 {
-            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
-        }
-
-        let read_buf = &whole_buf[0..matched_len + n];
-        debug_assert!(read_buf.len() <= magic.len());
-        if magic.starts_with(read_buf) {
-            matched_len = read_buf.len();
-            continue;
-        }
-        matched_len = 0;
-
-        let mut start_idx = read_buf.len();
-        for i in 1..read_buf.len() {
-            if read_buf[i] != magic[0] || !magic.starts_with(&read_buf[i..]) {
-                continue;
-            }
-            start_idx = i;
-            matched_len = read_buf.len() - i;
-            break;
-        }
-
-        trace!(magic_len = %magic.len(), %matched_len, read_len = %read_buf.len(),
-                "read");
-        out_stream.write_all(&read_buf[..start_idx]).await?;
-        out_stream.flush().await?;
-        if matched_len > 0 {
-            whole_buf[..matched_len].copy_from_slice(&magic[..matched_len]);
-        }
-    }
-
-    trace!("forward completed: magic found");
-    debug_assert_eq!(matched_len, magic.len());
-
-    Ok(())
-}
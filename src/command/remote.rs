@@ -1,19 +1,216 @@
-use tokio::fs::File;
+use super::{GlobalOpts, TlsOpts};
+use crate::{
+    audit,
+    common::{self, FdReader, FdWriter},
+    endpoint::subsystem,
+    prelude::*,
+    protocol, router, Error, Result,
+};
+use futures_util::future;
+use nix::{
+    fcntl::{self, FcntlArg, FdFlag},
+    libc, unistd,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    fmt::Write as _,
+    io::{Read as _, Write as _},
+    net,
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::UnixStream,
+    },
+    process::Stdio,
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    process::Command,
+    time,
+};
+use tokio_rustls::{rustls::Session as _, TlsAcceptor};
+use tracing_futures::Instrument as _;
 
-use super::GlobalOpts;
-use crate::{common, prelude::*, protocol, router, Error, Result};
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// File descriptor of the socket which passes the identity of the client from the listener
+const PEER_FD: RawFd = 3;
 
 /// Launch remote endpoint
 #[derive(Debug, clap::Clap)]
-pub(super) struct Opts;
+pub(super) struct Opts {
+    /// Serve the subsystem (e.g. `sftp`) on stdin/stdout instead of the RSRS protocol.
+    #[clap(name = "subsystem", long)]
+    subsystem: Option<String>,
+    /// Accept TLS connections on the address (e.g. `0.0.0.0:2222`) instead of serving stdin/stdout.
+    ///
+    /// Clients must present a certificate signed by the CA. Each connection is served by a new
+    /// `rsrs remote` process.
+    #[clap(name = "listen", long, conflicts_with = "subsystem")]
+    listen: Option<String>,
+    #[clap(flatten)]
+    tls: TlsOpts,
+    /// Read the identity of the client authenticated by the listener from the inherited socket
+    #[clap(name = "peer-from-listener", long, hidden = true)]
+    peer_from_listener: bool,
+}
+
+pub(super) async fn run(global: GlobalOpts, local: Opts) -> Result<()> {
+    if let Some(addr) = &local.listen {
+        return listen(&global, addr, &local.tls).await;
+    }
+    if local.peer_from_listener {
+        audit::set_peer(recv_peer()?);
+    }
+
+    // stdin/stdout may be a socket (e.g. the tcp transport), which cannot be opened by path
+    let stdin = unsafe { FdReader::boxed_from_raw_fd(libc::STDIN_FILENO)? };
+    let stdout = unsafe { FdWriter::boxed_from_raw_fd(libc::STDOUT_FILENO)? };
+
+    if let Some(name) = local.subsystem {
+        subsystem::serve(&name, stdin, stdout).await?;
+        return Ok(());
+    }
 
-pub(super) async fn run(_: GlobalOpts, _: Opts) -> Result<()> {
-    // TODO: subscriber should forward loggings to the server.
-    let stdin = File::open("/dev/stdin").await?;
-    let stdout = File::create("/dev/stdout").await?;
     let reader = common::new_reader(stdin).err_into::<Error>();
     let writer = common::new_writer(stdout).sink_map_err(Error::from);
 
-    router::spawn(protocol::ProcessKind::Local, reader, writer).await?;
+    let router = router::spawn(protocol::ProcessKind::Local, reader, writer);
+    // logs are sent to the client, since the stderr may not be visible to the user
+    common::forward_logs(router::lock().handler_tx());
+    router.await?;
     Ok(())
 }
+
+#[tracing::instrument(skip(global, tls), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn listen(global: &GlobalOpts, addr: &str, tls: &TlsOpts) -> Result<()> {
+    let acceptor = tls.files()?.acceptor()?;
+
+    // the socket address conversion in mio 0.6 is broken on recent Rust toolchains
+    let listener =
+        net::TcpListener::bind(addr).wrap_err_with(|| format!("failed to listen on {}", addr))?;
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener)?;
+    info!(addr = %listener.local_addr()?, "listening");
+
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let acceptor = acceptor.clone();
+        let log_directive = global.log_directive.clone();
+        tokio::spawn(
+            async move {
+                if let Err(e) = serve(acceptor, socket, peer, log_directive).await {
+                    warn!(error = %e, "connection closed with error");
+                }
+            }
+            .instrument(tracing::info_span!("connection", %peer)),
+        );
+    }
+}
+
+/// Authenticates the client and relays the connection to a new `rsrs remote` process.
+async fn serve(
+    acceptor: TlsAcceptor,
+    socket: TcpStream,
+    peer: net::SocketAddr,
+    log_directive: Option<String>,
+) -> Result<()> {
+    let stream = time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket))
+        .await
+        .map_err(|_| eyre!("TLS handshake timed out"))?
+        .map_err(|e| eyre!("TLS handshake failed: {}", e))?;
+    let identity = {
+        let mut identity = format!("tls:{}", peer);
+        let (_, session) = stream.get_ref();
+        if let Some(cert) = session
+            .get_peer_certificates()
+            .and_then(|certs| certs.into_iter().next())
+        {
+            identity.push_str(" sha256:");
+            for byte in Sha256::digest(&cert.0) {
+                let _ = write!(identity, "{:02x}", byte);
+            }
+        }
+        identity
+    };
+    info!(%identity, "client authenticated");
+
+    // the identity is passed through a socket inherited from this process instead of arguments,
+    // which could be given by any client
+    let (peer_socket, child_socket) = UnixStream::pair()?;
+    (&peer_socket).write_all(identity.as_bytes())?;
+    drop(peer_socket);
+
+    let mut cmd = Command::new(env::current_exe()?);
+    if let Some(log_directive) = log_directive {
+        cmd.arg("--log").arg(log_directive);
+    }
+    let child_fd = child_socket.as_raw_fd();
+    unsafe {
+        cmd.pre_exec(move || {
+            if child_fd == PEER_FD {
+                let _ = fcntl::fcntl(PEER_FD, FcntlArg::F_SETFD(FdFlag::empty()))
+                    .map_err(common::nix2io)?;
+            } else {
+                let _ = unistd::dup2(child_fd, PEER_FD).map_err(common::nix2io)?;
+            }
+            Ok(())
+        })
+    };
+    let mut child = cmd
+        .arg("remote")
+        .arg("--peer-from-listener")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    drop(child_socket);
+    let mut child_stdin = child.stdin.take().unwrap();
+    let mut child_stdout = child.stdout.take().unwrap();
+
+    let (mut reader, mut writer) = io::split(stream);
+    let input = async move {
+        let _ = io::copy(&mut reader, &mut child_stdin).await?;
+        // closing stdin terminates the process
+        drop(child_stdin);
+        Ok::<_, io::Error>(())
+    };
+    let output = async move {
+        let _ = io::copy(&mut child_stdout, &mut writer).await?;
+        match writer.shutdown().await {
+            // the client may close the connection first
+            Err(e) if e.kind() != io::ErrorKind::NotConnected => Err(e),
+            _ => Ok(()),
+        }
+    };
+    let res = future::try_join(input, output).await;
+
+    let status = child.await?;
+    info!(status = ?protocol::ExitStatus::from(status), "session closed");
+    res?;
+    Ok(())
+}
+
+/// Receives the identity of the client from the listener which launched this process.
+///
+/// On Linux, the socket must be created by the parent process running the same executable.
+fn recv_peer() -> Result<String> {
+    let mut socket = unsafe { UnixStream::from_raw_fd(PEER_FD) };
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use nix::sys::socket::{self, sockopt};
+
+        let cred = socket::getsockopt(PEER_FD, sockopt::PeerCredentials)
+            .wrap_err("identity of the client is not passed by the listener")?;
+        let parent = unistd::getppid().as_raw();
+        let parent_exe = std::fs::read_link(format!("/proc/{}/exe", parent)).ok();
+        ensure!(
+            cred.pid() == parent && parent_exe == Some(env::current_exe()?),
+            "identity of the client is not passed by the listener"
+        );
+    }
+    let mut peer = String::new();
+    let _ = socket.read_to_string(&mut peer)?;
+    Ok(peer)
+}
@@ -0,0 +1,38 @@
+use super::GlobalOpts;
+use crate::{
+    common,
+    prelude::*,
+    protocol::cli::{self, Request, Response},
+    Result,
+};
+
+/// Shut down the daemon and the nodes connected to it
+#[derive(Debug, clap::Clap)]
+pub(super) struct Opts {
+    /// Seconds to wait for running sessions to finish
+    #[clap(name = "timeout", long, default_value = "10")]
+    timeout: u64,
+}
+
+#[tracing::instrument(skip(global, local), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+pub(super) async fn run(global: GlobalOpts, local: Opts) -> Result<()> {
+    let mut stream = global.connect_root_daemon().await?;
+    let (in_stream, out_stream) = stream.split();
+    let mut writer = common::new_writer::<Request, _>(out_stream);
+    let mut reader = common::new_reader::<Response, _>(in_stream);
+
+    writer
+        .send(Request::Shutdown(cli::Shutdown {
+            timeout_secs: local.timeout,
+        }))
+        .await?;
+    super::recv_ok(&mut reader).await?;
+
+    // the connection is closed when the daemon exits
+    while reader.next().await.is_some() {}
+
+    debug!("shutdown completed");
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use super::GlobalOpts;
+use crate::{
+    common, metrics,
+    prelude::*,
+    protocol::cli::{Request, Response, Traffic},
+    Result,
+};
+
+/// Show traffic counters and queue depths of the daemon
+#[derive(Debug, clap::Clap)]
+pub(super) struct Opts {
+    /// Output in JSON format
+    #[clap(name = "json", long, conflicts_with = "prometheus")]
+    json: bool,
+    /// Output in the Prometheus text format
+    #[clap(name = "prometheus", long)]
+    prometheus: bool,
+}
+
+#[tracing::instrument(skip(global, local), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+pub(super) async fn run(global: GlobalOpts, local: Opts) -> Result<()> {
+    let mut stream = global.connect_root_daemon().await?;
+    let (in_stream, out_stream) = stream.split();
+    let mut writer = common::new_writer::<Request, _>(out_stream);
+    let mut reader = common::new_reader::<Response, _>(in_stream);
+
+    writer.send(Request::Stats).await?;
+    let stats = match reader.next().await.transpose()? {
+        Some(Response::Stats(stats)) => stats,
+        Some(Response::Err(msg)) => bail!("error received from server: {}", msg),
+        Some(resp) => bail!("unexpected response received: resp = {:?}", resp),
+        None => bail!("connection closed by server"),
+    };
+
+    if local.json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+    if local.prometheus {
+        print!("{}", metrics::render(&stats));
+        return Ok(());
+    }
+
+    println!(
+        "{:<24} {:>10} {:>12} {:>10} {:>12}",
+        "NODE", "SENT", "SENT_BYTES", "RECEIVED", "RECV_BYTES"
+    );
+    for node in &stats.nodes {
+        println!(
+            "{:<24} {}",
+            node.name,
+            format_traffic(&node.sent, &node.received)
+        );
+    }
+    if !stats.channels.is_empty() {
+        println!();
+        println!(
+            "{:<24} {:>10} {:>12} {:>10} {:>12}",
+            "CHANNEL", "SENT", "SENT_BYTES", "RECEIVED", "RECV_BYTES"
+        );
+        for channel in &stats.channels {
+            println!(
+                "{:<24} {}",
+                channel.id.to_string(),
+                format_traffic(&channel.sent, &channel.received)
+            );
+        }
+    }
+    if !stats.sessions.is_empty() {
+        println!();
+        println!(
+            "{:<24} {:>10} {:>12} {:>10} {:>12}",
+            "SESSION", "SENT", "SENT_BYTES", "RECEIVED", "RECV_BYTES"
+        );
+        for session in &stats.sessions {
+            println!(
+                "{:<24} {}",
+                format!("{}:{}", session.node, session.id),
+                format_traffic(&session.sent, &session.received)
+            );
+        }
+    }
+    println!();
+    println!("{:<24} {:>10} {:>12}", "QUEUE", "DEPTH", "CAPACITY");
+    for queue in &stats.queues {
+        println!(
+            "{:<24} {:>10} {:>12}",
+            queue.name, queue.depth, queue.capacity
+        );
+    }
+
+    Ok(())
+}
+
+fn format_traffic(sent: &Traffic, received: &Traffic) -> String {
+    format!(
+        "{:>10} {:>12} {:>10} {:>12}",
+        sent.frames, sent.bytes, received.frames, received.bytes
+    )
+}
@@ -0,0 +1,152 @@
+use super::TransportOutput;
+use crate::{prelude::*, Result};
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+use std::env;
+use tokio::fs;
+
+/// Arguments to launch the leaf daemon with the bootstrapped binary
+pub(crate) const LEAF_DAEMON_ARGS: &[&str] = &["daemon", "--as-leaf"];
+
+const STATUS_PREFIX: &str = "RSRS-BOOTSTRAP";
+
+#[derive(Debug)]
+struct Binary {
+    data: Vec<u8>,
+    /// SHA-256 digest of the binary in hex
+    digest: String,
+}
+
+impl Binary {
+    /// Returns the version hash of the binary, which is a part of the file name of the cache.
+    fn hash(&self) -> &str {
+        &self.digest[..16]
+    }
+}
+
+static BINARY: OnceCell<Binary> = OnceCell::new();
+
+async fn binary() -> Result<&'static Binary> {
+    if let Some(binary) = BINARY.get() {
+        return Ok(binary);
+    }
+    let exe = env::current_exe()?;
+    let data = fs::read(&exe)
+        .await
+        .wrap_err_with(|| format!("failed to read executable: {}", exe.display()))?;
+    let digest = Sha256::digest(&data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Ok(BINARY.get_or_init(|| Binary { data, digest }))
+}
+
+/// Installs the rsrs binary on the remote host and executes it with `args`.
+///
+/// The transport command must run a POSIX shell which reads commands from its stdin.
+/// The binary is cached in `$XDG_CACHE_HOME/rsrs` on the remote host and uploaded only if the
+/// binary of the same SHA-256 digest is not found there. The digest is computed by `sha256sum` or
+/// `shasum`, and the binary is uploaded every time if neither of them is available.
+#[tracing::instrument(skip(remote_stdin, output), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+pub(crate) async fn bootstrap(
+    mut remote_stdin: impl AsyncWrite + Unpin,
+    output: &mut TransportOutput<'_>,
+    args: &[&str],
+) -> Result<()> {
+    let binary = binary().await?;
+    debug!(digest = %binary.digest);
+
+    // every command must be written in a single line, since the shell reads the line before
+    // executing it and the binary following the line is read by `head`.
+    // the status prefix is not written literally so that the echo back of the command is not
+    // regarded as the status
+    let check = format!(
+        "p={}; b=\"${{XDG_CACHE_HOME:-${{HOME:-/tmp}}/.cache}}/rsrs/rsrs-{}\"; \
+         h=$( (sha256sum \"$b\" || shasum -a 256 \"$b\") 2>/dev/null | cut -d ' ' -f 1); \
+         echo \"$p:$(uname -sm):$(test -x \"$b\" && test \"$h\" = {} && echo found || echo missing)\"\n",
+        STATUS_PREFIX,
+        binary.hash(),
+        binary.digest,
+    );
+    remote_stdin
+        .write_all(check.as_bytes())
+        .await
+        .wrap_err("transport command exited during bootstrap")?;
+    remote_stdin.flush().await?;
+
+    let magic = format!("{}:", STATUS_PREFIX);
+    output
+        .forward_until(magic.as_bytes())
+        .await
+        .wrap_err("bootstrap failed")?;
+    let mut status = vec![];
+    loop {
+        match output.remote_stdout().read_u8().await? {
+            b'\n' => break,
+            b => status.push(b),
+        }
+    }
+    let status = String::from_utf8_lossy(&status);
+    debug!(%status);
+
+    let (platform, state) = status
+        .rsplit_once(':')
+        .ok_or_else(|| eyre!("invalid bootstrap status: {}", status))?;
+    ensure!(
+        is_compatible(platform),
+        "remote platform is not compatible with the local binary: {}",
+        platform
+    );
+
+    let exec = quote_args(args);
+    match state {
+        "found" => {
+            debug!("compatible binary found");
+            let command = format!("exec \"$b\"{} || exit 1\n", exec);
+            remote_stdin.write_all(command.as_bytes()).await?;
+        }
+        "missing" => {
+            info!(size = binary.data.len(), "uploading binary");
+            let size = binary.data.len();
+            let command = format!(
+                "t=\"$b.$$\"; mkdir -p \"${{b%/*}}\" && head -c {size} > \"$t\" && \
+                 test \"$(wc -c < \"$t\")\" -eq {size} && chmod 700 \"$t\" && mv \"$t\" \"$b\" && \
+                 exec \"$b\"{exec}; rm -f \"$t\"; exit 1\n",
+                size = size,
+                exec = exec,
+            );
+            remote_stdin.write_all(command.as_bytes()).await?;
+            remote_stdin.write_all(&binary.data).await?;
+        }
+        _ => bail!("invalid bootstrap status: {}", status),
+    }
+    remote_stdin.flush().await?;
+
+    trace!("completed");
+
+    Ok(())
+}
+
+fn quote_args(args: &[&str]) -> String {
+    args.iter()
+        .map(|arg| format!(" '{}'", arg.replace('\'', "'\\''")))
+        .collect()
+}
+
+/// Returns `true` if the binary runs on the platform described by `uname -sm`.
+fn is_compatible(platform: &str) -> bool {
+    let (system, machine) = match platform.split_once(' ') {
+        Some(platform) => platform,
+        None => return false,
+    };
+    let system_matched = match env::consts::OS {
+        "macos" => system == "Darwin",
+        os => system.eq_ignore_ascii_case(os),
+    };
+    let machine_matched = match env::consts::ARCH {
+        "aarch64" => machine == "aarch64" || machine == "arm64",
+        arch => machine == arch,
+    };
+    system_matched && machine_matched
+}
@@ -1,11 +1,12 @@
-use super::FileDesc;
+use super::{nix2io, FileDesc};
 use crate::prelude::*;
+use nix::unistd;
 use std::{
     os::unix::io::{AsRawFd, FromRawFd, RawFd},
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::io::PollEvented;
+use tokio::{fs::File, io::PollEvented};
 
 #[derive(Debug)]
 pub(crate) struct FdReader(PollEvented<FileDesc>);
@@ -21,6 +22,34 @@ impl FdReader {
         let inner = PollEvented::new(FileDesc::from_raw_fd(fd))?;
         Ok(Self(inner))
     }
+
+    /// Creates an async reader from any kind of file descriptor.
+    ///
+    /// The file descriptor is set to non-blocking mode, which is shared with its other users.
+    /// Files which cannot be polled (e.g. regular files) are handled on the blocking thread pool.
+    pub(crate) unsafe fn boxed_from_raw_fd(
+        fd: RawFd,
+    ) -> io::Result<Box<dyn AsyncRead + Send + Unpin>> {
+        let dup = unistd::dup(fd).map_err(nix2io)?;
+        if let Err(e) = super::set_nonblocking(dup) {
+            let _ = unistd::close(dup);
+            let _ = unistd::close(fd);
+            return Err(e);
+        }
+        match Self::from_raw_fd(dup) {
+            Ok(this) => {
+                let _ = unistd::close(fd);
+                Ok(Box::new(this))
+            }
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                Ok(Box::new(File::from_std(std::fs::File::from_raw_fd(fd))))
+            }
+            Err(e) => {
+                let _ = unistd::close(fd);
+                Err(e)
+            }
+        }
+    }
 }
 
 impl AsyncRead for FdReader {
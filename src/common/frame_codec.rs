@@ -0,0 +1,51 @@
+use crate::metrics::TrafficCounter;
+use bytes::{Bytes, BytesMut};
+use std::{io, sync::Arc};
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+/// Length-delimited codec which optionally counts the frames and the bytes including the headers.
+#[derive(Debug, Default)]
+pub(crate) struct Codec {
+    inner: LengthDelimitedCodec,
+    counter: Option<Arc<TrafficCounter>>,
+    /// Bytes of the frame being decoded, since the header is consumed before the payload arrives
+    partial: u64,
+}
+
+impl Codec {
+    /// Counts the frames passing through this codec.
+    pub(crate) fn count_traffic(&mut self, counter: Arc<TrafficCounter>) {
+        self.counter = Some(counter);
+    }
+}
+
+impl Encoder<Bytes> for Codec {
+    type Error = io::Error;
+
+    fn encode(&mut self, data: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        let len = dst.len();
+        self.inner.encode(data, dst)?;
+        if let Some(counter) = &self.counter {
+            counter.add((dst.len() - len) as u64);
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for Codec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        let len = src.len();
+        let frame = self.inner.decode(src)?;
+        self.partial += (len - src.len()) as u64;
+        if frame.is_some() {
+            if let Some(counter) = &self.counter {
+                counter.add(self.partial);
+            }
+            self.partial = 0;
+        }
+        Ok(frame)
+    }
+}
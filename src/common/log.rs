@@ -0,0 +1,157 @@
+use super::log_file;
+use crate::{metrics, prelude::*, protocol, Result};
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use std::{cell::Cell, fmt, fmt::Write as _, io::Write};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context, Layer},
+    reload, EnvFilter, Registry,
+};
+
+static LOG_FORWARDER: OnceCell<Mutex<metrics::Sender<protocol::Command>>> = OnceCell::new();
+static LOG_FILTER: OnceCell<reload::Handle<EnvFilter, Registry>> = OnceCell::new();
+
+thread_local! {
+    /// Set while the event is sent to the peer
+    static FORWARDING: Cell<bool> = const { Cell::new(false) };
+    /// Set if the current event is sent to the peer or suppressed, so that it is not written to
+    /// the local output
+    static CONSUMED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Keeps the handle which changes the filter of the installed subscriber.
+pub(crate) fn set_log_filter_handle(handle: reload::Handle<EnvFilter, Registry>) {
+    let _ = LOG_FILTER.set(handle);
+}
+
+/// Replaces the log filter of this process with the directive.
+pub(crate) fn set_log_filter(directive: &str) -> Result<()> {
+    let filter = EnvFilter::try_new(directive)
+        .wrap_err_with(|| format!("invalid log directive: {}", directive))?;
+    LOG_FILTER
+        .get()
+        .ok_or_else(|| eyre!("log filter cannot be changed"))?
+        .reload(filter)?;
+    Ok(())
+}
+
+/// Sends the tracing events of this process to the peer through the router instead of printing
+/// them to stderr.
+///
+/// Events are dropped while the queue of the router is full.
+pub(crate) fn forward_logs(handler_tx: metrics::Sender<protocol::Command>) {
+    let _ = LOG_FORWARDER.set(Mutex::new(handler_tx));
+}
+
+/// Returns the writer of the local log output, which discards the events sent to the peer.
+///
+/// The log file is used instead of stderr if it is opened.
+pub(crate) fn log_writer() -> Box<dyn Write> {
+    if CONSUMED.with(Cell::get) {
+        Box::new(std::io::sink())
+    } else if let Some(writer) = log_file::log_file_writer() {
+        Box::new(writer)
+    } else {
+        Box::new(std::io::stderr())
+    }
+}
+
+/// Tracing layer which sends events to the peer once `forward_logs` is called.
+///
+/// The layer must be added before the layers writing to `log_writer`, so that it marks the event
+/// as consumed before they write it.
+#[derive(Debug)]
+pub(crate) struct LogForwardLayer;
+
+impl<S: Subscriber> Layer<S> for LogForwardLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        // events emitted while an event is sent would be forwarded endlessly, so they are dropped
+        let consumed = FORWARDING.with(Cell::get) || forward(event);
+        CONSUMED.with(|c| c.set(consumed));
+    }
+}
+
+/// Sends the event to the peer, and returns false if it is not forwarded.
+fn forward(event: &Event<'_>) -> bool {
+    let tx = match LOG_FORWARDER.get() {
+        Some(tx) => tx,
+        None => return false,
+    };
+    let metadata = event.metadata();
+    // events of other crates are written to the local output
+    if !metadata.target().starts_with(env!("CARGO_PKG_NAME")) {
+        return false;
+    }
+
+    let mut visitor = LogVisitor::default();
+    event.record(&mut visitor);
+    let mut message = visitor.message;
+    if !visitor.fields.is_empty() {
+        if !message.is_empty() {
+            message.push(' ');
+        }
+        message.push_str(&visitor.fields);
+    }
+    let log = protocol::Log {
+        level: metadata.level().into(),
+        target: metadata.target().into(),
+        message,
+    };
+    FORWARDING.with(|f| f.set(true));
+    let _ = tx
+        .lock()
+        .try_send(protocol::Command::Send(protocol::RemoteCommand::Log(log)));
+    FORWARDING.with(|f| f.set(false));
+    true
+}
+
+#[derive(Debug, Default)]
+struct LogVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for LogVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            if !self.fields.is_empty() {
+                self.fields.push(' ');
+            }
+            let _ = write!(self.fields, "{}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Emits the event received from the peer to the local subscriber.
+pub(crate) fn emit_log(log: protocol::Log) {
+    let protocol::Log {
+        level,
+        target,
+        message,
+    } = log;
+    match level {
+        protocol::LogLevel::Error => error!(%target, "{}", message),
+        protocol::LogLevel::Warn => warn!(%target, "{}", message),
+        protocol::LogLevel::Info => info!(%target, "{}", message),
+        protocol::LogLevel::Debug => debug!(%target, "{}", message),
+        protocol::LogLevel::Trace => trace!(%target, "{}", message),
+    }
+}
+
+impl From<&Level> for protocol::LogLevel {
+    fn from(level: &Level) -> Self {
+        match *level {
+            Level::ERROR => Self::Error,
+            Level::WARN => Self::Warn,
+            Level::INFO => Self::Info,
+            Level::DEBUG => Self::Debug,
+            Level::TRACE => Self::Trace,
+        }
+    }
+}
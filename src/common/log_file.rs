@@ -0,0 +1,209 @@
+use crate::{config::LogRotation, prelude::*, Result};
+use once_cell::sync::OnceCell;
+use parking_lot::{Mutex, MutexGuard};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+static LOG_FILE: OnceCell<Mutex<LogFile>> = OnceCell::new();
+
+/// Log file which is rotated by its size and age.
+///
+/// Rotated files are renamed to `<path>.1`, `<path>.2`, ... (the larger, the older).
+///
+/// The age is counted from when the file is opened by this process or last rotated, not aligned
+/// to the clock, and the file is rotated when an event is written after the interval elapses.
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened_at: Instant,
+    max_size: Option<u64>,
+    interval: Option<Duration>,
+    keep: u32,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, rotation: &LogRotation) -> Result<Self> {
+        let (file, size) = open_append(&path)?;
+        Ok(Self {
+            path,
+            file,
+            size,
+            opened_at: Instant::now(),
+            max_size: rotation.max_size,
+            interval: rotation.interval.map(Duration::from_secs),
+            keep: rotation.keep,
+        })
+    }
+
+    fn needs_rotation(&self, len: usize) -> bool {
+        // an event larger than the limit is written to the file as it is
+        let too_large = matches!(
+            self.max_size,
+            Some(max_size) if self.size > 0 && self.size + len as u64 > max_size
+        );
+        let too_old =
+            matches!(self.interval, Some(interval) if self.opened_at.elapsed() >= interval);
+        too_large || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: u32| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                match fs::rename(rotated(n), rotated(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        let (file, size) = open_append(&self.path)?;
+        self.file = file;
+        self.size = size;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // an event is written at once, so the file is rotated at the boundary of events
+        if self.needs_rotation(buf.len()) {
+            if let Err(e) = self.rotate() {
+                // logging here would write to this file again
+                eprintln!("failed to rotate log file {}: {}", self.path.display(), e);
+            }
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+/// Writes the logs of this process to the file instead of stderr.
+pub(crate) fn open_log_file(path: &Path, rotation: &LogRotation) -> Result<()> {
+    let file = LogFile::open(path.to_owned(), rotation)
+        .wrap_err_with(|| format!("failed to open log file: {}", path.display()))?;
+    let _ = LOG_FILE.set(Mutex::new(file));
+    Ok(())
+}
+
+pub(crate) fn has_log_file() -> bool {
+    LOG_FILE.get().is_some()
+}
+
+pub(super) fn log_file_writer() -> Option<LogFileWriter> {
+    LOG_FILE.get().map(|file| LogFileWriter(file.lock()))
+}
+
+/// Writer of the log file, which holds the lock while an event is written.
+#[derive(Debug)]
+pub(crate) struct LogFileWriter(MutexGuard<'static, LogFile>);
+
+impl Write for LogFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    /// Creates an empty directory for the test, which is removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("rsrs-test-{}-{}", process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn open(dir: &TempDir, max_size: Option<u64>, interval: Option<u64>, keep: u32) -> LogFile {
+        let rotation = LogRotation {
+            max_size,
+            interval,
+            keep,
+        };
+        LogFile::open(dir.0.join("rsrs.log"), &rotation).unwrap()
+    }
+
+    fn read(dir: &TempDir, name: &str) -> Option<String> {
+        fs::read_to_string(dir.0.join(name)).ok()
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = TempDir::new("size");
+        let mut log = open(&dir, Some(10), None, 1);
+        // an event larger than the limit is written to the empty file
+        assert!(!log.needs_rotation(20));
+        let _ = log.write(b"aaaaaaaa").unwrap();
+        assert!(!log.needs_rotation(2));
+        assert!(log.needs_rotation(3));
+        let _ = log.write(b"bbb").unwrap();
+        assert_eq!(read(&dir, "rsrs.log").as_deref(), Some("bbb"));
+        assert_eq!(read(&dir, "rsrs.log.1").as_deref(), Some("aaaaaaaa"));
+    }
+
+    #[test]
+    fn rotate_by_interval() {
+        let dir = TempDir::new("interval");
+        assert!(!open(&dir, None, None, 1).needs_rotation(1));
+        assert!(!open(&dir, None, Some(3600), 1).needs_rotation(1));
+        assert!(open(&dir, None, Some(0), 1).needs_rotation(1));
+    }
+
+    #[test]
+    fn rotate_without_keeping() {
+        let dir = TempDir::new("keep0");
+        let mut log = open(&dir, Some(1), None, 0);
+        let _ = log.write(b"a").unwrap();
+        let _ = log.write(b"b").unwrap();
+        assert_eq!(read(&dir, "rsrs.log").as_deref(), Some("b"));
+        assert_eq!(read(&dir, "rsrs.log.1"), None);
+    }
+
+    #[test]
+    fn shift_rotated_files() {
+        let dir = TempDir::new("shift");
+        let mut log = open(&dir, Some(1), None, 2);
+        for event in &["a", "b", "c", "d"] {
+            let _ = log.write(event.as_bytes()).unwrap();
+        }
+        assert_eq!(read(&dir, "rsrs.log").as_deref(), Some("d"));
+        assert_eq!(read(&dir, "rsrs.log.1").as_deref(), Some("c"));
+        assert_eq!(read(&dir, "rsrs.log.2").as_deref(), Some("b"));
+        assert_eq!(read(&dir, "rsrs.log.3"), None);
+    }
+}
@@ -0,0 +1,328 @@
+use crate::{prelude::*, protocol, Error, Result};
+use futures_util::future;
+use std::time::Duration;
+use tokio::time;
+
+/// Maximum size of the transport command output kept for diagnostics
+const OUTPUT_CAPTURE_SIZE: usize = 4096;
+
+/// Output of the transport command before the handshake.
+///
+/// The output is forwarded to the local streams and kept to diagnose handshake failures.
+pub(crate) struct TransportOutput<'a> {
+    remote_stdout: &'a mut (dyn AsyncRead + Send + Unpin),
+    remote_stderr: &'a mut (dyn AsyncRead + Send + Unpin),
+    local_stdout: &'a mut (dyn AsyncWrite + Send + Unpin),
+    local_stderr: &'a mut (dyn AsyncWrite + Send + Unpin),
+    timeout: Duration,
+    output: Vec<u8>,
+}
+
+impl<'a> TransportOutput<'a> {
+    pub(crate) fn new(
+        remote_stdout: &'a mut (dyn AsyncRead + Send + Unpin),
+        remote_stderr: &'a mut (dyn AsyncRead + Send + Unpin),
+        local_stdout: &'a mut (dyn AsyncWrite + Send + Unpin),
+        local_stderr: &'a mut (dyn AsyncWrite + Send + Unpin),
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            remote_stdout,
+            remote_stderr,
+            local_stdout,
+            local_stderr,
+            timeout,
+            output: vec![],
+        }
+    }
+
+    pub(crate) fn remote_stdout(&mut self) -> &mut (dyn AsyncRead + Send + Unpin) {
+        self.remote_stdout
+    }
+
+    /// Waits for the magic number and the protocol version sent by the leaf daemon.
+    #[tracing::instrument(skip(self), err)]
+    #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+    pub(crate) async fn wait_handshake(&mut self) -> Result<()> {
+        self.forward_until(protocol::MAGIC).await?;
+
+        let version = match time::timeout(self.timeout, self.remote_stdout.read_u32()).await {
+            Ok(Ok(version)) => version,
+            Ok(Err(e)) => return Err(self.failure(e.into(), "failed to receive protocol version")),
+            Err(_) => {
+                return Err(self.failure(
+                    eyre!("timed out after {}s", self.timeout.as_secs()),
+                    "failed to receive protocol version",
+                ))
+            }
+        };
+        ensure!(
+            version == protocol::VERSION,
+            "protocol version mismatch (local: {}, remote: {}): \
+             update rsrs on the remote host or use `--bootstrap`",
+            protocol::VERSION,
+            version
+        );
+
+        trace!("completed");
+
+        Ok(())
+    }
+
+    /// Forwards the output until `magic` is found in the stdout of the transport command.
+    pub(crate) async fn forward_until(&mut self, magic: &[u8]) -> Result<()> {
+        let timeout = self.timeout;
+        let res = {
+            let output = &mut self.output;
+            let remote_stderr = &mut self.remote_stderr;
+            let local_stderr = &mut self.local_stderr;
+            let mut stderr_output = vec![];
+
+            let stdout = forward_until_magic(
+                &mut self.remote_stdout,
+                &mut self.local_stdout,
+                magic,
+                output,
+            );
+            let stderr = async {
+                let res = forward_output(remote_stderr, local_stderr, &mut stderr_output).await;
+                if let Err(e) = res {
+                    debug!(error = %e, "failed to forward stderr");
+                }
+                // stdout may be still available
+                future::pending::<()>().await
+            };
+            let res = time::timeout(timeout, async {
+                tokio::select! {
+                    res = stdout => res,
+                    () = stderr => unreachable!(),
+                }
+            })
+            .await;
+            push_output(output, &stderr_output);
+            res
+        };
+
+        match res {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                // wait for the error messages of the exited transport command
+                let mut stderr_output = vec![];
+                let _ = time::timeout(
+                    Duration::from_secs(1),
+                    forward_output(
+                        &mut self.remote_stderr,
+                        &mut self.local_stderr,
+                        &mut stderr_output,
+                    ),
+                )
+                .await;
+                push_output(&mut self.output, &stderr_output);
+                Err(self.failure(e, "transport command exited before handshake"))
+            }
+            Err(_) => Err(self.failure(
+                eyre!("timed out after {}s", timeout.as_secs()),
+                "handshake did not complete",
+            )),
+        }
+    }
+
+    fn failure(&self, error: Error, context: &str) -> Error {
+        let output = String::from_utf8_lossy(&self.output);
+        let context = match output.lines().rev().find(|line| !line.trim().is_empty()) {
+            Some(line) => format!("{} (last output: {:?})", context, line.trim()),
+            None => context.to_owned(),
+        };
+        let error = error.wrap_err(context);
+        match diagnose(&output) {
+            Some(reason) => error.wrap_err(reason),
+            None => error,
+        }
+    }
+}
+
+/// Guesses the reason of the handshake failure from the output of the transport command.
+fn diagnose(output: &str) -> Option<&'static str> {
+    let output = output.to_lowercase();
+    let last_line = output
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .unwrap_or("");
+
+    let prompts = [
+        "password",
+        "passphrase",
+        "verification code",
+        "one-time",
+        "otp",
+    ];
+    if last_line.trim_end().ends_with(':') && prompts.iter().any(|p| last_line.contains(p)) {
+        return Some("authentication prompt detected: the transport command requires interactive authentication");
+    }
+    if output.contains("host key verification failed") {
+        return Some("host key of the remote host is not verified");
+    }
+    if output.contains("permission denied") {
+        return Some("authentication failed");
+    }
+    let unreachable = [
+        "could not resolve hostname",
+        "connection refused",
+        "connection timed out",
+        "no route to host",
+    ];
+    if unreachable.iter().any(|p| output.contains(p)) {
+        return Some("cannot connect to the remote host");
+    }
+    // e.g. "bash: rsrs: command not found", "sh: 1: rsrs: not found"
+    let not_found = [
+        "command not found",
+        ": not found",
+        "no such file or directory",
+    ];
+    if not_found.iter().any(|p| last_line.contains(p)) {
+        return Some("command not found: install rsrs on the remote host or use `--bootstrap`");
+    }
+    None
+}
+
+fn push_output(output: &mut Vec<u8>, data: &[u8]) {
+    output.extend_from_slice(data);
+    if output.len() > OUTPUT_CAPTURE_SIZE {
+        let _ = output.drain(..output.len() - OUTPUT_CAPTURE_SIZE);
+    }
+}
+
+async fn forward_output(
+    mut in_stream: impl AsyncRead + Unpin,
+    mut out_stream: impl AsyncWrite + Unpin,
+    output: &mut Vec<u8>,
+) -> Result<()> {
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let n = in_stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        out_stream.write_all(&buf[..n]).await?;
+        out_stream.flush().await?;
+        push_output(output, &buf[..n]);
+    }
+}
+
+#[tracing::instrument(skip(in_stream, out_stream, magic, output), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn forward_until_magic(
+    mut in_stream: impl AsyncRead + Unpin,
+    mut out_stream: impl AsyncWrite + Unpin,
+    magic: &[u8],
+    output: &mut Vec<u8>,
+) -> Result<()> {
+    let mut whole_buf = vec![0u8; magic.len()];
+    let mut matched_len = 0;
+    while matched_len < magic.len() {
+        let n = in_stream.read(&mut whole_buf[matched_len..]).await?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let read_buf = &whole_buf[0..matched_len + n];
+        debug_assert!(read_buf.len() <= magic.len());
+        if magic.starts_with(read_buf) {
+            matched_len = read_buf.len();
+            continue;
+        }
+        matched_len = 0;
+
+        let mut start_idx = read_buf.len();
+        for i in 1..read_buf.len() {
+            if read_buf[i] != magic[0] || !magic.starts_with(&read_buf[i..]) {
+                continue;
+            }
+            start_idx = i;
+            matched_len = read_buf.len() - i;
+            break;
+        }
+
+        trace!(magic_len = %magic.len(), %matched_len, read_len = %read_buf.len(),
+                "read");
+        out_stream.write_all(&read_buf[..start_idx]).await?;
+        out_stream.flush().await?;
+        push_output(output, &read_buf[..start_idx]);
+        if matched_len > 0 {
+            whole_buf[..matched_len].copy_from_slice(&magic[..matched_len]);
+        }
+    }
+
+    trace!("forward completed: magic found");
+    debug_assert_eq!(matched_len, magic.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnose_failures() {
+        let cases = [
+            ("user@host's password: ", Some("authentication prompt")),
+            (
+                "Enter passphrase for key '/home/user/.ssh/id_ed25519':",
+                Some("authentication prompt"),
+            ),
+            ("Host key verification failed.\n", Some("host key")),
+            (
+                "user@host: Permission denied (publickey).\n",
+                Some("authentication failed"),
+            ),
+            (
+                "ssh: Could not resolve hostname nohost: Name or service not known\n",
+                Some("cannot connect"),
+            ),
+            ("bash: rsrs: command not found\n", Some("command not found")),
+            ("sh: 1: rsrs: not found\n", Some("command not found")),
+            (
+                "exec: rsrs: No such file or directory\n\n",
+                Some("command not found"),
+            ),
+            // only the last line tells whether the command is found
+            ("file not found in cache\nstarting daemon\n", None),
+            ("password changed\nwelcome\n", None),
+            ("", None),
+        ];
+        for &(output, expected) in &cases {
+            let reason = diagnose(output);
+            match expected {
+                Some(expected) => assert!(
+                    reason.is_some_and(|reason| reason.starts_with(expected)),
+                    "output = {:?}, reason = {:?}",
+                    output,
+                    reason
+                ),
+                None => assert_eq!(reason, None, "output = {:?}", output),
+            }
+        }
+    }
+
+    #[test]
+    fn push_output_keeps_tail() {
+        let mut output = vec![];
+        push_output(&mut output, b"hello");
+        assert_eq!(output, b"hello");
+
+        let data = (0..OUTPUT_CAPTURE_SIZE)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        push_output(&mut output, &data);
+        assert_eq!(output.len(), OUTPUT_CAPTURE_SIZE);
+        assert_eq!(output, data);
+
+        push_output(&mut output, b"world");
+        assert_eq!(output.len(), OUTPUT_CAPTURE_SIZE);
+        assert!(output.ends_with(b"world"));
+        assert_eq!(output[..OUTPUT_CAPTURE_SIZE - 5], data[5..]);
+    }
+}
@@ -1,28 +1,42 @@
 use crate::prelude::*;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use serde::{Deserialize, Serialize};
+use std::{env, os::unix::io::RawFd, path::PathBuf};
 use tokio_serde::{formats::SymmetricalBincode, SymmetricallyFramed};
-use tokio_util::codec::{self, LengthDelimitedCodec};
+use tokio_util::codec;
 
+pub(crate) use bootstrap::*;
 pub(crate) use fd::*;
 pub(crate) use fd_reader::*;
 pub(crate) use fd_writer::*;
+pub(crate) use frame_codec::*;
+pub(crate) use log::*;
+pub(crate) use log_file::*;
+pub(crate) use magic::*;
+pub(crate) use tls::*;
 
+mod bootstrap;
 mod fd;
 mod fd_reader;
 mod fd_writer;
+mod frame_codec;
+mod log;
+mod log_file;
+mod magic;
+mod tls;
 
 pub(crate) type FramedWrite<T, S> =
-    SymmetricallyFramed<codec::FramedWrite<S, LengthDelimitedCodec>, T, SymmetricalBincode<T>>;
+    SymmetricallyFramed<codec::FramedWrite<S, Codec>, T, SymmetricalBincode<T>>;
 
 pub(crate) type FramedRead<T, S> =
-    SymmetricallyFramed<codec::FramedRead<S, LengthDelimitedCodec>, T, SymmetricalBincode<T>>;
+    SymmetricallyFramed<codec::FramedRead<S, Codec>, T, SymmetricalBincode<T>>;
 
 pub(crate) fn new_writer<T, S>(inner: S) -> FramedWrite<T, S>
 where
     T: Serialize,
     S: AsyncWrite,
 {
-    let length_delimited = codec::FramedWrite::new(inner, LengthDelimitedCodec::new());
+    let length_delimited = codec::FramedWrite::new(inner, Codec::default());
     SymmetricallyFramed::new(length_delimited, SymmetricalBincode::default())
 }
 
@@ -31,10 +45,27 @@ where
     T: for<'a> Deserialize<'a>,
     S: AsyncRead,
 {
-    let length_delimited = codec::FramedRead::new(inner, LengthDelimitedCodec::new());
+    let length_delimited = codec::FramedRead::new(inner, Codec::default());
     SymmetricallyFramed::new(length_delimited, SymmetricalBincode::default())
 }
 
 pub(crate) fn nix2io(e: nix::Error) -> io::Error {
     e.as_errno().unwrap().into()
 }
+
+/// Returns the per-user directory where the daemon sockets are created.
+///
+/// `$XDG_RUNTIME_DIR/rsrs` is used if available, otherwise `rsrs-<uid>` in the temporary directory.
+pub(crate) fn runtime_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("rsrs"),
+        _ => env::temp_dir().join(format!("rsrs-{}", nix::unistd::getuid())),
+    }
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = fcntl(fd, FcntlArg::F_GETFL).map_err(nix2io)?;
+    let flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
+    let _ = fcntl(fd, FcntlArg::F_SETFL(flags)).map_err(nix2io)?;
+    Ok(())
+}
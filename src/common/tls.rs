@@ -0,0 +1,135 @@
+use crate::{prelude::*, Result};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile, AllowAnyAuthenticatedClient, Certificate, ClientConfig, PrivateKey,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+/// PEM files used for mutual TLS authentication
+#[derive(Debug, Clone)]
+pub(crate) struct TlsFiles {
+    /// Certificate chain of this host
+    pub(crate) cert: PathBuf,
+    /// Private key of `cert`
+    pub(crate) key: PathBuf,
+    /// CA certificates which sign the certificate of the peer
+    pub(crate) ca: PathBuf,
+}
+
+impl TlsFiles {
+    /// Creates an acceptor which requires client certificates signed by the CA.
+    pub(crate) fn acceptor(&self) -> Result<TlsAcceptor> {
+        let verifier = AllowAnyAuthenticatedClient::new(self.root_store()?);
+        let mut config = ServerConfig::new(verifier);
+        config.set_single_cert(self.certs()?, self.private_key()?)?;
+        Ok(Arc::new(config).into())
+    }
+
+    /// Creates a connector which presents the client certificate.
+    pub(crate) fn connector(&self) -> Result<TlsConnector> {
+        let mut config = ClientConfig::new();
+        config.root_store = self.root_store()?;
+        config.set_single_client_cert(self.certs()?, self.private_key()?)?;
+        Ok(Arc::new(config).into())
+    }
+
+    fn certs(&self) -> Result<Vec<Certificate>> {
+        let certs = pemfile::certs(&mut open(&self.cert)?)
+            .map_err(|()| eyre!("invalid certificate: {}", self.cert.display()))?;
+        ensure!(
+            !certs.is_empty(),
+            "no certificate found: {}",
+            self.cert.display()
+        );
+        Ok(certs)
+    }
+
+    fn private_key(&self) -> Result<PrivateKey> {
+        let invalid = || eyre!("invalid private key: {}", self.key.display());
+        let mut keys =
+            pemfile::pkcs8_private_keys(&mut open(&self.key)?).map_err(|()| invalid())?;
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut open(&self.key)?).map_err(|()| invalid())?;
+        }
+        keys.into_iter()
+            .next()
+            .ok_or_else(|| eyre!("no private key found: {}", self.key.display()))
+    }
+
+    fn root_store(&self) -> Result<RootCertStore> {
+        let mut store = RootCertStore::empty();
+        let (valid, _invalid) = store
+            .add_pem_file(&mut open(&self.ca)?)
+            .map_err(|()| eyre!("invalid CA certificate: {}", self.ca.display()))?;
+        ensure!(valid > 0, "no CA certificate found: {}", self.ca.display());
+        Ok(store)
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).wrap_err_with(|| format!("failed to open {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future;
+    use tokio::net::UnixStream;
+    use tokio_rustls::webpki::DNSNameRef;
+
+    fn files(name: &str) -> TlsFiles {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls");
+        TlsFiles {
+            cert: dir.join(format!("{}.pem", name)),
+            key: dir.join(format!("{}.key", name)),
+            ca: dir.join("ca.pem"),
+        }
+    }
+
+    async fn handshake(client: &str) -> (Result<()>, Result<()>) {
+        let acceptor = files("server").acceptor().unwrap();
+        let connector = files(client).connector().unwrap();
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let (server, client) = future::join(
+            acceptor.accept(server_stream),
+            connector.connect(domain, client_stream),
+        )
+        .await;
+        (
+            server.map(drop).map_err(Into::into),
+            client.map(drop).map_err(Into::into),
+        )
+    }
+
+    #[tokio::test]
+    async fn accept_signed_client() {
+        let (server, client) = handshake("client").await;
+        server.unwrap();
+        client.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_unsigned_client() {
+        let (server, _client) = handshake("rogue").await;
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn missing_files() {
+        let mut tls = files("client");
+        tls.key = tls.cert.clone();
+        assert!(tls.connector().is_err());
+        tls.ca = tls.ca.with_file_name("missing.pem");
+        assert!(tls.acceptor().is_err());
+    }
+}
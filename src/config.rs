@@ -0,0 +1,235 @@
+//! Configuration file (`~/.config/rsrs/config.toml`).
+//!
+//! ```toml
+//! sock_path = "/run/user/1000/rsrs.sock"
+//! log = "rsrs=info"
+//! log_format = "json"
+//! log_file = "/var/log/rsrs/daemon.log"
+//! log_rotation = { max_size = 10485760, interval = 86400, keep = 5 }
+//! metrics_addr = "127.0.0.1:9187"
+//!
+//! [hosts.web]
+//! command = ["ssh", "web1", "rsrs", "daemon", "--as-leaf"]
+//! name = "web-1"
+//! env = ["LANG"]
+//! reconnect = true
+//! keepalive = { interval = 30, count = 3 }
+//!
+//! [hosts.fresh]
+//! command = ["ssh", "-T", "fresh1", "sh"]
+//! bootstrap = true
+//!
+//! [hosts.app]
+//! command = ["docker://app1"]
+//! ```
+
+use crate::{
+    prelude::*,
+    protocol::network::{Keepalive, LinkOptions},
+    Error, Result,
+};
+use once_cell::sync::OnceCell;
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Socket path of the root daemon
+    pub(crate) sock_path: Option<PathBuf>,
+    /// Log directive
+    pub(crate) log: Option<String>,
+    /// Log format of the root daemon
+    pub(crate) log_format: Option<LogFormat>,
+    /// Log file of the root daemon. Logs are written to stderr if omitted
+    pub(crate) log_file: Option<PathBuf>,
+    /// Rotation of the log file
+    pub(crate) log_rotation: LogRotation,
+    /// Address of the Prometheus endpoint of the root daemon
+    pub(crate) metrics_addr: Option<String>,
+    /// Authorization policy file [default: ~/.config/rsrs/policy.toml]
+    pub(crate) policy: Option<PathBuf>,
+    /// Audit log of the processes spawned by remote peers
+    pub(crate) audit: AuditConfig,
+    /// Host aliases which can be used instead of transport commands
+    pub(crate) hosts: HashMap<String, Host>,
+}
+
+/// Host alias.
+///
+/// Port forwardings to set up on connection (`forwards`) are not supported yet, since rsrs has no
+/// port forwarding to apply them with. The key is rejected as unknown rather than ignored, so that
+/// a config file relying on it fails loudly until it is implemented.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Host {
+    /// Transport command which launches the leaf daemon, or a transport URL
+    pub(crate) command: Vec<String>,
+    /// Node name. The alias name is used if omitted
+    #[serde(default)]
+    pub(crate) name: Option<String>,
+    /// Names of the environment variables passed to the processes executed on the node
+    #[serde(default)]
+    pub(crate) env: Vec<String>,
+    /// Re-run the transport command when the connection is lost
+    #[serde(default)]
+    pub(crate) reconnect: bool,
+    #[serde(default)]
+    pub(crate) keepalive: Option<KeepaliveConfig>,
+    /// Install the rsrs binary on the host. `command` must run a POSIX shell
+    #[serde(default)]
+    pub(crate) bootstrap: bool,
+    /// Seconds to wait for the leaf daemon to start
+    #[serde(default)]
+    pub(crate) handshake_timeout: Option<u64>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AuditConfig {
+    /// File which the records are appended to
+    pub(crate) file: Option<PathBuf>,
+    /// Socket of the syslog daemon (e.g. `/dev/log`) which the records are sent to
+    pub(crate) syslog: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => bail!("unknown log format: {}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LogRotation {
+    /// Rotate the log file when it exceeds this size in bytes
+    pub(crate) max_size: Option<u64>,
+    /// Rotate the log file at this interval in seconds, counted from when the daemon opens the file
+    /// or last rotates it
+    pub(crate) interval: Option<u64>,
+    /// Number of rotated files to keep
+    pub(crate) keep: u32,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        Self {
+            max_size: Some(10 * 1024 * 1024),
+            interval: None,
+            keep: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct KeepaliveConfig {
+    /// Interval of ping requests in seconds
+    pub(crate) interval: u64,
+    /// Number of unanswered ping requests before the connection is regarded as lost
+    #[serde(default = "default_keepalive_count")]
+    pub(crate) count: u32,
+}
+
+fn default_keepalive_count() -> u32 {
+    3
+}
+
+impl Config {
+    /// Returns the node name and the host definition for a node name or a host alias.
+    pub(crate) fn resolve_node<'a>(&'a self, node: &'a str) -> (&'a str, Option<&'a Host>) {
+        if let Some((alias, host)) = self.hosts.get_key_value(node) {
+            return (host.node_name(alias), Some(host));
+        }
+        let host = self
+            .hosts
+            .iter()
+            .find(|(alias, host)| host.node_name(alias) == node)
+            .map(|(_, host)| host);
+        (node, host)
+    }
+}
+
+impl Host {
+    pub(crate) fn node_name<'a>(&'a self, alias: &'a str) -> &'a str {
+        self.name.as_deref().unwrap_or(alias)
+    }
+
+    pub(crate) fn link_options(&self) -> LinkOptions {
+        LinkOptions {
+            reconnect: self.reconnect,
+            keepalive: self.keepalive.as_ref().map(|keepalive| Keepalive {
+                interval_secs: keepalive.interval,
+                count: keepalive.count,
+            }),
+            bootstrap: self.bootstrap,
+            handshake_timeout_secs: self.handshake_timeout,
+        }
+    }
+}
+
+/// Returns the directory of the configuration files (`~/.config/rsrs`).
+pub(crate) fn dir() -> Option<PathBuf> {
+    let mut path = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    path.push("rsrs");
+    Some(path)
+}
+
+fn default_path() -> Option<PathBuf> {
+    dir().map(|dir| dir.join("config.toml"))
+}
+
+/// Loads the configuration file.
+///
+/// The default file is not required to exist, but the file specified by `path` is.
+pub(crate) fn load(path: Option<&Path>) -> Result<()> {
+    let (path, required) = match path {
+        Some(path) => (path.to_owned(), true),
+        None => match default_path() {
+            Some(path) => (path, false),
+            None => {
+                let _ = CONFIG.set(Config::default());
+                return Ok(());
+            }
+        },
+    };
+
+    let config = match fs::read_to_string(&path) {
+        Ok(s) => toml::from_str(&s)
+            .wrap_err_with(|| format!("failed to parse config file: {}", path.display()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Config::default(),
+        Err(e) => {
+            return Err(
+                Error::new(e).wrap_err(format!("failed to read config file: {}", path.display()))
+            )
+        }
+    };
+    let _ = CONFIG.set(config);
+    Ok(())
+}
+
+pub(crate) fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
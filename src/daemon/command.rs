@@ -1,45 +1,114 @@
+use super::session;
 use crate::{
     common::{self, FdReader, FdWriter},
-    daemon,
+    daemon, metrics,
     prelude::*,
-    protocol::cli::{self, Request, Response},
+    protocol::{
+        cli::{self, Request, Response},
+        network::{self, SessionData},
+        ChannelData,
+    },
     Error, Result,
 };
+use futures_util::future;
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    unistd,
+};
 use passfd::tokio_02::FdPassingExt;
 use std::{
     borrow::Cow,
+    env,
     fmt::Debug,
-    fs, io,
+    fs, io, iter,
     os::unix::{
-        fs::FileTypeExt as _,
-        io::{AsRawFd as _, RawFd},
+        fs::{DirBuilderExt as _, FileTypeExt as _, MetadataExt as _, PermissionsExt as _},
+        io::{AsRawFd as _, FromRawFd as _, RawFd},
     },
     path::{Path, PathBuf},
+    process,
+    time::Duration,
 };
-use tokio::net::{
-    unix::{ReadHalf, WriteHalf},
-    UnixListener, UnixStream,
+use tokio::{
+    net::{
+        unix::{ReadHalf, WriteHalf},
+        UnixListener, UnixStream,
+    },
+    sync::broadcast,
 };
 
 #[tracing::instrument(err)]
 #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
-pub(super) async fn setup(sock_path: Cow<'_, Path>) -> Result<(UnixListener, SocketGuard)> {
+pub(super) async fn setup(sock_path: Cow<'_, Path>) -> Result<(UnixListener, Option<SocketGuard>)> {
+    if let Some(listener) = take_activated_socket().wrap_err("failed to use activated socket")? {
+        info!(local_addr = ?listener.local_addr()?, "using socket passed by service manager");
+        return Ok((listener, None));
+    }
+
     let (listener, guard) = setup_socket(&sock_path)
         .await
         .wrap_err("failed to setup socket")?;
 
     trace!("completed");
 
-    Ok((listener, guard))
+    Ok((listener, Some(guard)))
+}
+
+/// Takes the listening socket passed by the service manager (systemd socket activation).
+///
+/// See `sd_listen_fds(3)` for the protocol.
+fn take_activated_socket() -> Result<Option<UnixListener>> {
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    // prevent processes spawned by the daemon from inheriting the variables
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+
+    let fds = match (pid, fds) {
+        (Some(pid), Some(fds)) if pid.parse() == Ok(process::id()) => fds,
+        _ => return Ok(None),
+    };
+    let fds = fds
+        .parse::<u32>()
+        .wrap_err_with(|| format!("invalid LISTEN_FDS: {}", fds))?;
+    ensure!(
+        fds == 1,
+        "exactly one socket must be passed, but LISTEN_FDS={}",
+        fds
+    );
+
+    let fd = SD_LISTEN_FDS_START;
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(common::nix2io)?;
+    let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+    // fails if the passed socket is not a unix domain socket
+    let _ = listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+
+    Ok(Some(UnixListener::from_std(listener)?))
 }
 
 #[tracing::instrument(err)]
 #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
 pub(super) async fn run(mut listener: UnixListener) -> Result<()> {
+    let uid = unistd::getuid().as_raw();
     while let Some(stream) = listener.next().await {
         match stream {
             Ok(stream) => {
-                let _ = tokio::spawn(async move { serve(stream).await.unwrap() });
+                match stream.peer_cred() {
+                    Ok(cred) if cred.uid == uid => {}
+                    Ok(cred) => {
+                        warn!(peer_uid = cred.uid, "rejected connection from another user");
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!(error = %e, "failed to get peer credentials");
+                        continue;
+                    }
+                }
+                tokio::spawn(async move { serve(stream).await.unwrap() });
             }
             Err(e) => {
                 warn!(error = %e, "accept failed");
@@ -72,6 +141,10 @@ impl Drop for SocketGuard {
 async fn setup_socket(sock_path: impl AsRef<Path> + Debug) -> Result<(UnixListener, SocketGuard)> {
     let sock_path = sock_path.as_ref();
 
+    if let Some(dir) = sock_path.parent() {
+        setup_socket_dir(dir)?;
+    }
+
     if sock_path.exists() {
         let metadata = sock_path.metadata()?;
         ensure!(
@@ -101,11 +174,12 @@ async fn setup_socket(sock_path: impl AsRef<Path> + Debug) -> Result<(UnixListen
             },
         }
 
-        fs::remove_file(&sock_path)?;
+        fs::remove_file(sock_path)?;
     }
 
     let listener = UnixListener::bind(sock_path)?;
     let guard = SocketGuard(sock_path.to_owned());
+    fs::set_permissions(sock_path, fs::Permissions::from_mode(0o600))?;
 
     debug!(local_addr = ?listener.local_addr()?,
             "daemon started");
@@ -113,6 +187,35 @@ async fn setup_socket(sock_path: impl AsRef<Path> + Debug) -> Result<(UnixListen
     Ok((listener, guard))
 }
 
+fn setup_socket_dir(dir: &Path) -> Result<()> {
+    if !dir.exists() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .wrap_err_with(|| format!("failed to create socket directory: {}", dir.display()))?;
+        return Ok(());
+    }
+
+    // The default directory may be placed in the shared temporary directory,
+    // so make sure that it has not been prepared by another user.
+    if dir == common::runtime_dir() {
+        let metadata = dir.metadata()?;
+        ensure!(
+            metadata.uid() == unistd::getuid().as_raw(),
+            "socket directory is owned by another user: {}",
+            dir.display()
+        );
+        ensure!(
+            metadata.mode() & 0o077 == 0,
+            "socket directory is accessible by other users: {}",
+            dir.display()
+        );
+    }
+
+    Ok(())
+}
+
 #[tracing::instrument(skip(stream), err)]
 #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
 async fn serve(mut stream: UnixStream) -> Result<()> {
@@ -127,6 +230,15 @@ async fn serve(mut stream: UnixStream) -> Result<()> {
         trace!(?req, "req received");
         let res = match req {
             Request::Open(req) => open(req, &mut reader, &mut writer).await,
+            Request::OpenVia(req) => open_via(req, &mut writer).await,
+            Request::ListNodes => list_nodes(&mut writer).await,
+            Request::Exec(req) => exec(req, &mut reader, &mut writer).await,
+            req @ Request::WindowSizeChange(..) => Err(eyre!("unexpected request: {:?}", req)),
+            Request::Close(req) => close(req, &mut writer).await,
+            Request::Shutdown(req) => shutdown(req, &mut writer).await,
+            Request::Subscribe => subscribe(&mut reader, &mut writer).await,
+            Request::SetLogFilter(req) => set_log_filter(req, &mut writer).await,
+            Request::Stats => stats(&mut writer).await,
         };
 
         // Send error response and shutdown UNIX stream
@@ -146,7 +258,14 @@ async fn open(
     reader: &mut common::FramedRead<Request, ReadHalf<'_>>,
     writer: &mut common::FramedWrite<Response, WriteHalf<'_>>,
 ) -> Result<()> {
-    let cli::Open { pid, command, args } = req;
+    let cli::Open {
+        name,
+        pid,
+        command,
+        args,
+        options,
+        pty,
+    } = req;
 
     trace!("sending response");
     writer.send(Response::Ok).await?;
@@ -154,24 +273,269 @@ async fn open(
     let stdin = unsafe { FdWriter::from_raw_fd(recv_fd("stdin", reader, writer).await?)? };
     let stdout = unsafe { FdReader::from_raw_fd(recv_fd("stdout", reader, writer).await?)? };
     let stderr = unsafe { FdReader::from_raw_fd(recv_fd("stderr", reader, writer).await?)? };
+    // closing the pty master hangs up the transport command
+    let pty = if pty {
+        let master = unsafe { FdReader::from_raw_fd(recv_fd("pty", reader, writer).await?)? };
+        let slave = unsafe { fs::File::from_raw_fd(recv_fd("pty slave", reader, writer).await?) };
+        Some((master, slave))
+    } else {
+        None
+    };
     trace!(
         stdin = ?stdin.as_raw_fd(),
         stdout = ?stdout.as_raw_fd(),
         stderr = ?stderr.as_raw_fd(),
+        pty = ?pty.as_ref().map(|(master, slave)| (master.as_raw_fd(), slave.as_raw_fd())),
         "file descriptor received"
     );
 
-    daemon::network::connect_to_leaf(stdin, stdout, stderr)
-        .await
-        .wrap_err("failed to connect to client")?;
+    let transport = iter::once(&command)
+        .chain(&args)
+        .map(|s| s.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let name = daemon::network::assign_name(name, &transport)?;
+    let res = daemon::network::connect_to_leaf(
+        name.clone(),
+        transport.clone(),
+        pid,
+        stdin,
+        stdout,
+        stderr,
+    )
+    .await;
+    daemon::network::release_name(&name);
+    let name = res.wrap_err("failed to connect to client")?;
+    daemon::network::remember_name(&transport, &name);
+    daemon::network::set_link_options(name.clone(), command.clone(), args.clone(), options);
+    if let Some((master, slave)) = pty {
+        daemon::network::drain_until_link_closed(name.clone(), master, slave);
+    }
+
+    trace!("sending response to command");
+    writer.send(Response::Ok).await?;
+
+    info!(%name, ?pid, ?command, ?args, "connection opened");
+    Ok(())
+}
+
+#[tracing::instrument(skip(writer), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn open_via(
+    req: cli::OpenVia,
+    writer: &mut common::FramedWrite<Response, WriteHalf<'_>>,
+) -> Result<()> {
+    let cli::OpenVia {
+        name,
+        via,
+        command,
+        args,
+        options,
+    } = req;
+
+    let transport = iter::once(&command)
+        .chain(&args)
+        .map(|s| s.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let name = daemon::network::assign_name(name, &transport)?;
+    let req = network::Request::Open {
+        name: name.clone(),
+        command,
+        args,
+        options,
+    };
+    let res = daemon::network::request(via.clone(), req).await;
+    if !matches!(res, Ok(network::Response::Ok)) {
+        daemon::network::release_name(&name);
+    }
+    match res? {
+        network::Response::Ok => {}
+        res => bail!("unexpected response received: {:?}", res),
+    }
+    daemon::network::remember_name(&transport, &name);
+
+    trace!("sending response to command");
+    writer.send(Response::Ok).await?;
+
+    info!(%name, %via, "connection opened");
+    Ok(())
+}
+
+#[tracing::instrument(skip(writer), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn list_nodes(writer: &mut common::FramedWrite<Response, WriteHalf<'_>>) -> Result<()> {
+    let nodes = daemon::network::list_nodes();
+    writer.send(Response::Nodes(nodes)).await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(writer), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn stats(writer: &mut common::FramedWrite<Response, WriteHalf<'_>>) -> Result<()> {
+    writer.send(Response::Stats(metrics::collect())).await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(writer), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn close(
+    req: cli::Close,
+    writer: &mut common::FramedWrite<Response, WriteHalf<'_>>,
+) -> Result<()> {
+    let cli::Close { node } = req;
+    daemon::network::close(node.clone()).await?;
+    writer.send(Response::Ok).await?;
+    info!(%node, "connection closed");
+    Ok(())
+}
+
+#[tracing::instrument(skip(writer), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn shutdown(
+    req: cli::Shutdown,
+    writer: &mut common::FramedWrite<Response, WriteHalf<'_>>,
+) -> Result<()> {
+    let cli::Shutdown { timeout_secs } = req;
+    daemon::network::shutdown(Duration::from_secs(timeout_secs)).await;
+    writer.send(Response::Ok).await?;
+    daemon::network::request_shutdown();
+    Ok(())
+}
+
+#[tracing::instrument(skip(writer), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn set_log_filter(
+    req: cli::SetLogFilter,
+    writer: &mut common::FramedWrite<Response, WriteHalf<'_>>,
+) -> Result<()> {
+    let cli::SetLogFilter { directive } = req;
+    common::set_log_filter(&directive)?;
+    writer.send(Response::Ok).await?;
+    info!(%directive, "log filter changed");
+    Ok(())
+}
+
+#[tracing::instrument(skip(reader, writer), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn subscribe(
+    reader: &mut common::FramedRead<Request, ReadHalf<'_>>,
+    writer: &mut common::FramedWrite<Response, WriteHalf<'_>>,
+) -> Result<()> {
+    let mut events = daemon::event::subscribe();
+    writer.send(Response::Ok).await?;
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => writer.send(Response::Event(event)).await?,
+                Err(broadcast::RecvError::Lagged(n)) => warn!(%n, "events dropped"),
+                Err(broadcast::RecvError::Closed) => break,
+            },
+            req = reader.next() => match req.transpose()? {
+                Some(req) => bail!("unexpected request: {:?}", req),
+                None => break,
+            },
+        }
+    }
+    Ok(())
+}
+
+#[tracing::instrument(skip(req, reader, writer), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn exec(
+    req: cli::Exec,
+    reader: &mut common::FramedRead<Request, ReadHalf<'_>>,
+    writer: &mut common::FramedWrite<Response, WriteHalf<'_>>,
+) -> Result<()> {
+    let cli::Exec {
+        node,
+        command,
+        env_vars,
+        pty,
+    } = req;
+
+    trace!("sending response");
+    writer.send(Response::Ok).await?;
+
+    let stdin = unsafe { FdReader::boxed_from_raw_fd(recv_fd("stdin", reader, writer).await?)? };
+    let mut stdout =
+        unsafe { FdWriter::boxed_from_raw_fd(recv_fd("stdout", reader, writer).await?)? };
+    let mut stderr =
+        unsafe { FdWriter::boxed_from_raw_fd(recv_fd("stderr", reader, writer).await?)? };
+
+    let (id, mut rx) = session::start(node.clone(), command, env_vars, pty).await?;
+    info!(%node, %id, "session started");
 
     trace!("sending response to command");
     writer.send(Response::Ok).await?;
 
-    info!(%pid, ?command, ?args, "connection opened");
+    let (forward_stdin, stdin_handle) = future::abortable({
+        let node = node.clone();
+        async move {
+            if let Err(e) = forward_stdin(node, id, stdin).await {
+                warn!(error = %e, "failed to forward stdin");
+            }
+        }
+    });
+    tokio::spawn(forward_stdin);
+
+    let res = async {
+        loop {
+            tokio::select! {
+                data = rx.next() => match data {
+                    Some(SessionData::Stdout(ChannelData::Output(data))) => {
+                        stdout.write_all(&data).await?;
+                        stdout.flush().await?;
+                    }
+                    Some(SessionData::Stderr(ChannelData::Output(data))) => {
+                        stderr.write_all(&data).await?;
+                        stderr.flush().await?;
+                    }
+                    Some(SessionData::Exit(status)) => break Ok(status),
+                    Some(_) => {}
+                    None => bail!("session closed unexpectedly"),
+                },
+                req = reader.next() => match req.transpose()? {
+                    Some(Request::WindowSizeChange(width, height)) => {
+                        let data = SessionData::Stdin(ChannelData::WindowSizeChange(width, height));
+                        session::send(node.clone(), id, data).await?;
+                    }
+                    Some(req) => bail!("unexpected request: {:?}", req),
+                    None => bail!("command disconnected"),
+                },
+            }
+        }
+    }
+    .await;
+    // stop reading stdin of the command
+    stdin_handle.abort();
+    if res.is_err() {
+        // the process is not left running without its client
+        let _ = session::send(node.clone(), id, SessionData::Hangup).await;
+    }
+
+    let status = res?;
+    info!(%node, %id, ?status, "session finished");
+    writer.send(Response::Exit(status)).await?;
     Ok(())
 }
 
+async fn forward_stdin(
+    node: network::NodeName,
+    id: network::SessionId,
+    mut stdin: Box<dyn AsyncRead + Send + Unpin>,
+) -> Result<()> {
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let n = stdin.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        let data = SessionData::Stdin(ChannelData::Output(buf[..n].into()));
+        session::send(node.clone(), id, data).await?;
+    }
+    session::send(node, id, SessionData::Stdin(ChannelData::Shutdown)).await
+}
+
 #[tracing::instrument(skip(reader, writer), err)]
 #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
 async fn recv_fd(
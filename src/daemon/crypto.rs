@@ -0,0 +1,328 @@
+//! End-to-end encryption of the messages between nodes.
+//!
+//! Each daemon generates an X25519 key pair on startup. Public keys are exchanged with the
+//! adjacent node in the handshake and announced to the ancestors by `RouteAdd`, so that nodes know
+//! the keys of their ancestors and descendants.
+//!
+//! `Request`, `Response` and `Session` messages are encrypted with ChaCha20-Poly1305, using a key
+//! derived from the shared secret of the source and the destination, so the intermediate nodes can
+//! neither read nor forge them. Note that keys learned through intermediate nodes are trusted as
+//! of the time the node joins the network.
+//!
+//! Keys are pinned on first use: a key announced for a known node is refused, unless the node is
+//! reconnected (i.e. its transport command is re-run by its parent). Keys are forgotten when the
+//! node is closed.
+
+use crate::{
+    prelude::*,
+    protocol::network::{MessageBody, NodeName, PublicKey, Sealed},
+    Result,
+};
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use x25519_dalek::StaticSecret;
+
+static SECRET: Lazy<StaticSecret> = Lazy::new(|| StaticSecret::new(OsRng));
+
+static PEERS: Lazy<Mutex<HashMap<NodeName, Peer>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct Peer {
+    key: PublicKey,
+    /// Cipher of the messages from this node to the peer
+    sender: ChaCha20Poly1305,
+    /// Cipher of the messages from the peer to this node
+    receiver: ChaCha20Poly1305,
+    sent: u64,
+    received: ReplayWindow,
+}
+
+impl Peer {
+    fn new(key: PublicKey) -> Result<Self> {
+        let shared = SECRET.diffie_hellman(&x25519_dalek::PublicKey::from(key.0));
+        ensure!(shared.as_bytes() != &[0; 32], "invalid public key");
+        let my_key = public_key();
+        Ok(Self {
+            key,
+            sender: cipher(shared.as_bytes(), &my_key, &key),
+            receiver: cipher(shared.as_bytes(), &key, &my_key),
+            sent: 0,
+            received: ReplayWindow::default(),
+        })
+    }
+}
+
+fn cipher(shared: &[u8; 32], from: &PublicKey, to: &PublicKey) -> ChaCha20Poly1305 {
+    let key = Sha256::new()
+        .chain(b"rsrs mesh")
+        .chain(shared)
+        .chain(from.0)
+        .chain(to.0)
+        .finalize();
+    ChaCha20Poly1305::new(&key)
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    Nonce::from(nonce)
+}
+
+/// Binds the ciphertext to the source and the destination of the message.
+fn aad(src: &NodeName, dst: &NodeName) -> Vec<u8> {
+    let mut aad = vec![];
+    for name in &[src, dst] {
+        aad.extend_from_slice(&(name.len() as u64).to_le_bytes());
+        aad.extend_from_slice(name.as_bytes());
+    }
+    aad
+}
+
+pub(super) fn public_key() -> PublicKey {
+    PublicKey(x25519_dalek::PublicKey::from(&*SECRET).to_bytes())
+}
+
+/// Registers the public key of the node.
+///
+/// Fails if another key is already registered for the node.
+pub(super) fn add_key(name: NodeName, key: PublicKey) -> Result<()> {
+    let mut peers = PEERS.lock();
+    if let Some(peer) = peers.get(&name) {
+        ensure!(
+            peer.key == key,
+            "public key of {} has changed (fingerprint: {}, pinned: {})",
+            name,
+            key.fingerprint(),
+            peer.key.fingerprint()
+        );
+        return Ok(());
+    }
+    let _ = peers.insert(name.clone(), Peer::new(key)?);
+    trace!(%name, fingerprint = %key.fingerprint(), "public key registered");
+    Ok(())
+}
+
+/// Registers the public key of the reconnected node, replacing the previous one.
+///
+/// If the key is changed (e.g. the daemon of the node is restarted), the message counters are reset.
+pub(super) fn replace_key(name: NodeName, key: PublicKey) -> Result<()> {
+    let mut peers = PEERS.lock();
+    if matches!(peers.get(&name), Some(peer) if peer.key == key) {
+        return Ok(());
+    }
+    let _ = peers.insert(name.clone(), Peer::new(key)?);
+    debug!(%name, fingerprint = %key.fingerprint(), "public key replaced");
+    Ok(())
+}
+
+/// Forgets the public key of the closed node.
+pub(super) fn remove_key(name: &NodeName) {
+    let _ = PEERS.lock().remove(name);
+}
+
+/// Returns the public keys of the nodes known by this node.
+pub(super) fn known_keys() -> Vec<(NodeName, PublicKey)> {
+    PEERS
+        .lock()
+        .iter()
+        .map(|(name, peer)| (name.clone(), peer.key))
+        .collect()
+}
+
+/// Encrypts the message body for the destination node.
+pub(super) fn seal(src: &NodeName, dst: &NodeName, body: &MessageBody) -> Result<Sealed> {
+    let plaintext = bincode::serialize(body)?;
+    let mut peers = PEERS.lock();
+    let peer = peers
+        .get_mut(dst)
+        .ok_or_else(|| eyre!("unknown node: {} (public key is not known)", dst))?;
+    let counter = peer.sent;
+    peer.sent += 1;
+    let payload = Payload {
+        msg: &plaintext,
+        aad: &aad(src, dst),
+    };
+    let ciphertext = peer
+        .sender
+        .encrypt(&nonce(counter), payload)
+        .map_err(|_| eyre!("failed to encrypt message to {}", dst))?;
+    Ok(Sealed {
+        counter,
+        ciphertext,
+    })
+}
+
+/// Decrypts the message body sent from the source node.
+pub(super) fn open(src: &NodeName, dst: &NodeName, sealed: Sealed) -> Result<MessageBody> {
+    let Sealed {
+        counter,
+        ciphertext,
+    } = sealed;
+    let plaintext = {
+        // scope for lock guard
+        let mut peers = PEERS.lock();
+        let peer = peers
+            .get_mut(src)
+            .ok_or_else(|| eyre!("unknown node: {} (public key is not known)", src))?;
+        ensure!(
+            peer.received.accepts(counter),
+            "replayed message from {}",
+            src
+        );
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: &aad(src, dst),
+        };
+        let plaintext = peer
+            .receiver
+            .decrypt(&nonce(counter), payload)
+            .map_err(|_| eyre!("failed to decrypt message from {}", src))?;
+        peer.received.update(counter);
+        plaintext
+    }; // lock ends here
+
+    let body = bincode::deserialize(&plaintext)?;
+    ensure!(
+        matches!(
+            body,
+            MessageBody::Request(..) | MessageBody::Response(..) | MessageBody::Session(..)
+        ),
+        "unexpected message sealed by {}",
+        src
+    );
+    Ok(body)
+}
+
+/// Counters of the recently received messages.
+///
+/// Messages sent concurrently may arrive out of order, so counters within the window are accepted
+/// once each.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    /// The largest received counter plus one
+    next: u64,
+    /// The n-th bit is set if `next - 1 - n` has been received
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    fn accepts(&self, counter: u64) -> bool {
+        if counter >= self.next {
+            return true;
+        }
+        let offset = self.next - 1 - counter;
+        offset < 64 && self.bitmap & (1 << offset) == 0
+    }
+
+    fn update(&mut self, counter: u64) {
+        if counter >= self.next {
+            let shift = counter - self.next + 1;
+            self.bitmap = if shift < 64 { self.bitmap << shift } else { 0 };
+            self.bitmap |= 1;
+            self.next = counter + 1;
+        } else {
+            self.bitmap |= 1 << (self.next - 1 - counter);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::network::Request;
+
+    /// Registers the nodes with the key of this node, so that messages between them can be opened
+    /// by this node.
+    fn names(src: &str, dst: &str) -> (NodeName, NodeName) {
+        let (src, dst) = (
+            NodeName::from(src.to_owned()),
+            NodeName::from(dst.to_owned()),
+        );
+        add_key(src.clone(), public_key()).unwrap();
+        add_key(dst.clone(), public_key()).unwrap();
+        (src, dst)
+    }
+
+    fn other_key() -> PublicKey {
+        PublicKey(x25519_dalek::PublicKey::from(&StaticSecret::new(OsRng)).to_bytes())
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let (src, dst) = names("round-trip-src", "round-trip-dst");
+        for id in 0..3 {
+            let sealed = seal(&src, &dst, &MessageBody::Request(id, Request::Ping)).unwrap();
+            assert_eq!(sealed.counter, id);
+            let body = open(&src, &dst, sealed).unwrap();
+            assert!(matches!(body, MessageBody::Request(i, Request::Ping) if i == id));
+        }
+    }
+
+    #[test]
+    fn tampered_message() {
+        let (src, dst) = names("tamper-src", "tamper-dst");
+        let body = MessageBody::Request(0, Request::Ping);
+
+        let mut sealed = seal(&src, &dst, &body).unwrap();
+        sealed.ciphertext[0] ^= 1;
+        assert!(open(&src, &dst, sealed).is_err());
+
+        // the ciphertext is bound to the source and the destination
+        let sealed = seal(&src, &dst, &body).unwrap();
+        assert!(open(&dst, &src, sealed).is_err());
+
+        let sealed = seal(&src, &dst, &body).unwrap();
+        let replayed = Sealed {
+            counter: sealed.counter,
+            ciphertext: sealed.ciphertext.clone(),
+        };
+        open(&src, &dst, sealed).unwrap();
+        assert!(open(&src, &dst, replayed).is_err());
+    }
+
+    #[test]
+    fn wrong_key() {
+        let (src, dst) = names("wrong-key-src", "wrong-key-dst");
+        let sealed = seal(&src, &dst, &MessageBody::Request(0, Request::Ping)).unwrap();
+        replace_key(src.clone(), other_key()).unwrap();
+        assert!(open(&src, &dst, sealed).is_err());
+        let unknown = NodeName::from("wrong-key-unknown".to_owned());
+        assert!(seal(&src, &unknown, &MessageBody::Request(0, Request::Ping)).is_err());
+    }
+
+    #[test]
+    fn pinned_key() {
+        let name = NodeName::from("pinned".to_owned());
+        let (key, other) = (other_key(), other_key());
+        add_key(name.clone(), key).unwrap();
+        add_key(name.clone(), key).unwrap();
+        assert!(add_key(name.clone(), other).is_err());
+        assert_eq!(PEERS.lock()[&name].key, key);
+        replace_key(name.clone(), other).unwrap();
+        assert_eq!(PEERS.lock()[&name].key, other);
+        remove_key(&name);
+        add_key(name.clone(), key).unwrap();
+        assert!(add_key(name.clone(), PublicKey([0; 32])).is_err());
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::default();
+        for &counter in &[0, 2, 1, 5, 3] {
+            assert!(window.accepts(counter), "counter = {}", counter);
+            window.update(counter);
+            assert!(!window.accepts(counter), "counter = {}", counter);
+        }
+        assert!(window.accepts(4));
+        window.update(100);
+        assert!(!window.accepts(4));
+        assert!(window.accepts(37));
+        assert!(!window.accepts(36));
+    }
+}
@@ -0,0 +1,16 @@
+use crate::protocol::cli::Event;
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+
+// TODO: specify appropriate buffer size
+static EVENTS: Lazy<broadcast::Sender<Event>> = Lazy::new(|| broadcast::channel(256).0);
+
+/// Notifies the event to the subscribers.
+pub(super) fn publish(event: Event) {
+    // no one may be subscribing
+    let _ = EVENTS.send(event);
+}
+
+pub(super) fn subscribe() -> broadcast::Receiver<Event> {
+    EVENTS.subscribe()
+}
@@ -1,8 +1,12 @@
-use crate::{prelude::*, Result};
+use crate::{prelude::*, protocol::cli, Result};
 use std::{borrow::Cow, path::Path};
 
 mod command;
+mod crypto;
+mod event;
+mod names;
 mod network;
+mod session;
 
 #[tracing::instrument(err)]
 #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
@@ -19,9 +23,16 @@ pub(crate) async fn run(sock_path: Cow<'_, Path>, is_leaf: bool) -> Result<()> {
 
     trace!("setup completed");
 
-    tokio::try_join!(
-        command::run(listener).map_err(|e| e.wrap_err("command server end unexpectedly"))
-    )?;
+    tokio::select! {
+        res = command::run(listener) => res.wrap_err("command server end unexpectedly")?,
+        () = network::wait_shutdown() => info!("shutting down"),
+    }
 
     Ok(())
 }
+
+/// Adds the statistics of the daemon network, if this process is a daemon.
+pub(crate) fn collect_stats(stats: &mut cli::Stats) {
+    network::collect_stats(stats);
+    session::collect_stats(stats);
+}
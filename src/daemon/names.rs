@@ -0,0 +1,69 @@
+//! Persistent mapping from transport commands to the node names assigned last time.
+
+use crate::{prelude::*, protocol::network::NodeName, Result};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::{collections::HashMap, env, fs, io, path::PathBuf};
+
+static NAMES: Lazy<Mutex<HashMap<String, NodeName>>> = Lazy::new(|| {
+    let names = load().unwrap_or_else(|e| {
+        warn!(error = %e, "failed to load node names");
+        HashMap::new()
+    });
+    Mutex::new(names)
+});
+
+fn names_path() -> Option<PathBuf> {
+    let mut path = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))?;
+    path.push("rsrs/names.json");
+    Some(path)
+}
+
+fn load() -> Result<HashMap<String, NodeName>> {
+    let path = match names_path() {
+        Some(path) => path,
+        None => return Ok(HashMap::new()),
+    };
+    match fs::read(&path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn store(names: &HashMap<String, NodeName>) -> Result<()> {
+    let path = match names_path() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(names)?)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+fn key(command: &[String]) -> String {
+    command.join(" ")
+}
+
+/// Returns the name assigned to the node launched by `command` last time.
+pub(super) fn lookup(command: &[String]) -> Option<NodeName> {
+    NAMES.lock().get(&key(command)).cloned()
+}
+
+/// Remembers the name assigned to the node launched by `command`.
+pub(super) fn remember(command: &[String], name: &NodeName) {
+    let mut names = NAMES.lock();
+    if names.get(&key(command)) == Some(name) {
+        return;
+    }
+    let _ = names.insert(key(command), name.clone());
+    if let Err(e) = store(&names) {
+        warn!(error = %e, "failed to store node names");
+    }
+}
@@ -1,20 +1,50 @@
+use super::{crypto, event, names, session};
 use crate::{
-    common,
+    common::{self, FdReader, FdWriter, TransportOutput},
+    metrics, policy,
     prelude::*,
-    protocol,
-    protocol::network::{Handshake, HandshakeRsp, Message, NodeName},
-    Result,
+    protocol::{
+        self,
+        cli::{self, Event},
+        network::{
+            Handshake, HandshakeRsp, Keepalive, LinkOptions, Message, MessageBody, NodeInfo,
+            NodeName, NodeState, Request, RequestId, Response,
+        },
+    },
+    transport::{self, Transport},
+    Error, Result,
+};
+use futures_core::Future;
+use futures_util::future;
+use nix::{
+    libc,
+    sys::signal::{self, Signal},
+    unistd,
 };
-use common::{FdReader, FdWriter};
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 use rand::prelude::*;
 use std::{
     borrow::Borrow,
-    collections::{hash_map::Entry, HashMap},
+    cmp,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    ffi::OsString,
     hash::Hash,
+    iter,
+    pin::Pin,
+    process::{self, Stdio},
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::OpenOptions,
+    io::BufReader,
+    process::Command,
+    sync::{mpsc, oneshot, Notify},
+    time,
 };
-use tokio::{fs::File, io::BufReader, sync::mpsc};
+use tracing_futures::Instrument as _;
 
 #[tracing::instrument(err)]
 #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
@@ -32,12 +62,14 @@ pub(crate) async fn setup(is_leaf: bool) -> Result<()> {
 #[tracing::instrument(err)]
 #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
 async fn setup_leaf() -> Result<()> {
-    let mut reader = File::open("/dev/stdin").await?;
-    let mut writer = File::create("/dev/stdout").await?;
+    // blocking reads of stdin prevent the daemon from exiting
+    let mut reader = unsafe { FdReader::boxed_from_raw_fd(libc::STDIN_FILENO)? };
+    let mut writer = unsafe { FdWriter::boxed_from_raw_fd(libc::STDOUT_FILENO)? };
 
     // send magic number to rsrs-open
     trace!("sending magic number");
     writer.write_all(protocol::MAGIC).await?;
+    writer.write_u32(protocol::VERSION).await?;
     writer.flush().await?;
 
     // receive handshake packet from rsrs-daemon
@@ -45,26 +77,55 @@ async fn setup_leaf() -> Result<()> {
     let Handshake {
         server_name,
         client_name,
+        server_key,
+        known_keys,
+        server_ancestors,
     } = common::new_reader(&mut reader).next().await.unwrap()?;
     trace!(%server_name, %client_name, "handshake received");
+    crypto::add_key(server_name.clone(), server_key)?;
+    for (name, key) in known_keys {
+        crypto::add_key(name, key)?;
+    }
 
     // send handshake response
     trace!("sending handshake response");
-    common::new_writer(&mut writer).send(HandshakeRsp).await?;
+    common::new_writer(&mut writer)
+        .send(HandshakeRsp {
+            client_key: crypto::public_key(),
+        })
+        .await?;
     debug!(%server_name, %client_name, "handshake completed");
 
-    let mut reader = common::new_reader::<Message, _>(reader);
-    let mut writer = common::new_writer::<Message, _>(writer);
+    let reader = common::new_reader::<Message, _>(reader);
+    let writer = common::new_writer::<Message, _>(writer);
 
     // TODO: specify appropriate buffer size
-    let (tx, rx) = mpsc::channel(100);
+    let (tx, rx) = metrics::channel(100);
     {
         // scope for lock guard
         let mut store = NODE_STORE.lock();
-        store.insert_with_name(client_name.clone(), Node::MyNode)?;
-        store.insert_with_name(server_name, Node::Connected { sender: tx })?;
+        let connected_at = Some(unix_time());
+        let info = NodeInfo {
+            connected_at,
+            pid: Some(process::id()),
+            public_key: Some(crypto::public_key()),
+            ..NodeInfo::new(client_name, Some(server_name.clone()), NodeState::Local)
+        };
+        store.insert_with_name(info, Node::MyNode)?;
+        let info = NodeInfo {
+            connected_at,
+            public_key: Some(server_key),
+            ..NodeInfo::new(server_name.clone(), None, NodeState::Connected)
+        };
+        store.insert_with_name(info, Node::Connected { sender: tx })?;
+        store.parent = Some(server_name.clone());
+        store.ancestors = iter::once(server_name.clone())
+            .chain(server_ancestors)
+            .collect();
     }
 
+    spawn_link(server_name, reader, writer, rx);
+
     Ok(())
 }
 
@@ -72,7 +133,13 @@ async fn setup_leaf() -> Result<()> {
 #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
 async fn setup_root() -> Result<()> {
     let mut store = NODE_STORE.lock();
-    let name = store.insert(Node::MyNode)?;
+    let name = store.new_name();
+    let info = NodeInfo {
+        pid: Some(process::id()),
+        public_key: Some(crypto::public_key()),
+        ..NodeInfo::new(name.clone(), None, NodeState::Local)
+    };
+    store.insert_with_name(info, Node::MyNode)?;
     info!(%name, "root daemon started");
     Ok(())
 }
@@ -80,18 +147,38 @@ async fn setup_root() -> Result<()> {
 #[tracing::instrument(skip(remote_stdin, remote_stdout, remote_stderr), err)]
 #[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
 pub(crate) async fn connect_to_leaf(
-    mut remote_stdin: FdWriter,
-    mut remote_stdout: FdReader,
-    remote_stderr: FdReader,
-) -> Result<()> {
-    let (client_name, server_name) = {
+    client_name: NodeName,
+    command: Vec<String>,
+    pid: Option<u32>,
+    mut remote_stdin: impl AsyncWrite + Send + Unpin + 'static,
+    mut remote_stdout: impl AsyncRead + Send + Unpin + 'static,
+    remote_stderr: impl AsyncRead + Send + Unpin + 'static,
+) -> Result<NodeName> {
+    let (client_name, server_name, lost_info) = {
         // scope for lock guard
         let mut store = NODE_STORE.lock();
-        let client_name = store.insert(Node::Handshake)?;
         let server_name = store.my_name.clone();
         assert!(!server_name.is_empty());
-        (client_name, server_name)
+        // restored if the node fails to reconnect
+        let lost_info = match store.get(&client_name) {
+            Some(Node::Disconnected) => store.infos.get(&client_name).cloned(),
+            _ => None,
+        };
+        let info = NodeInfo {
+            command,
+            pid,
+            ..NodeInfo::new(
+                client_name.clone(),
+                Some(server_name.clone()),
+                NodeState::Handshake,
+            )
+        };
+        store.insert_with_name(info, Node::Handshake)?;
+        (client_name, server_name, lost_info)
     }; // lock ends here
+    event::publish(Event::NodeConnecting {
+        name: client_name.clone(),
+    });
 
     // forward stderr
     tokio::spawn({
@@ -104,29 +191,62 @@ pub(crate) async fn connect_to_leaf(
         }
     });
 
-    trace!("sending handshake message to daemon");
-    common::new_writer(&mut remote_stdin)
-        .send(Handshake {
-            server_name: server_name.clone(),
-            client_name: client_name.clone(),
-        })
-        .await?;
+    let server_ancestors = NODE_STORE.lock().ancestors.clone();
+    let res = async {
+        trace!("sending handshake message to daemon");
+        common::new_writer(&mut remote_stdin)
+            .send(Handshake {
+                server_name: server_name.clone(),
+                client_name: client_name.clone(),
+                server_key: crypto::public_key(),
+                known_keys: crypto::known_keys(),
+                server_ancestors,
+            })
+            .await?;
 
-    trace!("receiving handshake response from daemon");
-    let HandshakeRsp = common::new_reader(&mut remote_stdout)
-        .next()
-        .await
-        .unwrap()?;
+        trace!("receiving handshake response from daemon");
+        let HandshakeRsp { client_key } = common::new_reader(&mut remote_stdout)
+            .next()
+            .await
+            .unwrap_or_else(|| Err(io::Error::from(io::ErrorKind::UnexpectedEof)))?;
+        // the key may change only when the node is reconnected
+        if lost_info.is_some() {
+            crypto::replace_key(client_name.clone(), client_key)?;
+        } else {
+            crypto::add_key(client_name.clone(), client_key)?;
+        }
+        Ok::<_, Error>(client_key)
+    }
+    .await;
+    let client_key = match res {
+        Ok(client_key) => client_key,
+        Err(e) => {
+            {
+                // scope for lock guard
+                let mut store = NODE_STORE.lock();
+                match lost_info {
+                    // the node stays disconnected, so that the transport command is re-run
+                    Some(info) => {
+                        let _ = store.nodes.insert(client_name.clone(), Node::Disconnected);
+                        let _ = store.infos.insert(client_name.clone(), info);
+                    }
+                    None => store.remove(&client_name),
+                }
+            } // lock ends here
+            event::publish(Event::NodeLost { name: client_name });
+            return Err(e);
+        }
+    };
 
     debug!(%server_name, %client_name, "handshake completed");
 
-    let mut reader = common::new_reader::<Message, _>(remote_stdout);
-    let mut writer = common::new_writer::<Message, _>(remote_stdin);
+    let reader = common::new_reader::<Message, _>(remote_stdout);
+    let writer = common::new_writer::<Message, _>(remote_stdin);
 
     // TODO: specify appropriate buffer size
-    let (tx, mut rx) = mpsc::channel(100);
+    let (tx, rx) = metrics::channel(100);
 
-    {
+    let info = {
         // scope for lock guard
         let mut store = NODE_STORE.lock();
         match store.get_mut(&client_name).unwrap() {
@@ -135,22 +255,972 @@ pub(crate) async fn connect_to_leaf(
             }
             _ => unreachable!(),
         }
+        let info = store.info_mut(&client_name).unwrap();
+        info.state = NodeState::Connected;
+        info.connected_at = Some(unix_time());
+        info.public_key = Some(client_key);
+        info.clone()
+    }; // lock ends here
+    event::publish(Event::NodeConnected(info.clone()));
+
+    spawn_link(client_name.clone(), reader, writer, rx);
+    announce(MessageBody::RouteAdd(info));
+
+    Ok(client_name)
+}
+
+type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Runs the transport command on this node and connects to the leaf daemon launched by it.
+///
+/// If `command` is a transport URL, the command line is built from it.
+#[tracing::instrument(err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn open(
+    name: NodeName,
+    command: OsString,
+    args: Vec<OsString>,
+    options: LinkOptions,
+) -> Result<()> {
+    let transport = match command.to_str() {
+        Some(url) if args.is_empty() => Transport::parse(url)?,
+        _ => None,
+    };
+    let (pid, mut remote_stdin, mut remote_stdout, mut remote_stderr) = match transport {
+        Some(Transport::Tcp { addr }) => connect_tcp(&addr, &options).await?,
+        Some(transport) => {
+            spawn_transport(transport.command(common::LEAF_DAEMON_ARGS, options.bootstrap)?)?
+        }
+        None => spawn_transport(iter::once(&command).chain(&args).cloned().collect())?,
+    };
+
+    // output before the magic number is not a part of the protocol
+    // `File::create` truncates the log file the daemon's stderr is redirected to
+    let mut local_stdout = OpenOptions::new().write(true).open("/dev/stderr").await?;
+    let mut local_stderr = OpenOptions::new().write(true).open("/dev/stderr").await?;
+    let mut output = TransportOutput::new(
+        &mut remote_stdout,
+        &mut remote_stderr,
+        &mut local_stdout,
+        &mut local_stderr,
+        options.handshake_timeout(),
+    );
+    let res = async {
+        if options.bootstrap {
+            common::bootstrap(&mut remote_stdin, &mut output, common::LEAF_DAEMON_ARGS).await?;
+        }
+        output.wait_handshake().await
+    }
+    .await;
+    if let Err(e) = res {
+        if let Some(pid) = pid {
+            let _ = signal::kill(unistd::Pid::from_raw(pid as i32), Signal::SIGTERM);
+        }
+        return Err(e);
+    }
+
+    let transport = iter::once(&command)
+        .chain(&args)
+        .map(|s| s.to_string_lossy().into_owned())
+        .collect();
+    let name = connect_to_leaf(
+        name,
+        transport,
+        pid,
+        remote_stdin,
+        remote_stdout,
+        remote_stderr,
+    )
+    .await
+    .wrap_err("failed to connect to client")?;
+    set_link_options(name.clone(), command.clone(), args.clone(), options);
+    info!(%name, ?pid, ?command, ?args, "connection opened");
+    Ok(())
+}
+
+fn spawn_transport(
+    command_line: Vec<OsString>,
+) -> Result<(Option<u32>, BoxedWriter, BoxedReader, BoxedReader)> {
+    let mut cmd = Command::new(&command_line[0]);
+    cmd.args(&command_line[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    unsafe {
+        cmd.pre_exec(|| {
+            // become a session leader to detach controlling terminal
+            unistd::setsid().map_err(common::nix2io)?;
+            Ok(())
+        })
+    };
+    let mut child = cmd.spawn()?;
+    let pid = child.id();
+
+    let remote_stdin = child.stdin.take().unwrap();
+    let remote_stdout = child.stdout.take().unwrap();
+    let remote_stderr = child.stderr.take().unwrap();
+
+    tokio::spawn(async move {
+        match child.await {
+            Ok(status) => {
+                debug!(%pid, status = ?protocol::ExitStatus::from(status), "transport command exited")
+            }
+            Err(e) => warn!(%pid, error = %e, "failed to wait transport command"),
+        }
+    });
+
+    Ok((
+        Some(pid),
+        Box::new(remote_stdin),
+        Box::new(remote_stdout),
+        Box::new(remote_stderr),
+    ))
+}
+
+async fn connect_tcp(
+    addr: &str,
+    options: &LinkOptions,
+) -> Result<(Option<u32>, BoxedWriter, BoxedReader, BoxedReader)> {
+    ensure!(
+        !options.bootstrap,
+        "bootstrap is not supported by tcp transport"
+    );
+    let stream = transport::connect_tcp(addr).await?;
+    let (reader, writer) = io::split(stream);
+    Ok((
+        None,
+        Box::new(writer),
+        Box::new(reader),
+        Box::new(io::empty()),
+    ))
+}
+
+/// Applies the options to the link to the adjacent node launched by the transport command.
+pub(crate) fn set_link_options(
+    name: NodeName,
+    command: OsString,
+    args: Vec<OsString>,
+    options: LinkOptions,
+) {
+    let mut store = NODE_STORE.lock();
+    if options.reconnect {
+        let _ = store
+            .transports
+            .insert(name.clone(), (command, args, options.clone()));
+    }
+    if let Some(keepalive) = options.keepalive {
+        // the task is kept running during reconnection
+        if store.keepalives.insert(name.clone()) {
+            tokio::spawn(send_keepalive(name, keepalive));
+        }
+    }
+}
+
+/// Reads the controlling terminal of the transport command until the link to the adjacent node is
+/// closed.
+///
+/// Closing the pty master hangs up the transport command, and the command blocks on writing to
+/// the terminal if the master is not read. `slave` is kept open so that reading the master does
+/// not fail before the transport command opens the terminal.
+pub(crate) fn drain_until_link_closed<T>(
+    name: NodeName,
+    mut pty: impl AsyncRead + Send + Unpin + 'static,
+    slave: T,
+) where
+    T: Send + 'static,
+{
+    let closed = {
+        // scope for lock guard
+        let mut store = NODE_STORE.lock();
+        match store.get(&name) {
+            Some(Node::Connected { .. }) => {
+                let (tx, rx) = oneshot::channel();
+                store.link_waiters.entry(name.clone()).or_default().push(tx);
+                rx
+            }
+            _ => return,
+        }
+    }; // lock ends here
+    tokio::spawn(
+        async move {
+            let drain = async {
+                let mut buf = vec![0u8; 4096];
+                loop {
+                    match pty.read(&mut buf).await {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            let output = String::from_utf8_lossy(&buf[..n]);
+                            debug!(%output, "transport command wrote to terminal");
+                        }
+                        // reading from the pty master fails with EIO after the slave is closed
+                        Err(e) => {
+                            debug!(error = %e, "failed to read terminal");
+                            break;
+                        }
+                    }
+                }
+                // the terminal is kept open
+                future::pending::<()>().await
+            };
+            tokio::select! {
+                _ = closed => {}
+                () = drain => unreachable!(),
+            }
+            drop(slave);
+        }
+        .instrument(tracing::debug_span!("drain_terminal", %name)),
+    );
+}
+
+/// Sends ping requests to the adjacent node periodically, and terminates the transport command if
+/// the node does not respond.
+#[tracing::instrument]
+async fn send_keepalive(name: NodeName, keepalive: Keepalive) {
+    let interval = Duration::from_secs(cmp::max(keepalive.interval_secs, 1));
+    let mut failures = 0;
+    loop {
+        time::delay_for(interval).await;
+        let pid = {
+            // scope for lock guard
+            let mut store = NODE_STORE.lock();
+            match store.get(&name) {
+                Some(Node::Connected { .. }) => store.infos.get(&name).and_then(|info| info.pid),
+                Some(_) => {
+                    failures = 0;
+                    continue;
+                }
+                None => {
+                    let _ = store.keepalives.remove(&name);
+                    debug!("keepalive stopped");
+                    return;
+                }
+            }
+        }; // lock ends here
+
+        match time::timeout(interval, request(name.clone(), Request::Ping)).await {
+            Ok(Ok(Response::Pong)) => failures = 0,
+            res => {
+                failures += 1;
+                debug!(?res, %failures, "no response to keepalive");
+            }
+        }
+        if failures >= keepalive.count {
+            warn!(
+                ?pid,
+                "node does not respond. terminating the transport command"
+            );
+            if let Some(pid) = pid {
+                let _ = signal::kill(unistd::Pid::from_raw(pid as i32), Signal::SIGTERM);
+            }
+            failures = 0;
+        }
+    }
+}
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Re-runs the transport command until the connection to the node is re-established.
+#[tracing::instrument(skip(command, args))]
+async fn reconnect(name: NodeName, command: OsString, args: Vec<OsString>, options: LinkOptions) {
+    let mut delay = Duration::from_secs(1);
+    loop {
+        time::delay_for(delay).await;
+        {
+            // scope for lock guard
+            let store = NODE_STORE.lock();
+            let disconnected = matches!(store.get(&name), Some(Node::Disconnected));
+            if !disconnected || !store.transports.contains_key(&name) {
+                debug!("reconnection cancelled");
+                return;
+            }
+        } // lock ends here
+          // the keepalive task is kept running during reconnection
+        let options = LinkOptions {
+            keepalive: None,
+            ..options.clone()
+        };
+        match open(name.clone(), command.clone(), args.clone(), options).await {
+            Ok(()) => return,
+            Err(e) => warn!(error = %e, ?delay, "failed to reconnect"),
+        }
+        delay = cmp::min(delay * 2, MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Chooses the name of the node launched by the transport command.
+///
+/// Unless a name is requested, the root daemon reuses the name assigned to the same command last
+/// time if it is not used.
+///
+/// The name is reserved until the node is connected or `release_name` is called.
+pub(crate) fn assign_name(requested: Option<NodeName>, command: &[String]) -> Result<NodeName> {
+    let mut store = NODE_STORE.lock();
+    let name = if let Some(name) = requested {
+        validate_name(&name)?;
+        ensure!(
+            store.is_available(&name),
+            "node name already used: {}",
+            name
+        );
+        name
+    } else {
+        let remembered = if store.parent.is_none() {
+            names::lookup(command).filter(|name| store.is_available(name))
+        } else {
+            None
+        };
+        remembered.unwrap_or_else(|| store.new_name())
+    };
+    store.reserve(name.clone());
+    Ok(name)
+}
+
+/// Releases the name reserved by `assign_name` if no node has taken it.
+pub(crate) fn release_name(name: &NodeName) {
+    let mut store = NODE_STORE.lock();
+    if store.is_reserved(name) {
+        let _ = store.nodes.remove(name);
+    }
+}
+
+/// Remembers the name of the node launched by the transport command on the root daemon.
+pub(crate) fn remember_name(command: &[String], name: &NodeName) {
+    if NODE_STORE.lock().parent.is_none() {
+        names::remember(command, name);
+    }
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    ensure!(
+        name.starts_with(|c: char| c.is_ascii_alphanumeric()),
+        "node name must start with an alphanumeric character: {:?}",
+        name
+    );
+    ensure!(
+        name.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'),
+        "node name must consist of alphanumeric characters, '-', '_' and '.': {:?}",
+        name
+    );
+    ensure!(name.len() <= 64, "node name is too long: {:?}", name);
+    Ok(())
+}
+
+/// Returns the information of all nodes known by this node.
+pub(crate) fn list_nodes() -> Vec<NodeInfo> {
+    let store = NODE_STORE.lock();
+    let mut nodes = store.infos.values().cloned().collect::<Vec<_>>();
+    nodes.sort_by(|a, b| a.name.cmp(&b.name));
+    nodes
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Spawns tasks transferring messages between this node and the adjacent node.
+fn spawn_link(
+    neighbor: NodeName,
+    mut reader: common::FramedRead<Message, impl AsyncRead + Send + Unpin + 'static>,
+    mut writer: common::FramedWrite<Message, impl AsyncWrite + Send + Unpin + 'static>,
+    rx: metrics::Receiver<Message>,
+) {
+    let traffic = NODE_STORE
+        .lock()
+        .traffic
+        .entry(neighbor.clone())
+        .or_default()
+        .clone();
+    // the traffic is counted by the codec, including the headers of the frames
+    writer
+        .get_mut()
+        .encoder_mut()
+        .count_traffic(traffic.sent.clone());
+    reader
+        .get_mut()
+        .decoder_mut()
+        .count_traffic(traffic.received.clone());
+    let span = tracing::info_span!(parent: None, "link", %neighbor);
+    tokio::spawn(
+        async move {
+            if let Err(e) = send_messages(writer, rx).await {
+                warn!(error = %e, "failed to send messages");
+            }
+        }
+        .instrument(span.clone()),
+    );
+    tokio::spawn(
+        async move {
+            if let Err(e) = recv_messages(&neighbor, reader).await {
+                warn!(error = %e, "failed to receive messages");
+            }
+            link_closed(&neighbor);
+        }
+        .instrument(span),
+    );
+}
+
+static SHUTDOWN: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Makes the daemon exit.
+pub(crate) fn request_shutdown() {
+    SHUTDOWN.notify();
+}
+
+/// Completes when the daemon should exit.
+pub(crate) async fn wait_shutdown() {
+    SHUTDOWN.notified().await
+}
+
+/// Handles the loss of the link to the adjacent node.
+fn link_closed(neighbor: &NodeName) {
+    let (lost, transport) = {
+        // scope for lock guard
+        let mut store = NODE_STORE.lock();
+        if store.parent.as_ref() == Some(neighbor) {
+            info!("link to the parent node closed");
+            request_shutdown();
+            return;
+        }
+        let lost = store.disconnect_link(neighbor);
+        (lost, store.transports.get(neighbor).cloned())
+    }; // lock ends here
+
+    nodes_lost(lost);
+    // notify after the route deletion is announced
+    let waiters = NODE_STORE.lock().link_waiters.remove(neighbor);
+    for tx in waiters.unwrap_or_default() {
+        let _ = tx.send(());
+    }
+    if let Some((command, args, options)) = transport {
+        tokio::spawn(reconnect(neighbor.clone(), command, args, options));
+    }
+}
+
+/// Cleans up the states related to the disconnected nodes and notifies the parent node.
+fn nodes_lost(lost: Vec<NodeName>) {
+    if lost.is_empty() {
+        return;
+    }
+    info!(nodes = ?lost, "nodes disconnected");
+    for name in &lost {
+        event::publish(Event::NodeLost { name: name.clone() });
+    }
+
+    session::close_peers(&lost);
+    {
+        // scope for lock guard
+        let mut pending = PENDING_REQUESTS.lock();
+        let ids = pending
+            .iter()
+            .filter(|(_, (dst, _))| lost.contains(dst))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            if let Some((dst, tx)) = pending.remove(&id) {
+                let _ = tx.send(Response::Err(format!("node disconnected: {}", dst)));
+            }
+        }
+    } // lock ends here
+
+    announce(MessageBody::RouteDel {
+        names: lost,
+        closed: false,
+    });
+}
+
+async fn send_messages(
+    mut writer: common::FramedWrite<Message, impl AsyncWrite + Unpin>,
+    mut rx: metrics::Receiver<Message>,
+) -> Result<()> {
+    while let Some(msg) = rx.recv().await {
+        trace!(?msg, "sending message");
+        writer.send(msg).await?;
+    }
+    Ok(())
+}
+
+async fn recv_messages(
+    neighbor: &NodeName,
+    mut reader: common::FramedRead<Message, impl AsyncRead + Unpin>,
+) -> Result<()> {
+    while let Some(msg) = reader.next().await {
+        let msg = msg?;
+        trace!(?msg, "message received");
+        if let Err(e) = route(Some(neighbor), msg).await {
+            warn!(error = %e, "failed to route message");
+        }
+    }
+    debug!("link closed");
+    Ok(())
+}
+
+/// Delivers the message to this node or forwards it to the next hop.
+async fn route(from: Option<&NodeName>, msg: Message) -> Result<()> {
+    let next_hop = {
+        // scope for lock guard
+        let store = NODE_STORE.lock();
+        if msg.dst == store.my_name {
+            None
+        } else {
+            Some(store.next_hop(&msg.dst))
+        }
+    }; // lock ends here
+
+    match next_hop {
+        None => {
+            let Message { src, dst, body } = msg;
+            let body = match body {
+                MessageBody::Sealed(sealed) => crypto::open(&src, &dst, sealed)?,
+                // messages between nodes must be sealed
+                MessageBody::Request(..) | MessageBody::Response(..) | MessageBody::Session(..)
+                    if from.is_some() =>
+                {
+                    bail!("unsealed message from {}", src)
+                }
+                body => body,
+            };
+            match body {
+                // session data must be passed to the session in order
+                MessageBody::Session(id, data) => session::dispatch(src, id, data).await,
+                body => deliver(from, Message { src, dst, body }),
+            }
+        }
+        Some(Some(mut sender)) => {
+            let dst = msg.dst.clone();
+            sender
+                .send(msg)
+                .await
+                .map_err(|_| eyre!("link to the next hop is closed: {}", dst))?;
+        }
+        Some(None) => {
+            let Message { src, dst, body } = msg;
+            let from = match from {
+                Some(from) => from,
+                None => bail!("destination unreachable: {}", dst),
+            };
+            warn!(%src, %dst, "destination unreachable");
+
+            // tell the requester that the request cannot be delivered.
+            // the response goes back through the link the request came from.
+            if let MessageBody::Request(id, _) = body {
+                let (my_name, sender) = {
+                    // scope for lock guard
+                    let store = NODE_STORE.lock();
+                    (store.my_name.clone(), store.next_hop(from))
+                }; // lock ends here
+                if let Some(mut sender) = sender {
+                    let res = Response::Err(format!("destination unreachable: {}", dst));
+                    let sealed = crypto::seal(&my_name, &src, &MessageBody::Response(id, res))?;
+                    let msg = Message {
+                        src: my_name,
+                        dst: src,
+                        body: MessageBody::Sealed(sealed),
+                    };
+                    let _ = sender.send(msg).await;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Closes the connection to the node and removes it from the network.
+pub(crate) async fn close(name: NodeName) -> Result<()> {
+    let parent = {
+        // scope for lock guard
+        let store = NODE_STORE.lock();
+        let info = store
+            .infos
+            .get(&name)
+            .ok_or_else(|| eyre!("unknown node: {}", name))?;
+        info.parent
+            .clone()
+            .ok_or_else(|| eyre!("cannot close the local node: {}", name))?
+    }; // lock ends here
+
+    if parent == NODE_STORE.lock().my_name {
+        return close_link(name).await;
+    }
+    match request(parent, Request::Close { name }).await? {
+        Response::Ok => Ok(()),
+        res => bail!("unexpected response received: {:?}", res),
+    }
+}
+
+/// Terminates the adjacent node and removes it and the nodes reachable through it.
+#[tracing::instrument(err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+async fn close_link(name: NodeName) -> Result<()> {
+    let (closed, pid) = {
+        // scope for lock guard
+        let mut store = NODE_STORE.lock();
+        match store.get(&name) {
+            Some(Node::Connected { .. }) => {}
+            Some(Node::Disconnected) => {}
+            Some(Node::MyNode) => bail!("cannot close the local node: {}", name),
+            Some(Node::Handshake) => bail!("node is in handshake: {}", name),
+            Some(Node::Indirect { .. }) => bail!("node is not adjacent: {}", name),
+            None => bail!("unknown node: {}", name),
+        }
+        // the node should not be reconnected
+        let _ = store.transports.remove(&name);
+        let closed = match store.get(&name) {
+            Some(Node::Connected { .. }) => {
+                let (tx, rx) = oneshot::channel();
+                store.link_waiters.entry(name.clone()).or_default().push(tx);
+                Some(rx)
+            }
+            _ => None,
+        };
+        (closed, store.infos.get(&name).and_then(|info| info.pid))
+    }; // lock ends here
+
+    if let Some(closed) = closed {
+        let shutdown = async {
+            // the node may exit before sending the response
+            if let Err(e) = request(name.clone(), Request::Shutdown).await {
+                debug!(error = %e, "failed to receive shutdown response");
+            }
+            let _ = closed.await;
+        };
+        if time::timeout(CLOSE_TIMEOUT, shutdown).await.is_err() {
+            warn!(
+                ?pid,
+                "node did not exit in time. terminating the transport command"
+            );
+            if let Some(pid) = pid {
+                let _ = signal::kill(unistd::Pid::from_raw(pid as i32), Signal::SIGTERM);
+            }
+        }
+    }
+
+    let removed = NODE_STORE.lock().remove_subtree(&name);
+    debug!(nodes = ?removed, "nodes removed");
+    for name in &removed {
+        event::publish(Event::NodeRemoved { name: name.clone() });
     }
+    announce(MessageBody::RouteDel {
+        names: removed,
+        closed: true,
+    });
+    Ok(())
+}
+
+/// Waits for the sessions to finish and terminates the child nodes.
+pub(crate) async fn shutdown(drain_timeout: Duration) {
+    if time::timeout(drain_timeout, session::drain())
+        .await
+        .is_err()
+    {
+        warn!("sessions are still running. shutting down anyway");
+    }
+    let children = {
+        // scope for lock guard
+        let store = NODE_STORE.lock();
+        store
+            .nodes
+            .iter()
+            .filter(|(name, node)| {
+                matches!(node, Node::Connected { .. } | Node::Disconnected)
+                    && store.parent.as_ref() != Some(*name)
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>()
+    }; // lock ends here
+    let _ = future::join_all(children.into_iter().map(close_link)).await;
+}
+
+/// Sends a message originated from this node.
+pub(super) async fn send(dst: NodeName, body: MessageBody) -> Result<()> {
+    let src = NODE_STORE.lock().my_name.clone();
+    let body = match body {
+        MessageBody::Request(..) | MessageBody::Response(..) | MessageBody::Session(..)
+            if dst != src =>
+        {
+            MessageBody::Sealed(crypto::seal(&src, &dst, &body)?)
+        }
+        body => body,
+    };
+    route(None, Message { src, dst, body }).await
+}
+
+fn deliver(from: Option<&NodeName>, msg: Message) {
+    let Message { src, dst: _, body } = msg;
+    match body {
+        MessageBody::RouteAdd(info) => {
+            let via = match from {
+                Some(via) => via.clone(),
+                None => return,
+            };
+            if let Err(e) = add_route(info, via) {
+                warn!(error = %e, "failed to add route");
+            }
+        }
+        MessageBody::RouteDel { names, closed } => {
+            let via = match from {
+                Some(via) => via,
+                None => return,
+            };
+            let deleted = NODE_STORE.lock().delete_routes(via, &names, closed);
+            if closed {
+                for name in &deleted {
+                    event::publish(Event::NodeRemoved { name: name.clone() });
+                }
+                announce(MessageBody::RouteDel { names, closed });
+            } else {
+                nodes_lost(deleted);
+            }
+        }
+        MessageBody::Request(id, req) => {
+            tokio::spawn(async move {
+                let is_shutdown = matches!(req, Request::Shutdown);
+                let res = handle_request(&src, req).await;
+                // refused shutdown requests are ignored
+                let is_shutdown = is_shutdown && matches!(res, Response::Ok);
+                if let Err(e) = send(src, MessageBody::Response(id, res)).await {
+                    warn!(error = %e, "failed to send response");
+                }
+                if is_shutdown {
+                    request_shutdown();
+                }
+            });
+        }
+        MessageBody::Response(id, res) => {
+            match PENDING_REQUESTS.lock().remove(&id) {
+                Some((_, tx)) => {
+                    let _ = tx.send(res);
+                }
+                None => debug!(%id, "response for unknown request"),
+            };
+        }
+        MessageBody::Session(..) | MessageBody::Sealed(..) => unreachable!(),
+    }
+}
+
+#[tracing::instrument(err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+fn add_route(info: NodeInfo, via: NodeName) -> Result<()> {
+    {
+        // scope for lock guard
+        let mut store = NODE_STORE.lock();
+        if let Some(key) = info.public_key {
+            // the key may change only when the node is reconnected by its parent
+            let reconnected = matches!(store.get(&info.name), Some(Node::Disconnected))
+                && matches!(store.infos.get(&info.name), Some(old) if old.parent == info.parent);
+            if reconnected {
+                crypto::replace_key(info.name.clone(), key)?;
+            } else {
+                crypto::add_key(info.name.clone(), key)?;
+            }
+        }
+        store.insert_with_name(info.clone(), Node::Indirect { via })?;
+    } // lock ends here
+    debug!("route added");
+    event::publish(Event::NodeConnected(info.clone()));
+    announce(MessageBody::RouteAdd(info));
+    Ok(())
+}
+
+static ANNOUNCEMENTS: Lazy<mpsc::UnboundedSender<MessageBody>> = Lazy::new(|| {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(body) = rx.recv().await {
+            let parent = NODE_STORE.lock().parent.clone();
+            if let Some(parent) = parent {
+                if let Err(e) = send(parent, body).await {
+                    warn!(error = %e, "failed to announce route change");
+                }
+            }
+        }
+    });
+    tx
+});
+
+/// Notifies the parent node of the route change.
+///
+/// Route changes are sent in the order of this function calls.
+fn announce(body: MessageBody) {
+    let _ = ANNOUNCEMENTS.send(body);
+}
+
+async fn handle_request(src: &NodeName, req: Request) -> Response {
+    trace!(%src, ?req, "request received");
+    let res = match req {
+        Request::Ping => Ok(Response::Pong),
+        Request::Open {
+            name,
+            command,
+            args,
+            options,
+        } => async {
+            authorize_ancestor(src, "open a link")?;
+            policy::get()?.authorize_forwarding()?;
+            open(name, command, args, options).await
+        }
+        .await
+        .map(|()| Response::Ok),
+        Request::Spawn {
+            session,
+            command,
+            env_vars,
+            pty,
+        } => async {
+            // not even the local node may spawn processes through the mesh, so the root daemon
+            // never does
+            ensure!(
+                NODE_STORE.lock().is_ancestor(src),
+                "{} is not an ancestor of this node and may not spawn a process",
+                src
+            );
+            session::spawn(src.clone(), session, command, env_vars, pty).await
+        }
+        .await
+        .map(|()| Response::Ok),
+        Request::Close { name } => async {
+            authorize_ancestor(src, "close a link")?;
+            close_link(name).await
+        }
+        .await
+        .map(|()| Response::Ok),
+        Request::Shutdown => authorize_ancestor(src, "shut down the node").map(|()| Response::Ok),
+    };
+    res.unwrap_or_else(|e| Response::Err(format!("{:#}", e)))
+}
 
+/// Refuses the request unless it is sent by an ancestor of this node.
+///
+/// Requests which run commands on this node or change its links are accepted only from the nodes
+/// which (transitively) launched this node, since the descendants may run on less trusted hosts.
+/// Requests originated from this node itself come from the local socket, which is trusted.
+fn authorize_ancestor(src: &NodeName, action: &str) -> Result<()> {
+    let store = NODE_STORE.lock();
+    ensure!(
+        *src == store.my_name || store.is_ancestor(src),
+        "{} is not an ancestor of this node and may not {}",
+        src,
+        action
+    );
     Ok(())
 }
 
+type PendingRequests = HashMap<RequestId, (NodeName, oneshot::Sender<Response>)>;
+
+static PENDING_REQUESTS: Lazy<Mutex<PendingRequests>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Sends a request to the node and waits for its response.
+pub(crate) async fn request(dst: NodeName, req: Request) -> Result<Response> {
+    let (id, rx) = {
+        // scope for lock guard
+        let mut pending = PENDING_REQUESTS.lock();
+        let id = loop {
+            let id = rand::random();
+            if !pending.contains_key(&id) {
+                break id;
+            }
+        };
+        let (tx, rx) = oneshot::channel();
+        let _ = pending.insert(id, (dst.clone(), tx));
+        (id, rx)
+    }; // lock ends here
+    let rx = PendingRequest { id, rx };
+
+    send(dst, MessageBody::Request(id, req)).await?;
+    match rx.await? {
+        Response::Err(msg) => bail!("error received from node: {}", msg),
+        res => Ok(res),
+    }
+}
+
+/// Receiver of a response, which unregisters the pending request on drop.
+#[derive(Debug)]
+struct PendingRequest {
+    id: RequestId,
+    rx: oneshot::Receiver<Response>,
+}
+
+impl Future for PendingRequest {
+    type Output = Result<Response>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map_err(|_| eyre!("request cancelled"))
+    }
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        let _ = PENDING_REQUESTS.lock().remove(&self.id);
+    }
+}
+
 #[derive(custom_debug::Debug)]
+#[allow(clippy::enum_variant_names)]
 enum Node {
     MyNode,
     Handshake,
-    Connected { sender: mpsc::Sender<Message> },
+    Connected {
+        sender: metrics::Sender<Message>,
+    },
+    /// Node reachable through the adjacent node `via`
+    Indirect {
+        via: NodeName,
+    },
+    /// Node which was reachable before but the connection is lost
+    Disconnected,
+}
+
+#[derive(Debug, Default)]
+struct LinkTraffic {
+    sent: Arc<metrics::TrafficCounter>,
+    received: Arc<metrics::TrafficCounter>,
+}
+
+/// Adds the traffic and the queue depths of the links to the adjacent nodes.
+pub(super) fn collect_stats(stats: &mut cli::Stats) {
+    let store = NODE_STORE.lock();
+    let mut nodes = store
+        .traffic
+        .iter()
+        .map(|(name, traffic)| cli::NodeStats {
+            name: name.clone(),
+            sent: traffic.sent.get(),
+            received: traffic.received.get(),
+        })
+        .collect::<Vec<_>>();
+    nodes.sort_by(|a, b| a.name.cmp(&b.name));
+    stats.nodes.extend(nodes);
+
+    let mut queues = store
+        .nodes
+        .iter()
+        .filter_map(|(name, node)| match node {
+            Node::Connected { sender } => Some(sender.queue().stats(format!("link:{}", name))),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    queues.sort_by(|a, b| a.name.cmp(&b.name));
+    stats.queues.extend(queues);
 }
 
 static NODE_STORE: Lazy<Mutex<NodeStore>> = Lazy::new(|| {
     Mutex::new(NodeStore {
         my_name: NodeName::default(),
+        parent: None,
+        ancestors: vec![],
         nodes: HashMap::new(),
+        infos: HashMap::new(),
+        transports: HashMap::new(),
+        keepalives: HashSet::new(),
+        link_waiters: HashMap::new(),
+        traffic: HashMap::new(),
         name_gen: namegen::Generator::with_rng(StdRng::from_entropy()),
     })
 });
@@ -158,15 +1228,32 @@ static NODE_STORE: Lazy<Mutex<NodeStore>> = Lazy::new(|| {
 #[derive(Debug)]
 struct NodeStore {
     my_name: NodeName,
+    parent: Option<NodeName>,
+    /// Nodes on the path to the root, from the parent to the root
+    ancestors: Vec<NodeName>,
     nodes: HashMap<NodeName, Node>,
+    infos: HashMap<NodeName, NodeInfo>,
+    /// Transport commands to re-run when the connection to the adjacent node is lost
+    transports: HashMap<NodeName, (OsString, Vec<OsString>, LinkOptions)>,
+    /// Nodes to which keepalive requests are sent
+    keepalives: HashSet<NodeName>,
+    /// Senders notified when the link to the adjacent node is closed
+    link_waiters: HashMap<NodeName, Vec<oneshot::Sender<()>>>,
+    /// Traffic of the links to the adjacent nodes, which is kept while the node is reconnected
+    traffic: HashMap<NodeName, Arc<LinkTraffic>>,
     name_gen: namegen::Generator<'static, StdRng>,
 }
 
 impl NodeStore {
-    fn insert_with_name(&mut self, name: impl Into<NodeName>, node: Node) -> Result<()> {
-        let name = name.into();
+    fn insert_with_name(&mut self, info: NodeInfo, node: Node) -> Result<()> {
+        let name = info.name.clone();
         assert!(!name.is_empty());
 
+        // the ancestors other than the parent are not in `nodes`, and their names must not be taken
+        // by the descendants
+        if self.is_ancestor(&name) {
+            bail!("node name already used by an ancestor: {}", name);
+        }
         if let Node::MyNode = node {
             if !self.my_name.is_empty() {
                 bail!("my node name is already registered");
@@ -174,22 +1261,131 @@ impl NodeStore {
             self.my_name = name.clone();
         }
 
-        match self.nodes.entry(name) {
+        // disconnected nodes are replaced with the reconnected ones, and reserved names are taken
+        // by the nodes they are reserved for
+        let replaceable =
+            matches!(self.get(&name), Some(Node::Disconnected)) || self.is_reserved(&name);
+        match self.nodes.entry(name.clone()) {
+            Entry::Occupied(mut e) if replaceable => {
+                let _ = e.insert(node);
+            }
             Entry::Occupied(e) => bail!("node name already used: {}", e.key()),
-            Entry::Vacant(e) => e.insert(node),
+            Entry::Vacant(e) => {
+                let _ = e.insert(node);
+            }
         };
+        let _ = self.infos.insert(name, info);
         Ok(())
     }
 
-    fn insert(&mut self, node: Node) -> Result<NodeName> {
-        let name = loop {
+    fn remove(&mut self, name: &NodeName) {
+        crypto::remove_key(name);
+        let _ = self.nodes.remove(name);
+        let _ = self.infos.remove(name);
+        let _ = self.transports.remove(name);
+        let _ = self.traffic.remove(name);
+    }
+
+    /// Removes the node and its descendants, and returns the names of the removed nodes.
+    fn remove_subtree(&mut self, name: &NodeName) -> Vec<NodeName> {
+        let mut removed = vec![name.clone()];
+        let mut i = 0;
+        while i < removed.len() {
+            let children = self
+                .infos
+                .values()
+                .filter(|info| info.parent.as_ref() == Some(&removed[i]))
+                .map(|info| info.name.clone())
+                .collect::<Vec<_>>();
+            removed.extend(children);
+            i += 1;
+        }
+        for name in &removed {
+            self.remove(name);
+        }
+        removed
+    }
+
+    /// Marks the adjacent node and the nodes reachable through it as disconnected.
+    fn disconnect_link(&mut self, neighbor: &NodeName) -> Vec<NodeName> {
+        let lost = self
+            .nodes
+            .iter()
+            .filter(|(name, node)| match node {
+                Node::Connected { .. } | Node::Handshake => *name == neighbor,
+                Node::Indirect { via } => via == neighbor,
+                Node::MyNode | Node::Disconnected => false,
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        self.disconnect(&lost);
+        lost
+    }
+
+    /// Deletes the routes to the nodes reachable through the adjacent node `via`, and returns the
+    /// names of the deleted nodes.
+    ///
+    /// The nodes are marked as disconnected, or removed if they are closed. Closed nodes are
+    /// removed even if they are already disconnected.
+    fn delete_routes(&mut self, via: &NodeName, names: &[NodeName], closed: bool) -> Vec<NodeName> {
+        let deleted = names
+            .iter()
+            .filter(|name| match self.get(*name) {
+                Some(Node::Indirect { via: v }) => v == via,
+                Some(Node::Disconnected) => closed,
+                _ => false,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if closed {
+            for name in &deleted {
+                self.remove(name);
+            }
+        } else {
+            self.disconnect(&deleted);
+        }
+        deleted
+    }
+
+    fn disconnect(&mut self, names: &[NodeName]) {
+        for name in names {
+            let _ = self.nodes.insert(name.clone(), Node::Disconnected);
+            if let Some(info) = self.infos.get_mut(name) {
+                info.state = NodeState::Disconnected;
+            }
+        }
+    }
+
+    /// Returns true if the name can be assigned to a new node.
+    ///
+    /// The names of disconnected nodes are kept for their reconnection.
+    fn is_available(&self, name: &NodeName) -> bool {
+        self.get(name).is_none() && !self.is_ancestor(name)
+    }
+
+    fn new_name(&mut self) -> NodeName {
+        loop {
             let name = NodeName::from(self.name_gen.next().unwrap());
-            if !self.nodes.contains_key(&name) {
+            if !self.nodes.contains_key(&name) && !self.is_ancestor(&name) {
                 break name;
             }
-        };
-        self.insert_with_name(name.clone(), node)?;
-        Ok(name)
+        }
+    }
+
+    /// Reserves the name until the node is connected, so that it is not assigned to another node.
+    ///
+    /// A reserved name is a `Node::Handshake` entry without its information.
+    fn reserve(&mut self, name: NodeName) {
+        let _ = self.nodes.insert(name, Node::Handshake);
+    }
+
+    /// Returns whether the node is on the path from this node to the root.
+    fn is_ancestor(&self, name: &NodeName) -> bool {
+        self.ancestors.contains(name)
+    }
+
+    fn is_reserved(&self, name: &NodeName) -> bool {
+        matches!(self.get(name), Some(Node::Handshake)) && !self.infos.contains_key(name)
     }
 
     fn get<Q>(&self, name: &Q) -> Option<&Node>
@@ -207,4 +1403,83 @@ impl NodeStore {
     {
         self.nodes.get_mut(name)
     }
+
+    fn info_mut<Q>(&mut self, name: &Q) -> Option<&mut NodeInfo>
+    where
+        NodeName: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.infos.get_mut(name)
+    }
+
+    /// Returns the sender of the link to the adjacent node on the path to `dst`.
+    ///
+    /// Messages to unknown nodes are forwarded to the parent node.
+    fn next_hop(&self, dst: &NodeName) -> Option<metrics::Sender<Message>> {
+        let neighbor = match self.get(dst) {
+            Some(Node::Connected { sender }) => return Some(sender.clone()),
+            Some(Node::Indirect { via }) => via,
+            Some(Node::MyNode) | Some(Node::Handshake) | Some(Node::Disconnected) => return None,
+            None => self.parent.as_ref()?,
+        };
+        match self.get(neighbor) {
+            Some(Node::Connected { sender }) => Some(sender.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::SpawnCommand;
+
+    #[tokio::test]
+    async fn refuse_spawn_from_non_ancestors() {
+        let name = |s: &str| NodeName::from(s.to_owned());
+        {
+            // scope for lock guard
+            let mut store = NODE_STORE.lock();
+            let info = NodeInfo::new(name("leaf"), Some(name("parent")), NodeState::Local);
+            store.insert_with_name(info, Node::MyNode).unwrap();
+            store.parent = Some(name("parent"));
+            store.ancestors = vec![name("parent"), name("root")];
+        } // lock ends here
+
+        for src in &["child", "sibling", "leaf"] {
+            let req = Request::Spawn {
+                session: 1,
+                command: SpawnCommand::Program("true".into(), vec![]),
+                env_vars: vec![],
+                pty: None,
+            };
+            match handle_request(&name(src), req).await {
+                Response::Err(msg) => assert!(msg.contains("not an ancestor"), "{}", msg),
+                res => panic!("spawn request from {} accepted: {:?}", src, res),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn refuse_shutdown_from_descendants() {
+        let name = |s: &str| NodeName::from(s.to_owned());
+        NODE_STORE.lock().ancestors = vec![name("parent")];
+
+        let reqs = vec![
+            Request::Shutdown,
+            Request::Close {
+                name: name("sibling"),
+            },
+        ];
+        for req in reqs {
+            match handle_request(&name("child"), req).await {
+                Response::Err(msg) => assert!(msg.contains("not an ancestor"), "{}", msg),
+                res => panic!("request from child accepted: {:?}", res),
+            }
+        }
+        assert!(matches!(
+            handle_request(&name("parent"), Request::Shutdown).await,
+            Response::Ok
+        ));
+    }
 }
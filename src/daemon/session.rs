@@ -0,0 +1,406 @@
+use super::{event, network};
+use crate::{
+    audit,
+    endpoint::process,
+    metrics, policy,
+    prelude::*,
+    protocol::{
+        cli::{self, Event},
+        network::{MessageBody, NodeName, Request, Response, SessionData, SessionId},
+        ChannelData, ExitStatus, PtyParam, SpawnCommand,
+    },
+    terminal, Result,
+};
+use futures_core::Stream;
+use nix::{
+    libc,
+    sys::signal::{self, Signal},
+    unistd,
+};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::OpenOptions,
+    os::unix::{fs::OpenOptionsExt as _, io::AsRawFd as _},
+    pin::Pin,
+    process::Stdio,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    process::Command,
+    sync::{mpsc, Notify},
+};
+
+type SessionKey = (NodeName, SessionId);
+
+static SESSIONS: Lazy<Mutex<HashMap<SessionKey, mpsc::Sender<SessionData>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+/// Traffic of the sessions of the processes spawned by the peers
+static TRAFFIC: Lazy<Mutex<HashMap<SessionKey, Arc<SessionTraffic>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+/// Notified when the last session is finished
+static DRAINED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Registers a session with `peer` and returns the receiver of data sent by the peer.
+fn register(peer: NodeName, id: SessionId) -> Option<SessionReceiver> {
+    let mut sessions = SESSIONS.lock();
+    let key = (peer, id);
+    if sessions.contains_key(&key) {
+        return None;
+    }
+    let (tx, rx) = mpsc::channel(64);
+    let _ = sessions.insert(key.clone(), tx);
+    Some(SessionReceiver { key, rx })
+}
+
+fn unregister(key: &SessionKey) {
+    let _ = TRAFFIC.lock().remove(key);
+    let mut sessions = SESSIONS.lock();
+    let _ = sessions.remove(key);
+    if sessions.is_empty() {
+        DRAINED.notify();
+    }
+}
+
+#[derive(Debug, Default)]
+struct SessionTraffic {
+    /// Output of the process sent to the peer
+    sent: metrics::TrafficCounter,
+    /// Input of the process received from the peer
+    received: metrics::TrafficCounter,
+}
+
+/// Adds the traffic of the sessions of the spawned processes.
+pub(super) fn collect_stats(stats: &mut cli::Stats) {
+    let mut sessions = TRAFFIC
+        .lock()
+        .iter()
+        .map(|((node, id), traffic)| cli::SessionStats {
+            node: node.clone(),
+            id: *id,
+            sent: traffic.sent.get(),
+            received: traffic.received.get(),
+        })
+        .collect::<Vec<_>>();
+    sessions.sort_by(|a, b| (&a.node, a.id).cmp(&(&b.node, b.id)));
+    stats.sessions.extend(sessions);
+}
+
+#[derive(Debug)]
+pub(super) struct SessionReceiver {
+    key: SessionKey,
+    rx: mpsc::Receiver<SessionData>,
+}
+
+impl Stream for SessionReceiver {
+    type Item = SessionData;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+impl Drop for SessionReceiver {
+    fn drop(&mut self) {
+        unregister(&self.key);
+    }
+}
+
+/// Passes the data received from `peer` to the session.
+pub(super) async fn dispatch(peer: NodeName, id: SessionId, data: SessionData) {
+    if let SessionData::Exit(status) = &data {
+        event::publish(Event::SessionExited {
+            node: peer.clone(),
+            session: id,
+            status: *status,
+        });
+    }
+    let tx = SESSIONS.lock().get(&(peer, id)).cloned();
+    match tx {
+        Some(mut tx) => {
+            // the session may have been finished
+            let _ = tx.send(data).await;
+        }
+        None => debug!(%id, ?data, "data for unknown session"),
+    }
+}
+
+/// Waits until all sessions are finished.
+pub(super) async fn drain() {
+    loop {
+        // a notification sent before waiting is kept by `Notify`
+        let drained = DRAINED.notified();
+        if SESSIONS.lock().is_empty() {
+            return;
+        }
+        drained.await;
+    }
+}
+
+/// Closes the sessions with the disconnected nodes.
+///
+/// The processes spawned for the nodes are hung up.
+pub(super) fn close_peers(peers: &[NodeName]) {
+    let mut sessions = SESSIONS.lock();
+    sessions.retain(|(peer, _), tx| {
+        if !peers.contains(peer) {
+            return true;
+        }
+        let _ = tx.try_send(SessionData::Hangup);
+        false
+    });
+    if sessions.is_empty() {
+        DRAINED.notify();
+    }
+}
+
+/// Sends the data to the session on `peer`.
+pub(super) async fn send(peer: NodeName, id: SessionId, data: SessionData) -> Result<()> {
+    network::send(peer, MessageBody::Session(id, data)).await
+}
+
+/// Spawns a process on `node` and returns the receiver of its output.
+pub(super) async fn start(
+    node: NodeName,
+    command: SpawnCommand,
+    env_vars: Vec<(OsString, OsString)>,
+    pty: Option<PtyParam>,
+) -> Result<(SessionId, SessionReceiver)> {
+    let (id, rx) = loop {
+        let id = rand::random();
+        if let Some(rx) = register(node.clone(), id) {
+            break (id, rx);
+        }
+    };
+
+    let req = Request::Spawn {
+        session: id,
+        command,
+        env_vars,
+        pty,
+    };
+    match network::request(node.clone(), req).await? {
+        Response::Ok => {
+            event::publish(Event::SessionStarted { node, session: id });
+            Ok((id, rx))
+        }
+        res => bail!("unexpected response received: {:?}", res),
+    }
+}
+
+/// Spawns a process requested by `peer`.
+#[tracing::instrument(err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+pub(super) async fn spawn(
+    peer: NodeName,
+    id: SessionId,
+    command: SpawnCommand,
+    mut env_vars: Vec<(OsString, OsString)>,
+    pty: Option<PtyParam>,
+) -> Result<()> {
+    let mut audit = audit::Spawn::new(
+        format!("node:{}", peer),
+        id,
+        &command,
+        &env_vars,
+        pty.is_some(),
+    );
+    let rx = register(peer.clone(), id).ok_or_else(|| eyre!("session id already used: {}", id))?;
+    let spawned = policy::get()
+        .and_then(|policy| policy.authorize_spawn(command, &mut env_vars, pty.is_some()))
+        .and_then(|command| {
+            audit.authorized(&command);
+            spawn_process(command, env_vars, pty)
+        });
+    let (pty_name, pid, status, stdin, stdout, stderr) = match spawned {
+        Ok(spawned) => spawned,
+        Err(e) => {
+            audit.failed(&e);
+            unregister(&(peer, id));
+            return Err(e);
+        }
+    };
+    debug!(%pid, "process spawned");
+
+    let audit = audit.started();
+    let stdin = Box::new(audit.count_input(stdin));
+    let stdout = Box::new(audit.count_output(stdout));
+    let stderr = stderr.map(|stderr| Box::new(audit.count_output(stderr)) as _);
+
+    let traffic = Arc::new(SessionTraffic::default());
+    let _ = TRAFFIC
+        .lock()
+        .insert((peer.clone(), id), Arc::clone(&traffic));
+    tokio::spawn(write_input(rx, Arc::clone(&traffic), stdin, pty_name, pid));
+    tokio::spawn(async move {
+        let stdout = relay_output(peer.clone(), id, &traffic.sent, stdout, SessionData::Stdout);
+        let stderr = async {
+            match stderr {
+                Some(stderr) => {
+                    relay_output(peer.clone(), id, &traffic.sent, stderr, SessionData::Stderr).await
+                }
+                None => Ok(()),
+            }
+        };
+        let (status, stdout, stderr) = tokio::join!(status, stdout, stderr);
+        for res in &[stdout, stderr] {
+            if let Err(e) = res {
+                warn!(error = %e, "failed to relay output");
+            }
+        }
+        let status = match status {
+            Ok(status) => ExitStatus::from(status),
+            Err(e) => {
+                warn!(error = %e, "failed to wait process");
+                ExitStatus::Code(255)
+            }
+        };
+        debug!(?status, "process exited");
+        audit.exited(Some(status)).await;
+        if let Err(e) = send(peer.clone(), id, SessionData::Exit(status)).await {
+            warn!(error = %e, "failed to send exit status");
+        }
+        // stop writing to the stdin of the exited process
+        unregister(&(peer, id));
+    });
+
+    Ok(())
+}
+
+type Spawned = (
+    Option<String>,
+    u32,
+    process::ExitStatusFuture,
+    Box<dyn AsyncWrite + Send + Unpin>,
+    Box<dyn AsyncRead + Send + Unpin>,
+    Option<Box<dyn AsyncRead + Send + Unpin>>,
+);
+
+fn spawn_process(
+    command: SpawnCommand,
+    env_vars: Vec<(OsString, OsString)>,
+    pty: Option<PtyParam>,
+) -> Result<Spawned> {
+    if let SpawnCommand::Subsystem(name) = &command {
+        bail!("subsystem is not supported in daemon sessions: {}", name);
+    }
+
+    let mut std_command = process::new_command(command, env_vars)?;
+    let spawned = if let Some(param) = pty {
+        let (slave_name, child, pty_master) = process::spawn_with_pty(std_command, param)?;
+        let (stdout, stdin) = io::split(pty_master);
+        (
+            Some(slave_name),
+            child.id(),
+            Box::new(child) as process::ExitStatusFuture,
+            Box::new(stdin) as Box<dyn AsyncWrite + Send + Unpin>,
+            Box::new(stdout) as Box<dyn AsyncRead + Send + Unpin>,
+            None,
+        )
+    } else {
+        std_command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = Command::from(std_command).spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+        (
+            None,
+            child.id(),
+            Box::new(child) as _,
+            Box::new(stdin) as _,
+            Box::new(stdout) as _,
+            Some(Box::new(stderr) as Box<dyn AsyncRead + Send + Unpin>),
+        )
+    };
+    Ok(spawned)
+}
+
+async fn write_input(
+    mut rx: SessionReceiver,
+    traffic: Arc<SessionTraffic>,
+    stdin: Box<dyn AsyncWrite + Send + Unpin>,
+    pty_name: Option<String>,
+    pid: u32,
+) {
+    // dropped on shutdown, since shutting down the stdin of a child process does not close it
+    let mut stdin = Some(stdin);
+    while let Some(data) = rx.next().await {
+        let res = match data {
+            SessionData::Stdin(ChannelData::Output(data)) => {
+                async {
+                    traffic.received.add(data.len() as u64);
+                    if let Some(stdin) = &mut stdin {
+                        stdin.write_all(&data).await?;
+                        stdin.flush().await?;
+                    }
+                    Ok(())
+                }
+                .await
+            }
+            SessionData::Stdin(ChannelData::WindowSizeChange(width, height)) => {
+                set_window_size(pty_name.as_deref(), width, height)
+            }
+            SessionData::Stdin(ChannelData::Shutdown) => {
+                if let Some(mut stdin) = stdin.take() {
+                    let _ = stdin.shutdown().await;
+                }
+                continue;
+            }
+            SessionData::Hangup => {
+                debug!(%pid, "client hung up");
+                let _ = signal::kill(unistd::Pid::from_raw(pid as i32), Signal::SIGHUP);
+                break;
+            }
+            data => {
+                debug!(?data, "unexpected session data");
+                Ok(())
+            }
+        };
+        if let Err(e) = res {
+            debug!(error = %e, "failed to write input");
+            break;
+        }
+    }
+}
+
+fn set_window_size(pty_name: Option<&str>, width: u16, height: u16) -> Result<()> {
+    if let Some(pty_name) = pty_name {
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(pty_name)?;
+        terminal::set_window_size(slave.as_raw_fd(), width, height)?;
+    }
+    Ok(())
+}
+
+async fn relay_output(
+    peer: NodeName,
+    id: SessionId,
+    traffic: &metrics::TrafficCounter,
+    mut stream: Box<dyn AsyncRead + Send + Unpin>,
+    wrap: fn(ChannelData) -> SessionData,
+) -> Result<()> {
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let n = match stream.read(&mut buf).await {
+            Ok(n) => n,
+            // reading from the pty master fails with EIO after the slave is closed
+            Err(e) if e.raw_os_error() == Some(libc::EIO) => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if n == 0 {
+            break;
+        }
+        traffic.add(n as u64);
+        send(peer.clone(), id, wrap(ChannelData::Output(buf[..n].into()))).await?;
+    }
+    send(peer, id, wrap(ChannelData::Shutdown)).await
+}
@@ -0,0 +1,81 @@
+use crate::{
+    prelude::*,
+    protocol::{
+        self,
+        fs::{DirEntry, Op, Reply, Request, Response},
+    },
+    router, Result,
+};
+use std::io::SeekFrom;
+use tokio::fs::{self, File, OpenOptions};
+
+/// Maximum number of bytes returned by a single read request.
+const MAX_READ_LEN: u64 = 1024 * 1024;
+
+pub(crate) async fn run(req: Request) -> Result<()> {
+    let Request { id, op } = req;
+    trace!(?id, ?op, "fs request received");
+
+    let result = execute(op).await.map_err(Into::into);
+    let mut handler_tx = router::lock().handler_tx();
+    handler_tx
+        .send(protocol::Command::Send(
+            protocol::RemoteCommand::FsResponse(Response { id, result }),
+        ))
+        .map_err(|_| eyre!("send failed"))
+        .await?;
+
+    Ok(())
+}
+
+async fn execute(op: Op) -> io::Result<Reply> {
+    let reply = match op {
+        Op::Stat(path) => Reply::Stat((&fs::symlink_metadata(path).await?).into()),
+        Op::ReadDir(path) => {
+            let mut dir = fs::read_dir(path).await?;
+            let mut entries = vec![];
+            while let Some(entry) = dir.next_entry().await? {
+                entries.push(DirEntry {
+                    name: entry.file_name(),
+                    stat: (&entry.metadata().await?).into(),
+                });
+            }
+            Reply::ReadDir(entries)
+        }
+        Op::Read { path, offset, len } => {
+            let mut file = File::open(path).await?;
+            let _ = file.seek(SeekFrom::Start(offset)).await?;
+            let mut data = vec![];
+            let _ = file
+                .take(len.min(MAX_READ_LEN))
+                .read_to_end(&mut data)
+                .await?;
+            Reply::Read(data)
+        }
+        Op::Write {
+            path,
+            offset,
+            data,
+            truncate,
+        } => {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(truncate)
+                .open(path)
+                .await?;
+            let _ = file.seek(SeekFrom::Start(offset)).await?;
+            file.write_all(&data).await?;
+            Reply::Write(data.len() as u64)
+        }
+        Op::Rename { from, to } => {
+            fs::rename(from, to).await?;
+            Reply::Done
+        }
+        Op::Unlink(path) => {
+            fs::remove_file(path).await?;
+            Reply::Done
+        }
+    };
+    Ok(reply)
+}
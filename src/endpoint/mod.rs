@@ -1,3 +1,5 @@
+pub(crate) mod fs;
 pub(crate) mod process;
 pub(crate) mod sink;
 pub(crate) mod source;
+pub(crate) mod subsystem;
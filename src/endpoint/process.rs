@@ -1,4 +1,7 @@
 use crate::{
+    audit,
+    endpoint::subsystem,
+    policy,
     prelude::*,
     protocol,
     router::{self, ChannelReceiver},
@@ -11,91 +14,71 @@ use std::{
     ffi::OsString,
     fs::OpenOptions,
     future::Future,
-    os::unix::{fs::OpenOptionsExt, io::AsRawFd, process::CommandExt},
+    os::unix::{
+        fs::OpenOptionsExt,
+        io::AsRawFd,
+        process::{CommandExt, ExitStatusExt as _},
+    },
     process::{Command as StdCommand, Stdio},
 };
-use tokio::process::Command;
-use tokio_pty_command::{CommandExt as _, PtyMaster};
+use tokio::{net::UnixStream, process::Command};
+use tokio_pty_command::{Child as PtyChild, CommandExt as _, PtyMaster};
+
+pub(crate) type ExitStatusFuture =
+    Box<dyn Future<Output = io::Result<std::process::ExitStatus>> + Send + Unpin>;
+type Spawned = (
+    Option<String>,
+    ExitStatusFuture,
+    Box<dyn AsyncWrite + Send + Unpin>,
+    Box<dyn AsyncRead + Send + Unpin>,
+);
 
 pub(crate) async fn run(rx: ChannelReceiver, spawn: protocol::Spawn) -> Result<()> {
     let protocol::Spawn {
         id,
         command,
-        env_vars,
+        mut env_vars,
         pty,
     } = spawn;
 
-    let (program, args, arg0) = match command {
-        protocol::SpawnCommand::LoginShell => {
-            let shell = if let Some(passwd) = Passwd::current_user()? {
-                OsString::from(passwd.shell.to_str()?)
-            } else if let Some(shell) = env::var_os("SHELL") {
-                shell
-            } else {
-                panic!("cannot get login shell for the user");
-            };
-            let arg0 = {
-                let mut arg0 = OsString::from("-");
-                arg0.push(&shell);
-                Some(arg0)
-            };
-            (shell, vec![], arg0)
-        }
-        protocol::SpawnCommand::Program(program, args) => (program, args, None),
-    };
+    let mut audit = audit::Spawn::new(audit::peer(), id, &command, &env_vars, pty.is_some());
+    let spawned = policy::get()
+        .and_then(|policy| policy.authorize_spawn(command, &mut env_vars, pty.is_some()))
+        .and_then(|command| {
+            audit.authorized(&command);
+            match command {
+                protocol::SpawnCommand::Subsystem(name) => spawn_subsystem(name),
+                command => spawn_process(command, env_vars, pty),
+            }
+        });
 
-    let mut std_command = StdCommand::new(program);
-    std_command.args(args);
-    if let Some(arg0) = arg0 {
-        std_command.arg0(arg0);
-    }
-    std_command.envs(env_vars);
+    let mut handler_tx = router::lock().handler_tx();
 
-    let (pty_name, status, child_stdin, child_stdout) = if let Some(param) = pty {
-        let pty_master = PtyMaster::open()?;
-        let slave_name = pty_master.slave_name().to_string();
-        {
-            let slave = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .custom_flags(libc::O_NOCTTY)
-                .open(&slave_name)?;
-            terminal::set_window_size(slave.as_raw_fd(), param.width, param.height)?;
+    let (pty_name, status, child_stdin, child_stdout) = match spawned {
+        Ok(spawned) => spawned,
+        Err(e) => {
+            warn!(error = %e, "spawn request denied or failed");
+            audit.failed(&e);
+            handler_tx
+                .send(protocol::Command::Send(
+                    protocol::RemoteCommand::SpawnError(protocol::SpawnError {
+                        id,
+                        message: format!("{:#}", e),
+                    }),
+                ))
+                .map_err(|_| eyre!("send failed"))
+                .await?;
+            return Err(e);
         }
-
-        let child = Command::from(std_command).spawn_with_pty(&pty_master)?;
-        let (child_stdout, child_stdin) = io::split(pty_master);
-        (
-            Some(slave_name),
-            Box::new(child)
-                as Box<dyn Future<Output = io::Result<std::process::ExitStatus>> + Send + Unpin>,
-            Box::new(child_stdin) as Box<dyn AsyncWrite + Send + Unpin>,
-            Box::new(child_stdout) as Box<dyn AsyncRead + Send + Unpin>,
-        )
-    } else {
-        std_command.stdin(Stdio::piped());
-        std_command.stdout(Stdio::piped());
-        std_command.stderr(Stdio::inherit());
-
-        let mut child = Command::from(std_command).spawn()?;
-        let child_stdin = child.stdin.take().unwrap();
-        let child_stdout = child.stdout.take().unwrap();
-
-        (
-            None,
-            Box::new(child) as _,
-            Box::new(child_stdin) as _,
-            Box::new(child_stdout) as _,
-        )
     };
 
-    let mut handler_tx = router::lock().handler_tx();
+    let audit = audit.started();
 
     handler_tx
         .send(protocol::Command::Sink(protocol::Sink {
             id,
             rx,
-            stream: child_stdin,
+            stream: Box::new(audit.count_input(child_stdin)),
             pty_name,
         }))
         .map_err(|_| eyre!("send failed"))
@@ -104,12 +87,14 @@ pub(crate) async fn run(rx: ChannelReceiver, spawn: protocol::Spawn) -> Result<(
     handler_tx
         .send(protocol::Command::Source(protocol::Source {
             id,
-            stream: child_stdout,
+            stream: Box::new(audit.count_output(child_stdout)),
         }))
         .map_err(|_| eyre!("send failed"))
         .await?;
 
-    let code = status.await?;
+    let code = status.await;
+    tokio::spawn(audit.exited(code.as_ref().ok().copied().map(Into::into)));
+    let code = code?;
     handler_tx
         .send(protocol::Command::Send(
             protocol::RemoteCommand::ProcessExit(protocol::ProcessExitStatus {
@@ -122,3 +107,123 @@ pub(crate) async fn run(rx: ChannelReceiver, spawn: protocol::Spawn) -> Result<(
 
     Ok(())
 }
+
+fn spawn_process(
+    command: protocol::SpawnCommand,
+    env_vars: Vec<(OsString, OsString)>,
+    pty: Option<protocol::PtyParam>,
+) -> Result<Spawned> {
+    let mut std_command = new_command(command, env_vars)?;
+
+    let spawned = if let Some(param) = pty {
+        let (slave_name, child, pty_master) = spawn_with_pty(std_command, param)?;
+        let (child_stdout, child_stdin) = io::split(pty_master);
+        (
+            Some(slave_name),
+            Box::new(child) as _,
+            Box::new(child_stdin) as _,
+            Box::new(child_stdout) as _,
+        )
+    } else {
+        std_command.stdin(Stdio::piped());
+        std_command.stdout(Stdio::piped());
+        std_command.stderr(Stdio::inherit());
+
+        let mut child = Command::from(std_command).spawn()?;
+        let child_stdin = child.stdin.take().unwrap();
+        let child_stdout = child.stdout.take().unwrap();
+
+        (
+            None,
+            Box::new(child) as _,
+            Box::new(child_stdin) as _,
+            Box::new(child_stdout) as _,
+        )
+    };
+
+    Ok(spawned)
+}
+
+/// Creates a command running the program specified by `command`.
+pub(crate) fn new_command(
+    command: protocol::SpawnCommand,
+    env_vars: Vec<(OsString, OsString)>,
+) -> Result<StdCommand> {
+    let (program, args, arg0) = match command {
+        protocol::SpawnCommand::LoginShell => {
+            let shell = if let Some(passwd) = Passwd::current_user()? {
+                OsString::from(passwd.shell.to_str()?)
+            } else if let Some(shell) = env::var_os("SHELL") {
+                shell
+            } else {
+                panic!("cannot get login shell for the user");
+            };
+            let arg0 = {
+                let mut arg0 = OsString::from("-");
+                arg0.push(&shell);
+                Some(arg0)
+            };
+            (shell, vec![], arg0)
+        }
+        protocol::SpawnCommand::Program(program, args) => (program, args, None),
+        protocol::SpawnCommand::Subsystem(name) => {
+            bail!("subsystem cannot be run as a program: {}", name)
+        }
+    };
+
+    let mut std_command = StdCommand::new(program);
+    std_command.args(args);
+    if let Some(arg0) = arg0 {
+        std_command.arg0(arg0);
+    }
+    std_command.envs(env_vars);
+    Ok(std_command)
+}
+
+/// Spawns the command with a newly allocated pseudo-terminal.
+///
+/// Returns the name of the slave device, the child process and the master device.
+pub(crate) fn spawn_with_pty(
+    std_command: StdCommand,
+    param: protocol::PtyParam,
+) -> Result<(String, PtyChild, PtyMaster)> {
+    let pty_master = PtyMaster::open()?;
+    let slave_name = pty_master.slave_name().to_string();
+    {
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&slave_name)?;
+        terminal::set_window_size(slave.as_raw_fd(), param.width, param.height)?;
+    }
+
+    let child = Command::from(std_command).spawn_with_pty(&pty_master)?;
+    Ok((slave_name, child, pty_master))
+}
+
+fn spawn_subsystem(name: String) -> Result<Spawned> {
+    let (local, remote) = UnixStream::pair()?;
+
+    let status = tokio::spawn(async move {
+        let (reader, writer) = io::split(local);
+        let code = match subsystem::serve(&name, reader, writer).await {
+            Ok(()) => 0,
+            Err(e) => {
+                warn!(error = %e, subsystem = %name, "subsystem failed");
+                1
+            }
+        };
+        // convert the exit code to the wait status
+        std::process::ExitStatus::from_raw(code << 8)
+    })
+    .map(|res| res.map_err(io::Error::other));
+
+    let (child_stdout, child_stdin) = io::split(remote);
+    Ok((
+        None,
+        Box::new(status),
+        Box::new(child_stdin),
+        Box::new(child_stdout),
+    ))
+}
@@ -0,0 +1,12 @@
+use crate::{prelude::*, sftp, Result};
+
+pub(crate) async fn serve(
+    name: &str,
+    reader: impl AsyncRead + Unpin,
+    writer: impl AsyncWrite + Unpin,
+) -> Result<()> {
+    match name {
+        sftp::SUBSYSTEM_NAME => sftp::serve(reader, writer).await,
+        _ => bail!("unknown subsystem: {}", name),
+    }
+}
@@ -4,15 +4,21 @@ use self::prelude::*;
 use clap::Clap as _;
 use command::Opts;
 
+mod audit;
 mod command;
 mod common;
+mod config;
 mod daemon;
 mod endpoint;
 mod ioctl;
+mod metrics;
+mod policy;
 mod prelude;
 mod protocol;
 mod router;
+mod sftp;
 mod terminal;
+mod transport;
 
 type Error = eyre::Error;
 type Result<T> = eyre::Result<T, Error>;
@@ -21,28 +27,52 @@ type Result<T> = eyre::Result<T, Error>;
 async fn main() -> Result<()> {
     let opts = Opts::parse();
 
-    install_tracing(opts.log_directive());
     color_eyre::install()?;
+    // tracing is configured by the config file
+    let config_res = config::load(opts.config_path());
+    // logs are written to stderr if the log file cannot be opened
+    let log_file_res = opts.log_file().map_or(Ok(()), |path| {
+        common::open_log_file(path, &config::get().log_rotation)
+    });
+    install_tracing(opts.log_directive(), opts.log_format());
+    config_res?;
+    log_file_res?;
 
     command::run(opts).await?;
 
     Ok(())
 }
 
-fn install_tracing(directive: Option<&str>) {
+fn install_tracing(directive: Option<&str>, format: config::LogFormat) {
     use tracing_error::ErrorLayer;
-    use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+    use tracing_subscriber::{fmt, prelude::*, reload, EnvFilter};
 
-    let fmt_layer = fmt::layer().with_writer(std::io::stderr);
-    let filter_layer = directive
+    let (text_layer, json_layer) = match format {
+        config::LogFormat::Text => {
+            let layer = fmt::layer()
+                .with_ansi(!common::has_log_file())
+                .with_writer(common::log_writer);
+            (Some(layer), None)
+        }
+        config::LogFormat::Json => {
+            let layer = fmt::layer().json().with_writer(common::log_writer);
+            (None, Some(layer))
+        }
+    };
+    let filter = directive
         .map(EnvFilter::new)
         .or_else(|| EnvFilter::try_from_default_env().ok())
         .unwrap_or_else(|| EnvFilter::new("info"));
+    let (filter_layer, filter_handle) = reload::Layer::new(filter);
+    common::set_log_filter_handle(filter_handle);
     let error_layer = ErrorLayer::default();
 
+    // events are passed to the layers in the order they are added
     tracing_subscriber::registry()
         .with(filter_layer)
-        .with(fmt_layer)
+        .with(common::LogForwardLayer)
+        .with(text_layer)
+        .with(json_layer)
         .with(error_layer)
         .init();
 }
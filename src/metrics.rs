@@ -0,0 +1,390 @@
+//! Traffic counters and queue depths, reported by `rsrs stats` and the Prometheus endpoint.
+//!
+//! The endpoint is enabled by `rsrs daemon --metrics-addr` or `metrics_addr` in the config file:
+//!
+//! ```toml
+//! metrics_addr = "127.0.0.1:9187"
+//! ```
+//!
+//! The endpoint has no authentication, so it should listen on a loopback address.
+
+use crate::{
+    daemon,
+    prelude::*,
+    protocol::cli::{QueueStats, Stats, Traffic},
+    router, Result,
+};
+use futures_core::Stream;
+use std::{
+    fmt::{self, Write as _},
+    net,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{
+        self,
+        error::{SendError, TrySendError},
+    },
+};
+
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Collects the statistics of this process.
+pub(crate) fn collect() -> Stats {
+    let mut stats = Stats::default();
+    router::lock().collect_stats(&mut stats);
+    daemon::collect_stats(&mut stats);
+    stats
+}
+
+/// Frames and bytes passed through a link or a channel.
+#[derive(Debug, Default)]
+pub(crate) struct TrafficCounter {
+    frames: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl TrafficCounter {
+    pub(crate) fn add(&self, bytes: u64) {
+        let _ = self.frames.fetch_add(1, Ordering::Relaxed);
+        let _ = self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> Traffic {
+        Traffic {
+            frames: self.frames.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Creates a bounded mpsc channel whose queue depth can be observed.
+pub(crate) fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = mpsc::channel(capacity);
+    let queue = Queue {
+        depth: Arc::new(AtomicUsize::new(0)),
+        capacity,
+    };
+    (
+        Sender {
+            tx,
+            queue: queue.clone(),
+        },
+        Receiver { rx, queue },
+    )
+}
+
+/// Depth of the queue of a channel.
+#[derive(Debug, Clone)]
+pub(crate) struct Queue {
+    depth: Arc<AtomicUsize>,
+    capacity: usize,
+}
+
+impl Queue {
+    pub(crate) fn stats(&self, name: impl Into<String>) -> QueueStats {
+        QueueStats {
+            name: name.into(),
+            depth: self.depth.load(Ordering::Relaxed),
+            capacity: self.capacity,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Sender<T> {
+    tx: mpsc::Sender<T>,
+    queue: Queue,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    pub(crate) async fn send(&mut self, value: T) -> std::result::Result<(), SendError<T>> {
+        // counted before sending, so that the receiver never sees a negative depth
+        let _ = self.queue.depth.fetch_add(1, Ordering::Relaxed);
+        let res = self.tx.send(value).await;
+        if res.is_err() {
+            let _ = self.queue.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    pub(crate) fn try_send(&mut self, value: T) -> std::result::Result<(), TrySendError<T>> {
+        let _ = self.queue.depth.fetch_add(1, Ordering::Relaxed);
+        let res = self.tx.try_send(value);
+        if res.is_err() {
+            let _ = self.queue.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    pub(crate) fn queue(&self) -> Queue {
+        self.queue.clone()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Receiver<T> {
+    rx: mpsc::Receiver<T>,
+    queue: Queue,
+}
+
+impl<T> Receiver<T> {
+    pub(crate) async fn recv(&mut self) -> Option<T> {
+        self.next().await
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let res = self.rx.poll_recv(cx);
+        if let Poll::Ready(Some(_)) = &res {
+            let _ = self.queue.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+        res
+    }
+}
+
+/// Renders the statistics in the Prometheus text format.
+pub(crate) fn render(stats: &Stats) -> String {
+    let mut out = String::new();
+    // writing to a String never fails
+    let _ = write_metrics(&mut out, stats);
+    out
+}
+
+fn write_metrics(out: &mut String, stats: &Stats) -> fmt::Result {
+    let traffic_metrics = [
+        ("sent_frames", "frames sent"),
+        ("sent_bytes", "bytes sent"),
+        ("received_frames", "frames received"),
+        ("received_bytes", "bytes received"),
+    ];
+    let values = |sent: &Traffic, received: &Traffic| {
+        [sent.frames, sent.bytes, received.frames, received.bytes]
+    };
+
+    for (i, (name, help)) in traffic_metrics.iter().enumerate() {
+        writeln!(
+            out,
+            "# HELP rsrs_node_{}_total Number of {} to the adjacent node",
+            name, help
+        )?;
+        writeln!(out, "# TYPE rsrs_node_{}_total counter", name)?;
+        for node in &stats.nodes {
+            let value = values(&node.sent, &node.received)[i];
+            writeln!(
+                out,
+                "rsrs_node_{}_total{{node=\"{}\"}} {}",
+                name,
+                escape(&node.name),
+                value
+            )?;
+        }
+    }
+    for (i, (name, help)) in traffic_metrics.iter().enumerate() {
+        writeln!(
+            out,
+            "# HELP rsrs_channel_{}_total Number of {} through the channel",
+            name, help
+        )?;
+        writeln!(out, "# TYPE rsrs_channel_{}_total counter", name)?;
+        for channel in &stats.channels {
+            let value = values(&channel.sent, &channel.received)[i];
+            writeln!(
+                out,
+                "rsrs_channel_{}_total{{id=\"{}\"}} {}",
+                name, channel.id, value
+            )?;
+        }
+    }
+    for (i, (name, help)) in traffic_metrics.iter().enumerate() {
+        writeln!(
+            out,
+            "# HELP rsrs_session_{}_total Number of {} through the session of the spawned process",
+            name, help
+        )?;
+        writeln!(out, "# TYPE rsrs_session_{}_total counter", name)?;
+        for session in &stats.sessions {
+            let value = values(&session.sent, &session.received)[i];
+            writeln!(
+                out,
+                "rsrs_session_{}_total{{node=\"{}\",session=\"{}\"}} {}",
+                name,
+                escape(&session.node),
+                session.id,
+                value
+            )?;
+        }
+    }
+
+    writeln!(out, "# HELP rsrs_queue_depth Number of items in the queue")?;
+    writeln!(out, "# TYPE rsrs_queue_depth gauge")?;
+    for queue in &stats.queues {
+        writeln!(
+            out,
+            "rsrs_queue_depth{{queue=\"{}\"}} {}",
+            escape(&queue.name),
+            queue.depth
+        )?;
+    }
+    writeln!(out, "# HELP rsrs_queue_capacity Capacity of the queue")?;
+    writeln!(out, "# TYPE rsrs_queue_capacity gauge")?;
+    for queue in &stats.queues {
+        writeln!(
+            out,
+            "rsrs_queue_capacity{{queue=\"{}\"}} {}",
+            escape(&queue.name),
+            queue.capacity
+        )?;
+    }
+    Ok(())
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Starts the Prometheus endpoint (`GET /metrics`) on the address.
+pub(crate) fn serve(addr: &str) -> Result<()> {
+    // the socket address conversion in mio 0.6 is broken on recent Rust toolchains
+    let listener = net::TcpListener::bind(addr)
+        .wrap_err_with(|| format!("failed to listen on metrics address {}", addr))?;
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener)?;
+    debug!(addr = %listener.local_addr()?, "metrics endpoint started");
+
+    tokio::spawn(async move {
+        while let Some(stream) = listener.next().await {
+            match stream {
+                Ok(stream) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = respond(stream).await {
+                            debug!(error = %e, "failed to respond to metrics request");
+                        }
+                    });
+                }
+                Err(e) => warn!(error = %e, "accept failed"),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn respond(mut stream: TcpStream) -> Result<()> {
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        ensure!(request.len() < MAX_REQUEST_SIZE, "request too large");
+        let n = stream.read(&mut buf).await?;
+        ensure!(n > 0, "connection closed before the request is received");
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(&collect())),
+        (Some("GET"), Some(_)) => ("404 Not Found", "not found\n".into()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".into()),
+    };
+    let response = format!(
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown(net::Shutdown::Write)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{
+        cli::{ChannelStats, NodeStats, SessionStats},
+        network::NodeName,
+        Id, ProcessKind,
+    };
+
+    fn traffic(frames: u64, bytes: u64) -> Traffic {
+        Traffic { frames, bytes }
+    }
+
+    #[test]
+    fn escape_label() {
+        assert_eq!(escape("web-1"), "web-1");
+        assert_eq!(escape(r#"a"b\c"#), r#"a\"b\\c"#);
+        assert_eq!(escape("a\nb"), r"a\nb");
+    }
+
+    #[test]
+    fn render_stats() {
+        let stats = Stats {
+            nodes: vec![NodeStats {
+                name: NodeName::from("web\"1".to_owned()),
+                sent: traffic(1, 10),
+                received: traffic(2, 20),
+            }],
+            channels: vec![ChannelStats {
+                id: Id::new(ProcessKind::Local, 3),
+                sent: traffic(4, 40),
+                received: traffic(5, 50),
+            }],
+            sessions: vec![SessionStats {
+                node: NodeName::from("db".to_owned()),
+                id: 7,
+                sent: traffic(8, 80),
+                received: traffic(9, 90),
+            }],
+            queues: vec![QueueStats {
+                name: "link:db".to_owned(),
+                depth: 6,
+                capacity: 64,
+            }],
+        };
+        let out = render(&stats);
+        let lines = out.lines().collect::<Vec<_>>();
+        for line in &[
+            r#"rsrs_node_sent_frames_total{node="web\"1"} 1"#,
+            r#"rsrs_node_received_bytes_total{node="web\"1"} 20"#,
+            r#"rsrs_channel_sent_bytes_total{id="local-3"} 40"#,
+            r#"rsrs_channel_received_frames_total{id="local-3"} 5"#,
+            r#"rsrs_session_sent_frames_total{node="db",session="7"} 8"#,
+            r#"rsrs_session_received_bytes_total{node="db",session="7"} 90"#,
+            r#"rsrs_queue_depth{queue="link:db"} 6"#,
+            r#"rsrs_queue_capacity{queue="link:db"} 64"#,
+            "# TYPE rsrs_session_sent_bytes_total counter",
+            "# TYPE rsrs_queue_depth gauge",
+        ] {
+            assert!(lines.contains(line), "missing line: {}", line);
+        }
+        assert!(out.ends_with('\n'));
+    }
+
+    #[test]
+    fn render_empty_stats() {
+        let out = render(&Stats::default());
+        assert!(out.lines().all(|line| line.starts_with('#')), "{}", out);
+    }
+}
@@ -0,0 +1,282 @@
+//! Authorization policy of the requests from remote peers (`~/.config/rsrs/policy.toml`).
+//!
+//! ```toml
+//! # `login-shell` allows the login shell of the user
+//! allowed_programs = ["login-shell", "/usr/bin/rsync"]
+//! allowed_subsystems = ["sftp"]
+//! forbidden_env = ["LD_*", "PATH"]
+//! pty = false
+//! filesystem = false
+//! forwarding = false
+//! ```
+//!
+//! Everything is permitted if the file does not exist.
+
+use crate::{config, prelude::*, protocol::SpawnCommand, Error, Result};
+use once_cell::sync::Lazy;
+use std::{
+    ffi::{OsStr, OsString},
+    fs, io,
+};
+
+const LOGIN_SHELL: &str = "login-shell";
+const ORIGINAL_COMMAND_ENV: &str = "RSRS_ORIGINAL_COMMAND";
+
+// the error is kept as a message, since it is reported for each request.
+// all requests are denied if the policy file is broken.
+static POLICY: Lazy<std::result::Result<Policy, String>> = Lazy::new(|| {
+    load().map_err(|e| {
+        warn!(error = %e, "failed to load policy file. all requests are denied");
+        format!("{:#}", e)
+    })
+});
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Policy {
+    /// Programs which may be spawned. Any program is allowed if omitted
+    allowed_programs: Option<Vec<String>>,
+    /// Subsystems which may be started. Any subsystem is allowed if omitted
+    allowed_subsystems: Option<Vec<String>>,
+    /// Environment variables which cannot be set by peers. A trailing `*` matches any suffix
+    forbidden_env: Vec<String>,
+    /// Command executed instead of the requested one, like `ForceCommand` of sshd.
+    /// The requested command line is set to `RSRS_ORIGINAL_COMMAND`
+    force_command: Option<Vec<String>>,
+    /// Allow allocating pseudo terminals
+    pty: bool,
+    /// Allow file operations of `rsrs fs`
+    filesystem: bool,
+    /// Allow opening connections from this node to other nodes (`rsrs open --via`)
+    forwarding: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            allowed_programs: None,
+            allowed_subsystems: None,
+            forbidden_env: vec![],
+            force_command: None,
+            pty: true,
+            filesystem: true,
+            forwarding: true,
+        }
+    }
+}
+
+impl Policy {
+    /// Checks the spawn request and returns the command which should be executed.
+    pub(crate) fn authorize_spawn(
+        &self,
+        command: SpawnCommand,
+        env_vars: &mut Vec<(OsString, OsString)>,
+        pty: bool,
+    ) -> Result<SpawnCommand> {
+        ensure!(self.pty || !pty, "pseudo terminal is not permitted");
+        if let Some((key, _)) = env_vars.iter().find(|(key, _)| !self.allows_env(key)) {
+            bail!(
+                "environment variable is not permitted: {}",
+                key.to_string_lossy()
+            );
+        }
+
+        if let Some((program, args)) = self.force_command.as_ref().and_then(|c| c.split_first()) {
+            if let SpawnCommand::Program(program, args) = &command {
+                let mut original = program.clone();
+                for arg in args {
+                    original.push(" ");
+                    original.push(arg);
+                }
+                env_vars.push((ORIGINAL_COMMAND_ENV.into(), original));
+            }
+            return Ok(SpawnCommand::Program(
+                program.into(),
+                args.iter().map(Into::into).collect(),
+            ));
+        }
+
+        match &command {
+            SpawnCommand::LoginShell => ensure!(
+                allows(&self.allowed_programs, OsStr::new(LOGIN_SHELL)),
+                "login shell is not permitted"
+            ),
+            SpawnCommand::Program(program, _) => ensure!(
+                program != LOGIN_SHELL && allows(&self.allowed_programs, program),
+                "program is not permitted: {}",
+                program.to_string_lossy()
+            ),
+            SpawnCommand::Subsystem(name) => ensure!(
+                allows(&self.allowed_subsystems, OsStr::new(name)),
+                "subsystem is not permitted: {}",
+                name
+            ),
+        }
+        Ok(command)
+    }
+
+    /// Checks the file operation request.
+    pub(crate) fn authorize_fs(&self) -> Result<()> {
+        ensure!(self.filesystem, "file operations are not permitted");
+        Ok(())
+    }
+
+    /// Checks the request to open a connection to another node.
+    pub(crate) fn authorize_forwarding(&self) -> Result<()> {
+        ensure!(self.forwarding, "forwarding is not permitted");
+        Ok(())
+    }
+
+    pub(crate) fn allows_env(&self, key: &OsStr) -> bool {
+        let key = key.to_string_lossy();
+        !self
+            .forbidden_env
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => key.starts_with(prefix),
+                None => key == pattern.as_str(),
+            })
+    }
+}
+
+fn allows(allowed: &Option<Vec<String>>, name: &OsStr) -> bool {
+    match allowed {
+        Some(allowed) => allowed.iter().any(|a| OsStr::new(a) == name),
+        None => true,
+    }
+}
+
+fn load() -> Result<Policy> {
+    let path = match config::get().policy.clone() {
+        Some(path) => path,
+        None => match config::dir() {
+            Some(dir) => dir.join("policy.toml"),
+            None => return Ok(Policy::default()),
+        },
+    };
+    let policy: Policy = match fs::read_to_string(&path) {
+        Ok(s) => toml::from_str(&s)
+            .wrap_err_with(|| format!("failed to parse policy file: {}", path.display()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Policy::default()),
+        Err(e) => {
+            return Err(
+                Error::new(e).wrap_err(format!("failed to read policy file: {}", path.display()))
+            )
+        }
+    };
+    if let Some(command) = &policy.force_command {
+        ensure!(
+            !command.is_empty(),
+            "force_command is empty: {}",
+            path.display()
+        );
+    }
+    debug!(path = %path.display(), ?policy, "policy loaded");
+    Ok(policy)
+}
+
+/// Returns the policy. Fails if the policy file is broken.
+pub(crate) fn get() -> Result<&'static Policy> {
+    POLICY
+        .as_ref()
+        .map_err(|e| eyre!("request denied: invalid policy file: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Policy {
+        toml::from_str(s).unwrap()
+    }
+
+    fn program(argv: &[&str]) -> SpawnCommand {
+        SpawnCommand::Program(argv[0].into(), argv[1..].iter().map(Into::into).collect())
+    }
+
+    #[test]
+    fn default_policy() {
+        let policy = Policy::default();
+        let mut env_vars = vec![("LD_PRELOAD".into(), "x.so".into())];
+        let command = policy
+            .authorize_spawn(program(&["ls", "-l"]), &mut env_vars, true)
+            .unwrap();
+        assert!(matches!(command, SpawnCommand::Program(p, _) if p == "ls"));
+        policy.authorize_fs().unwrap();
+        policy.authorize_forwarding().unwrap();
+    }
+
+    #[test]
+    fn force_command() {
+        let policy = parse(r#"force_command = ["/usr/bin/rsync", "--server"]"#);
+        let mut env_vars = vec![];
+        let command = policy
+            .authorize_spawn(program(&["rm", "-rf", "/"]), &mut env_vars, false)
+            .unwrap();
+        assert!(matches!(
+            command,
+            SpawnCommand::Program(p, args) if p == "/usr/bin/rsync" && args == ["--server"]
+        ));
+        assert_eq!(
+            env_vars,
+            [(
+                OsString::from(ORIGINAL_COMMAND_ENV),
+                OsString::from("rm -rf /")
+            )]
+        );
+    }
+
+    #[test]
+    fn forbidden_env() {
+        let policy = parse(r#"forbidden_env = ["LD_*", "PATH"]"#);
+        for key in &["LD_PRELOAD", "LD_LIBRARY_PATH", "PATH"] {
+            assert!(!policy.allows_env(OsStr::new(key)), "key = {}", key);
+            let mut env_vars = vec![(key.into(), "x".into())];
+            assert!(policy
+                .authorize_spawn(program(&["ls"]), &mut env_vars, false)
+                .is_err());
+        }
+        assert!(policy.allows_env(OsStr::new("LD")));
+        assert!(policy.allows_env(OsStr::new("PATHS")));
+    }
+
+    #[test]
+    fn allowed_programs() {
+        let policy = parse(r#"allowed_programs = ["login-shell", "/usr/bin/rsync"]"#);
+        let mut env_vars = vec![];
+        policy
+            .authorize_spawn(SpawnCommand::LoginShell, &mut env_vars, false)
+            .unwrap();
+        policy
+            .authorize_spawn(program(&["/usr/bin/rsync"]), &mut env_vars, false)
+            .unwrap();
+        for argv in &[&["sh"][..], &["login-shell"][..]] {
+            assert!(policy
+                .authorize_spawn(program(argv), &mut env_vars, false)
+                .is_err());
+        }
+        assert!(policy
+            .authorize_spawn(SpawnCommand::Subsystem("sftp".into()), &mut env_vars, false)
+            .is_ok());
+
+        let policy = parse(r#"allowed_programs = ["/usr/bin/rsync"]"#);
+        assert!(policy
+            .authorize_spawn(SpawnCommand::LoginShell, &mut env_vars, false)
+            .is_err());
+    }
+
+    #[test]
+    fn denied_switches() {
+        let policy = parse("pty = false\nfilesystem = false\nforwarding = false");
+        let mut env_vars = vec![];
+        assert!(policy
+            .authorize_spawn(SpawnCommand::LoginShell, &mut env_vars, true)
+            .is_err());
+        policy
+            .authorize_spawn(SpawnCommand::LoginShell, &mut env_vars, false)
+            .unwrap();
+        assert!(policy.authorize_fs().is_err());
+        assert!(policy.authorize_forwarding().is_err());
+        assert!(toml::from_str::<Policy>("unknown = true").is_err());
+    }
+}
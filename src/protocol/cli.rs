@@ -1,19 +1,150 @@
+use super::{
+    network::{LinkOptions, NodeInfo, NodeName, SessionId},
+    ExitStatus, Id, PtyParam, SpawnCommand,
+};
 use std::ffi::OsString;
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub(crate) enum Request {
     Open(Open),
+    OpenVia(OpenVia),
+    ListNodes,
+    Exec(Exec),
+    WindowSizeChange(u16, u16),
+    Close(Close),
+    Shutdown(Shutdown),
+    /// Receives `Response::Event` until the connection is closed
+    Subscribe,
+    SetLogFilter(SetLogFilter),
+    Stats,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub(crate) struct Open {
-    pub(crate) pid: u32,
+    pub(crate) name: Option<NodeName>,
+    pub(crate) pid: Option<u32>,
     pub(crate) command: OsString,
     pub(crate) args: Vec<OsString>,
+    pub(crate) options: LinkOptions,
+    /// The controlling terminal of the transport command is passed after the standard streams
+    pub(crate) pty: bool,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct OpenVia {
+    pub(crate) name: Option<NodeName>,
+    pub(crate) via: NodeName,
+    pub(crate) command: OsString,
+    pub(crate) args: Vec<OsString>,
+    pub(crate) options: LinkOptions,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Exec {
+    pub(crate) node: NodeName,
+    pub(crate) command: SpawnCommand,
+    pub(crate) env_vars: Vec<(OsString, OsString)>,
+    pub(crate) pty: Option<PtyParam>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Close {
+    pub(crate) node: NodeName,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Shutdown {
+    /// Seconds to wait for running sessions to finish
+    pub(crate) timeout_secs: u64,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct SetLogFilter {
+    /// Log directive in the syntax of `RUST_LOG`
+    pub(crate) directive: String,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub(crate) enum Response {
     Ok,
+    Nodes(Vec<NodeInfo>),
+    Exit(ExitStatus),
+    Event(Event),
+    Stats(Stats),
     Err(String),
 }
+
+/// Traffic counters and queue depths of the daemon
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Stats {
+    pub(crate) nodes: Vec<NodeStats>,
+    pub(crate) channels: Vec<ChannelStats>,
+    pub(crate) sessions: Vec<SessionStats>,
+    pub(crate) queues: Vec<QueueStats>,
+}
+
+/// Traffic of the link to the adjacent node
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct NodeStats {
+    pub(crate) name: NodeName,
+    pub(crate) sent: Traffic,
+    pub(crate) received: Traffic,
+}
+
+/// Traffic of the channel of `rsrs login` and `rsrs remote`
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct ChannelStats {
+    pub(crate) id: Id,
+    pub(crate) sent: Traffic,
+    pub(crate) received: Traffic,
+}
+
+/// Traffic of the session of the process spawned by the node
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct SessionStats {
+    pub(crate) node: NodeName,
+    pub(crate) id: SessionId,
+    pub(crate) sent: Traffic,
+    pub(crate) received: Traffic,
+}
+
+#[derive(Debug, Default, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Traffic {
+    pub(crate) frames: u64,
+    pub(crate) bytes: u64,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct QueueStats {
+    pub(crate) name: String,
+    pub(crate) depth: usize,
+    pub(crate) capacity: usize,
+}
+
+/// State transitions of the daemon network
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) enum Event {
+    /// Transport command connected to this node and the handshake started
+    NodeConnecting {
+        name: NodeName,
+    },
+    /// Node became reachable
+    NodeConnected(NodeInfo),
+    /// Connection to the node is lost
+    NodeLost {
+        name: NodeName,
+    },
+    /// Node is removed by `rsrs close`
+    NodeRemoved {
+        name: NodeName,
+    },
+    SessionStarted {
+        node: NodeName,
+        session: SessionId,
+    },
+    SessionExited {
+        node: NodeName,
+        session: SessionId,
+        status: ExitStatus,
+    },
+}
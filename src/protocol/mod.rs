@@ -1,10 +1,15 @@
 use crate::{prelude::*, router};
-use std::{ffi::OsString, os::unix::process::ExitStatusExt as _};
+use std::{ffi::OsString, fmt, os::unix::process::ExitStatusExt as _, time::Duration};
 
 pub(crate) mod cli;
+pub(crate) mod fs;
 pub(crate) mod network;
 
 pub(crate) const MAGIC: &[u8] = b"\0RSRS\0magic\0number\0";
+/// Version of the protocol between daemons, sent after the magic number
+pub(crate) const VERSION: u32 = 3;
+/// Time to wait for the magic number and the protocol version from the transport command
+pub(crate) const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, serde::Serialize, serde::Deserialize)]
 pub(crate) enum ProcessKind {
@@ -21,12 +26,28 @@ impl Id {
     }
 }
 
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.0 {
+            ProcessKind::Local => "local",
+            ProcessKind::Remote => "remote",
+        };
+        write!(f, "{}-{}", kind, self.1)
+    }
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub(crate) enum RemoteCommand {
     SetEnv(SetEnv),
     Spawn(Spawn),
     Channel(ChannelCommand),
     ProcessExit(ProcessExitStatus),
+    /// The spawn request is denied or failed
+    SpawnError(SpawnError),
+    FsRequest(fs::Request),
+    FsResponse(fs::Response),
+    /// Tracing event of the remote endpoint
+    Log(Log),
     Exit,
 }
 
@@ -47,6 +68,7 @@ pub(crate) struct SetEnv {
 pub(crate) enum SpawnCommand {
     LoginShell,
     Program(OsString, Vec<OsString>),
+    Subsystem(String),
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -83,6 +105,28 @@ pub(crate) struct ProcessExitStatus {
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct SpawnError {
+    pub(crate) id: Id,
+    pub(crate) message: String,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Log {
+    pub(crate) level: LogLevel,
+    pub(crate) target: String,
+    pub(crate) message: String,
+}
+
+#[derive(Debug, Copy, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Copy, Clone, serde::Serialize, serde::Deserialize)]
 pub(crate) enum ExitStatus {
     Code(i32),
     Signal(i32),
@@ -1,7 +1,11 @@
+use super::{ChannelData, ExitStatus, PtyParam, SpawnCommand};
+use sha2::{Digest, Sha256};
 use std::{
     borrow::{Borrow, BorrowMut},
+    ffi::OsString,
     fmt::Display,
     ops::Deref,
+    time::Duration,
 };
 
 #[derive(
@@ -50,17 +54,177 @@ impl Deref for NodeName {
     }
 }
 
+/// X25519 public key of a node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct PublicKey(pub(crate) [u8; 32]);
+
+impl PublicKey {
+    /// Returns the first 8 bytes of the SHA-256 digest of the key in hex.
+    pub(crate) fn fingerprint(&self) -> String {
+        Sha256::digest(&self.0)[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub(crate) struct Handshake {
     pub(crate) client_name: NodeName,
     pub(crate) server_name: NodeName,
+    pub(crate) server_key: PublicKey,
+    /// Public keys of the other nodes known by the server
+    pub(crate) known_keys: Vec<(NodeName, PublicKey)>,
+    /// Ancestors of the server, from its parent to the root
+    pub(crate) server_ancestors: Vec<NodeName>,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
-pub(crate) struct HandshakeRsp;
+pub(crate) struct HandshakeRsp {
+    pub(crate) client_key: PublicKey,
+}
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub(crate) struct Message {
-    src: NodeName,
-    dst: NodeName,
+    pub(crate) src: NodeName,
+    pub(crate) dst: NodeName,
+    pub(crate) body: MessageBody,
+}
+
+pub(crate) type RequestId = u64;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct NodeInfo {
+    pub(crate) name: NodeName,
+    pub(crate) parent: Option<NodeName>,
+    pub(crate) state: NodeState,
+    /// Time when the handshake completed, in seconds since the UNIX epoch
+    pub(crate) connected_at: Option<u64>,
+    /// Transport command which launched the leaf daemon
+    pub(crate) command: Vec<String>,
+    /// PID of the transport command on the parent node, or of the daemon for the local node
+    pub(crate) pid: Option<u32>,
+    pub(crate) public_key: Option<PublicKey>,
+}
+
+impl NodeInfo {
+    pub(crate) fn new(name: NodeName, parent: Option<NodeName>, state: NodeState) -> Self {
+        Self {
+            name,
+            parent,
+            state,
+            connected_at: None,
+            command: vec![],
+            pid: None,
+            public_key: None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum NodeState {
+    Local,
+    Handshake,
+    Connected,
+    Disconnected,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) enum MessageBody {
+    /// Announces that the node becomes reachable through the sender.
+    RouteAdd(NodeInfo),
+    /// Announces that the nodes are no longer reachable through the sender.
+    ///
+    /// `closed` is true if the nodes are removed from the network by `rsrs close`, and false if
+    /// the link to them is lost.
+    RouteDel {
+        names: Vec<NodeName>,
+        closed: bool,
+    },
+    Request(RequestId, Request),
+    Response(RequestId, Response),
+    /// Data of the session started by `Request::Spawn`
+    Session(SessionId, SessionData),
+    /// `Request`, `Response` or `Session` encrypted for the destination node
+    Sealed(Sealed),
+}
+
+#[derive(custom_debug::Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Sealed {
+    /// Number of the messages sent from the source to the destination before this message
+    pub(crate) counter: u64,
+    #[debug(skip)]
+    pub(crate) ciphertext: Vec<u8>,
+}
+
+pub(crate) type SessionId = u64;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) enum SessionData {
+    Stdin(ChannelData),
+    Stdout(ChannelData),
+    Stderr(ChannelData),
+    Exit(ExitStatus),
+    /// The client of the session is gone. The process is sent `SIGHUP`
+    Hangup,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) enum Request {
+    Ping,
+    /// Runs the transport command and connects to the leaf daemon as the node `name`
+    Open {
+        name: NodeName,
+        command: OsString,
+        args: Vec<OsString>,
+        options: LinkOptions,
+    },
+    /// Spawns a process whose stdio are relayed by `MessageBody::Session`
+    Spawn {
+        session: SessionId,
+        command: SpawnCommand,
+        env_vars: Vec<(OsString, OsString)>,
+        pty: Option<PtyParam>,
+    },
+    /// Closes the connection to the adjacent node `name`
+    Close {
+        name: NodeName,
+    },
+    /// Terminates the daemon
+    Shutdown,
+}
+
+/// Options of the link between a node and its child node
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct LinkOptions {
+    /// Re-run the transport command when the connection is lost
+    pub(crate) reconnect: bool,
+    pub(crate) keepalive: Option<Keepalive>,
+    /// Install the rsrs binary through the shell run by the transport command
+    pub(crate) bootstrap: bool,
+    pub(crate) handshake_timeout_secs: Option<u64>,
+}
+
+impl LinkOptions {
+    pub(crate) fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(super::DEFAULT_HANDSHAKE_TIMEOUT)
+    }
+}
+
+/// Ping requests sent periodically to detect unresponsive nodes
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Keepalive {
+    pub(crate) interval_secs: u64,
+    /// Number of unanswered requests before the transport command is terminated
+    pub(crate) count: u32,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) enum Response {
+    Ok,
+    Pong,
+    Err(String),
 }
@@ -1,4 +1,9 @@
-use crate::{endpoint, prelude::*, protocol, Error, Result};
+use crate::{
+    audit, common, endpoint, metrics, policy,
+    prelude::*,
+    protocol::{self, cli},
+    Error, Result,
+};
 use futures_core::{Future, Stream};
 use futures_util::{pin_mut, sink::Sink};
 use generational_arena::{Arena, Index};
@@ -10,10 +15,8 @@ use std::{
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::{
-    sync::{mpsc, oneshot},
-    task::JoinHandle,
-};
+use tokio::{sync::oneshot, task::JoinHandle};
+use tracing_futures::Instrument as _;
 
 static ROUTER: Lazy<Mutex<Router>> = Lazy::new(|| Mutex::new(Router::new()));
 
@@ -21,11 +24,17 @@ static ROUTER: Lazy<Mutex<Router>> = Lazy::new(|| Mutex::new(Router::new()));
 pub(crate) struct Router {
     kind: Option<protocol::ProcessKind>,
     id: usize,
-    handler_tx: Option<mpsc::Sender<protocol::Command>>,
+    handler_tx: Option<metrics::Sender<protocol::Command>>,
+    /// Queue of the frames sent to the peer
+    peer_queue: Option<metrics::Queue>,
     channel_id_map: HashMap<protocol::Id, Index>,
-    channels: Arena<(protocol::Id, mpsc::Sender<protocol::ChannelData>)>,
+    channels: Arena<(protocol::Id, metrics::Sender<protocol::ChannelData>)>,
+    /// Traffic of the channels, which is kept until the channel is shut down in both directions
+    traffic: HashMap<protocol::Id, ChannelTraffic>,
     status_id_map: HashMap<protocol::Id, Index>,
-    status_notifiers: Arena<(protocol::Id, oneshot::Sender<protocol::ProcessExitStatus>)>,
+    status_notifiers: Arena<(protocol::Id, oneshot::Sender<ProcessResult>)>,
+    fs_id_map: HashMap<protocol::Id, Index>,
+    fs_notifiers: Arena<(protocol::Id, oneshot::Sender<protocol::fs::Response>)>,
 }
 
 impl Router {
@@ -34,10 +43,14 @@ impl Router {
             kind: None,
             id: 0,
             handler_tx: None,
+            peer_queue: None,
             channel_id_map: HashMap::new(),
             channels: Arena::new(),
+            traffic: HashMap::new(),
             status_id_map: HashMap::new(),
             status_notifiers: Arena::new(),
+            fs_id_map: HashMap::new(),
+            fs_notifiers: Arena::new(),
         }
     }
 
@@ -45,7 +58,7 @@ impl Router {
         match self.channel_id_map.entry(id) {
             Entry::Vacant(e) => {
                 // FIXME: implement back-pressure
-                let (tx, rx) = mpsc::channel(64);
+                let (tx, rx) = metrics::channel(64);
                 let index = self.channels.insert((id, tx));
                 e.insert(index);
                 Some(ChannelReceiver { index, rx })
@@ -57,7 +70,7 @@ impl Router {
     fn remove_channel(
         &mut self,
         index: Index,
-    ) -> Option<(protocol::Id, mpsc::Sender<protocol::ChannelData>)> {
+    ) -> Option<(protocol::Id, metrics::Sender<protocol::ChannelData>)> {
         self.channels.remove(index).map(|(id, tx)| {
             let _ = self
                 .channel_id_map
@@ -70,11 +83,11 @@ impl Router {
     fn get_channel(
         &mut self,
         id: protocol::Id,
-    ) -> Option<(protocol::Id, mpsc::Sender<protocol::ChannelData>)> {
+    ) -> Option<(protocol::Id, metrics::Sender<protocol::ChannelData>)> {
         let channels = &mut self.channels;
         self.channel_id_map
             .get(&id)
-            .and_then(|index| channels.get_mut(*index))
+            .and_then(|index| channels.get(*index))
             .cloned()
     }
 
@@ -93,7 +106,7 @@ impl Router {
     fn remove_status(
         &mut self,
         index: Index,
-    ) -> Option<(protocol::Id, oneshot::Sender<protocol::ProcessExitStatus>)> {
+    ) -> Option<(protocol::Id, oneshot::Sender<ProcessResult>)> {
         self.status_notifiers.remove(index).map(|(id, tx)| {
             let _ = self
                 .status_id_map
@@ -106,17 +119,94 @@ impl Router {
     fn take_status(
         &mut self,
         id: protocol::Id,
-    ) -> Option<(protocol::Id, oneshot::Sender<protocol::ProcessExitStatus>)> {
+    ) -> Option<(protocol::Id, oneshot::Sender<ProcessResult>)> {
         let notifiers = &mut self.status_notifiers;
         self.status_id_map
             .remove(&id)
             .and_then(|index| notifiers.remove(index))
     }
 
-    pub(crate) fn handler_tx(&self) -> mpsc::Sender<protocol::Command> {
+    pub(crate) fn insert_fs_notifier(&mut self, id: protocol::Id) -> Option<FsResponseReceiver> {
+        match self.fs_id_map.entry(id) {
+            Entry::Vacant(e) => {
+                let (tx, rx) = oneshot::channel();
+                let index = self.fs_notifiers.insert((id, tx));
+                e.insert(index);
+                Some(FsResponseReceiver { index, rx })
+            }
+            Entry::Occupied(_e) => None,
+        }
+    }
+
+    fn remove_fs_notifier(
+        &mut self,
+        index: Index,
+    ) -> Option<(protocol::Id, oneshot::Sender<protocol::fs::Response>)> {
+        self.fs_notifiers.remove(index).map(|(id, tx)| {
+            let _ = self.fs_id_map.remove(&id).expect("corrupt internal status");
+            (id, tx)
+        })
+    }
+
+    fn take_fs_notifier(
+        &mut self,
+        id: protocol::Id,
+    ) -> Option<(protocol::Id, oneshot::Sender<protocol::fs::Response>)> {
+        let notifiers = &mut self.fs_notifiers;
+        self.fs_id_map
+            .remove(&id)
+            .and_then(|index| notifiers.remove(index))
+    }
+
+    pub(crate) fn handler_tx(&self) -> metrics::Sender<protocol::Command> {
         self.handler_tx.clone().unwrap()
     }
 
+    /// Counts a frame of the channel, and forgets the channel when it is shut down.
+    fn count_traffic(&mut self, command: &protocol::ChannelCommand, sent: bool) {
+        let bytes = bincode::serialized_size(command).unwrap_or(0);
+        let traffic = self.traffic.entry(command.id).or_default();
+        let shutdown = matches!(command.data, protocol::ChannelData::Shutdown);
+        if sent {
+            traffic.sent.add(bytes);
+            traffic.sent_shutdown |= shutdown;
+        } else {
+            traffic.received.add(bytes);
+            traffic.received_shutdown |= shutdown;
+        }
+        if traffic.sent_shutdown && traffic.received_shutdown {
+            let _ = self.traffic.remove(&command.id);
+        }
+    }
+
+    pub(crate) fn collect_stats(&self, stats: &mut cli::Stats) {
+        let mut channels = self
+            .traffic
+            .iter()
+            .map(|(id, traffic)| cli::ChannelStats {
+                id: *id,
+                sent: traffic.sent.get(),
+                received: traffic.received.get(),
+            })
+            .collect::<Vec<_>>();
+        channels.sort_by_key(|channel| channel.id.to_string());
+        stats.channels.extend(channels);
+
+        if let Some(tx) = &self.handler_tx {
+            stats.queues.push(tx.queue().stats("router"));
+        }
+        if let Some(queue) = &self.peer_queue {
+            stats.queues.push(queue.stats("peer"));
+        }
+        let mut channel_queues = self
+            .channels
+            .iter()
+            .map(|(_, (id, tx))| tx.queue().stats(format!("channel:{}", id)))
+            .collect::<Vec<_>>();
+        channel_queues.sort_by(|a, b| a.name.cmp(&b.name));
+        stats.queues.extend(channel_queues);
+    }
+
     pub(crate) fn new_id(&mut self) -> protocol::Id {
         let id = self.id;
         self.id += 1;
@@ -131,7 +221,15 @@ pub(crate) fn lock() -> MutexGuard<'static, Router> {
 #[derive(Debug)]
 pub(crate) struct ChannelReceiver {
     index: Index,
-    rx: mpsc::Receiver<protocol::ChannelData>,
+    rx: metrics::Receiver<protocol::ChannelData>,
+}
+
+#[derive(Debug, Default)]
+struct ChannelTraffic {
+    sent: metrics::TrafficCounter,
+    received: metrics::TrafficCounter,
+    sent_shutdown: bool,
+    received_shutdown: bool,
 }
 
 impl Stream for ChannelReceiver {
@@ -149,14 +247,18 @@ impl Drop for ChannelReceiver {
     }
 }
 
+/// Exit status of the process, or the reason why it was not spawned
+pub(crate) type ProcessResult =
+    std::result::Result<protocol::ProcessExitStatus, protocol::SpawnError>;
+
 #[derive(Debug)]
 pub(crate) struct StatusReceiver {
     index: Index,
-    rx: oneshot::Receiver<protocol::ProcessExitStatus>,
+    rx: oneshot::Receiver<ProcessResult>,
 }
 
 impl Future for StatusReceiver {
-    type Output = std::result::Result<protocol::ProcessExitStatus, oneshot::error::RecvError>;
+    type Output = std::result::Result<ProcessResult, oneshot::error::RecvError>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         Pin::new(&mut self.rx).poll(cx)
@@ -170,9 +272,30 @@ impl Drop for StatusReceiver {
     }
 }
 
+#[derive(Debug)]
+pub(crate) struct FsResponseReceiver {
+    index: Index,
+    rx: oneshot::Receiver<protocol::fs::Response>,
+}
+
+impl Future for FsResponseReceiver {
+    type Output = std::result::Result<protocol::fs::Response, oneshot::error::RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx).poll(cx)
+    }
+}
+
+impl Drop for FsResponseReceiver {
+    fn drop(&mut self) {
+        let mut router = ROUTER.lock();
+        router.remove_fs_notifier(self.index);
+    }
+}
+
 async fn sender(
     sink: impl Sink<protocol::RemoteCommand, Error = Error>,
-    mut peer_rx: mpsc::Receiver<protocol::RemoteCommand>,
+    mut peer_rx: metrics::Receiver<protocol::RemoteCommand>,
 ) -> Result<()> {
     pin_mut!(sink);
 
@@ -189,25 +312,34 @@ async fn receiver(source: impl Stream<Item = Result<protocol::RemoteCommand>>) -
     while let Some(frame) = source.next().await {
         let frame = frame?;
         let mut tx = ROUTER.lock().handler_tx.clone().unwrap();
-        tx.send(protocol::Command::Recv(frame))
-            .map_err(|_| eyre!("send failed"))
-            .await?;
+        if tx.send(protocol::Command::Recv(frame)).await.is_err() {
+            // the router has exited. the frames sent by the peer after `Exit` are ignored
+            break;
+        }
     }
     Ok(())
 }
 
 async fn router(
-    mut rx: mpsc::Receiver<protocol::Command>,
-    mut peer_tx: mpsc::Sender<protocol::RemoteCommand>,
+    mut rx: metrics::Receiver<protocol::Command>,
+    mut peer_tx: metrics::Sender<protocol::RemoteCommand>,
 ) -> Result<()> {
     while let Some(command) = rx.next().await {
         match command {
             protocol::Command::Recv(remote) => match remote {
-                protocol::RemoteCommand::SetEnv(set_env) => {
-                    for (k, v) in set_env.env_vars {
-                        env::set_var(k, v);
+                protocol::RemoteCommand::SetEnv(set_env) => match policy::get() {
+                    Ok(policy) => {
+                        audit::set_env(audit::peer(), &set_env.env_vars);
+                        for (k, v) in set_env.env_vars {
+                            if policy.allows_env(&k) {
+                                env::set_var(k, v);
+                            } else {
+                                warn!(key = ?k, "environment variable is not permitted");
+                            }
+                        }
                     }
-                }
+                    Err(e) => warn!(error = %e, "failed to set environment variables"),
+                },
                 protocol::RemoteCommand::Spawn(spawn) => {
                     let rx = ROUTER
                         .lock()
@@ -218,8 +350,14 @@ async fn router(
                         let _ = endpoint::process::run(rx, spawn).await;
                     });
                 }
-                protocol::RemoteCommand::Channel(protocol::ChannelCommand { id, data }) => {
-                    let chan_tx = ROUTER.lock().get_channel(id);
+                protocol::RemoteCommand::Channel(command) => {
+                    let chan_tx = {
+                        // scope for lock guard
+                        let mut router = ROUTER.lock();
+                        router.count_traffic(&command, false);
+                        router.get_channel(command.id)
+                    }; // lock ends here
+                    let protocol::ChannelCommand { data, .. } = command;
                     if let Some((_, mut tx)) = chan_tx {
                         tx.send(data).await?;
                     }
@@ -228,12 +366,50 @@ async fn router(
                     let stat_tx = ROUTER.lock().take_status(status.id);
                     if let Some((_, tx)) = stat_tx {
                         // ignore error
-                        let _ = tx.send(status);
+                        let _ = tx.send(Ok(status));
                     }
                 }
+                protocol::RemoteCommand::SpawnError(error) => {
+                    let stat_tx = ROUTER.lock().take_status(error.id);
+                    if let Some((_, tx)) = stat_tx {
+                        // ignore error
+                        let _ = tx.send(Err(error));
+                    }
+                }
+                protocol::RemoteCommand::FsRequest(req) => {
+                    if let Err(e) = policy::get().and_then(|policy| policy.authorize_fs()) {
+                        warn!(error = %e, "file operation denied");
+                        let result = Err(protocol::fs::Error {
+                            kind: protocol::fs::ErrorKind::PermissionDenied,
+                            message: format!("{:#}", e),
+                        });
+                        let res = protocol::fs::Response { id: req.id, result };
+                        peer_tx
+                            .send(protocol::RemoteCommand::FsResponse(res))
+                            .await?;
+                        continue;
+                    }
+                    tokio::spawn(async move {
+                        // FIXME: error handling
+                        let _ = endpoint::fs::run(req).await;
+                    });
+                }
+                protocol::RemoteCommand::FsResponse(res) => {
+                    let res_tx = ROUTER.lock().take_fs_notifier(res.id);
+                    if let Some((_, tx)) = res_tx {
+                        // ignore error
+                        let _ = tx.send(res);
+                    }
+                }
+                protocol::RemoteCommand::Log(log) => common::emit_log(log),
                 protocol::RemoteCommand::Exit => break,
             },
-            protocol::Command::Send(remote) => peer_tx.send(remote).await?,
+            protocol::Command::Send(remote) => {
+                if let protocol::RemoteCommand::Channel(command) = &remote {
+                    ROUTER.lock().count_traffic(command, true);
+                }
+                peer_tx.send(remote).await?
+            }
             protocol::Command::Source(source) => {
                 tokio::spawn(async move {
                     // FIXME: error handling
@@ -256,10 +432,11 @@ pub(crate) fn spawn(
     source: impl Stream<Item = Result<protocol::RemoteCommand>> + Send + 'static,
     sink: impl Sink<protocol::RemoteCommand, Error = Error> + Send + 'static,
 ) -> JoinHandle<()> {
-    let (handler_tx, handler_rx) = mpsc::channel(64);
-    let (peer_tx, peer_rx) = mpsc::channel(64);
+    let (handler_tx, handler_rx) = metrics::channel(64);
+    let (peer_tx, peer_rx) = metrics::channel(64);
     ROUTER.lock().kind = Some(kind);
     ROUTER.lock().handler_tx = Some(handler_tx);
+    ROUTER.lock().peer_queue = Some(peer_tx.queue());
 
     tokio::spawn(async move {
         sender(sink, peer_rx).await.unwrap();
@@ -267,7 +444,11 @@ pub(crate) fn spawn(
     tokio::spawn(async move {
         receiver(source).await.unwrap();
     });
-    tokio::spawn(async move {
-        router(handler_rx, peer_tx).await.unwrap();
-    })
+    // events received from the peer are emitted in the span of the caller
+    tokio::spawn(
+        async move {
+            router(handler_rx, peer_tx).await.unwrap();
+        }
+        .in_current_span(),
+    )
 }
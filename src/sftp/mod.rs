@@ -0,0 +1,32 @@
+use crate::{prelude::*, Result};
+use bytes::Bytes;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+mod packet;
+mod server;
+
+pub(crate) const SUBSYSTEM_NAME: &str = "sftp";
+
+/// Serves the SFTP version 3 protocol over the given streams.
+#[tracing::instrument(skip(reader, writer), err)]
+#[allow(clippy::unit_arg)] // workaround for https://github.com/tokio-rs/tracing/issues/843
+pub(crate) async fn serve(
+    reader: impl AsyncRead + Unpin,
+    writer: impl AsyncWrite + Unpin,
+) -> Result<()> {
+    // SFTP packets are framed with 32-bit big-endian length, which is the default of the codec
+    let mut reader = FramedRead::new(reader, LengthDelimitedCodec::new());
+    let mut writer = FramedWrite::new(writer, LengthDelimitedCodec::new());
+    let mut server = server::Server::default();
+
+    while let Some(frame) = reader.next().await {
+        let req = packet::Request::decode(&frame?).wrap_err("malformed packet received")?;
+        trace!(?req, "request received");
+        let res = server.handle(req).await;
+        trace!(?res, "sending response");
+        writer.send(Bytes::from(res.encode())).await?;
+    }
+
+    debug!("connection closed");
+    Ok(())
+}
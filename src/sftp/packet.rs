@@ -0,0 +1,592 @@
+use crate::prelude::*;
+use std::{
+    ffi::OsString,
+    fs::Metadata,
+    os::unix::{
+        ffi::{OsStrExt as _, OsStringExt as _},
+        fs::MetadataExt as _,
+    },
+    path::PathBuf,
+};
+
+pub(super) const VERSION: u32 = 3;
+
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_WRITE: u8 = 6;
+const SSH_FXP_LSTAT: u8 = 7;
+const SSH_FXP_FSTAT: u8 = 8;
+const SSH_FXP_SETSTAT: u8 = 9;
+const SSH_FXP_FSETSTAT: u8 = 10;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_REMOVE: u8 = 13;
+const SSH_FXP_MKDIR: u8 = 14;
+const SSH_FXP_RMDIR: u8 = 15;
+const SSH_FXP_REALPATH: u8 = 16;
+const SSH_FXP_STAT: u8 = 17;
+const SSH_FXP_RENAME: u8 = 18;
+const SSH_FXP_READLINK: u8 = 19;
+const SSH_FXP_SYMLINK: u8 = 20;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+
+pub(super) const SSH_FXF_READ: u32 = 0x0000_0001;
+pub(super) const SSH_FXF_WRITE: u32 = 0x0000_0002;
+pub(super) const SSH_FXF_APPEND: u32 = 0x0000_0004;
+pub(super) const SSH_FXF_CREAT: u32 = 0x0000_0008;
+pub(super) const SSH_FXF_TRUNC: u32 = 0x0000_0010;
+pub(super) const SSH_FXF_EXCL: u32 = 0x0000_0020;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x0000_0001;
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x0000_0002;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x0000_0004;
+const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x0000_0008;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x8000_0000;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(super) enum StatusCode {
+    Ok = 0,
+    Eof = 1,
+    NoSuchFile = 2,
+    PermissionDenied = 3,
+    Failure = 4,
+    BadMessage = 5,
+    OpUnsupported = 8,
+}
+
+impl From<&io::Error> for StatusCode {
+    fn from(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => Self::NoSuchFile,
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied,
+            io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => Self::BadMessage,
+            _ => Self::Failure,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub(super) struct Attrs {
+    pub(super) size: Option<u64>,
+    pub(super) uid_gid: Option<(u32, u32)>,
+    pub(super) permissions: Option<u32>,
+    pub(super) atime_mtime: Option<(u32, u32)>,
+}
+
+impl From<&Metadata> for Attrs {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            size: Some(metadata.size()),
+            uid_gid: Some((metadata.uid(), metadata.gid())),
+            permissions: Some(metadata.mode()),
+            atime_mtime: Some((metadata.atime() as u32, metadata.mtime() as u32)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(super) struct NameEntry {
+    pub(super) filename: OsString,
+    pub(super) longname: OsString,
+    pub(super) attrs: Attrs,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(super) enum Request {
+    Init {
+        version: u32,
+    },
+    Open {
+        id: u32,
+        path: PathBuf,
+        pflags: u32,
+        attrs: Attrs,
+    },
+    Close {
+        id: u32,
+        handle: Vec<u8>,
+    },
+    Read {
+        id: u32,
+        handle: Vec<u8>,
+        offset: u64,
+        len: u32,
+    },
+    Write {
+        id: u32,
+        handle: Vec<u8>,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    Lstat {
+        id: u32,
+        path: PathBuf,
+    },
+    Fstat {
+        id: u32,
+        handle: Vec<u8>,
+    },
+    Setstat {
+        id: u32,
+        path: PathBuf,
+        attrs: Attrs,
+    },
+    Fsetstat {
+        id: u32,
+        handle: Vec<u8>,
+        attrs: Attrs,
+    },
+    Opendir {
+        id: u32,
+        path: PathBuf,
+    },
+    Readdir {
+        id: u32,
+        handle: Vec<u8>,
+    },
+    Remove {
+        id: u32,
+        path: PathBuf,
+    },
+    Mkdir {
+        id: u32,
+        path: PathBuf,
+        attrs: Attrs,
+    },
+    Rmdir {
+        id: u32,
+        path: PathBuf,
+    },
+    Realpath {
+        id: u32,
+        path: PathBuf,
+    },
+    Stat {
+        id: u32,
+        path: PathBuf,
+    },
+    Rename {
+        id: u32,
+        old_path: PathBuf,
+        new_path: PathBuf,
+    },
+    Readlink {
+        id: u32,
+        path: PathBuf,
+    },
+    Symlink {
+        id: u32,
+        target_path: PathBuf,
+        link_path: PathBuf,
+    },
+    Unsupported {
+        id: u32,
+        kind: u8,
+    },
+}
+
+impl Request {
+    pub(super) fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut buf = Decoder(buf);
+        let kind = buf.u8()?;
+        if kind == SSH_FXP_INIT {
+            let version = buf.u32()?;
+            // extension data sent by the client is ignored
+            return Ok(Self::Init { version });
+        }
+
+        let id = buf.u32()?;
+        let req = match kind {
+            SSH_FXP_OPEN => Self::Open {
+                id,
+                path: buf.path()?,
+                pflags: buf.u32()?,
+                attrs: buf.attrs()?,
+            },
+            SSH_FXP_CLOSE => Self::Close {
+                id,
+                handle: buf.string()?,
+            },
+            SSH_FXP_READ => Self::Read {
+                id,
+                handle: buf.string()?,
+                offset: buf.u64()?,
+                len: buf.u32()?,
+            },
+            SSH_FXP_WRITE => Self::Write {
+                id,
+                handle: buf.string()?,
+                offset: buf.u64()?,
+                data: buf.string()?,
+            },
+            SSH_FXP_LSTAT => Self::Lstat {
+                id,
+                path: buf.path()?,
+            },
+            SSH_FXP_FSTAT => Self::Fstat {
+                id,
+                handle: buf.string()?,
+            },
+            SSH_FXP_SETSTAT => Self::Setstat {
+                id,
+                path: buf.path()?,
+                attrs: buf.attrs()?,
+            },
+            SSH_FXP_FSETSTAT => Self::Fsetstat {
+                id,
+                handle: buf.string()?,
+                attrs: buf.attrs()?,
+            },
+            SSH_FXP_OPENDIR => Self::Opendir {
+                id,
+                path: buf.path()?,
+            },
+            SSH_FXP_READDIR => Self::Readdir {
+                id,
+                handle: buf.string()?,
+            },
+            SSH_FXP_REMOVE => Self::Remove {
+                id,
+                path: buf.path()?,
+            },
+            SSH_FXP_MKDIR => Self::Mkdir {
+                id,
+                path: buf.path()?,
+                attrs: buf.attrs()?,
+            },
+            SSH_FXP_RMDIR => Self::Rmdir {
+                id,
+                path: buf.path()?,
+            },
+            SSH_FXP_REALPATH => Self::Realpath {
+                id,
+                path: buf.path()?,
+            },
+            SSH_FXP_STAT => Self::Stat {
+                id,
+                path: buf.path()?,
+            },
+            SSH_FXP_RENAME => Self::Rename {
+                id,
+                old_path: buf.path()?,
+                new_path: buf.path()?,
+            },
+            SSH_FXP_READLINK => Self::Readlink {
+                id,
+                path: buf.path()?,
+            },
+            // OpenSSH sends the target path first, contrary to the draft.
+            // Follow OpenSSH, since it is the de facto standard implementation.
+            SSH_FXP_SYMLINK => Self::Symlink {
+                id,
+                target_path: buf.path()?,
+                link_path: buf.path()?,
+            },
+            kind => Self::Unsupported { id, kind },
+        };
+        Ok(req)
+    }
+
+    pub(super) fn id(&self) -> Option<u32> {
+        match self {
+            Self::Init { .. } => None,
+            Self::Open { id, .. }
+            | Self::Close { id, .. }
+            | Self::Read { id, .. }
+            | Self::Write { id, .. }
+            | Self::Lstat { id, .. }
+            | Self::Fstat { id, .. }
+            | Self::Setstat { id, .. }
+            | Self::Fsetstat { id, .. }
+            | Self::Opendir { id, .. }
+            | Self::Readdir { id, .. }
+            | Self::Remove { id, .. }
+            | Self::Mkdir { id, .. }
+            | Self::Rmdir { id, .. }
+            | Self::Realpath { id, .. }
+            | Self::Stat { id, .. }
+            | Self::Rename { id, .. }
+            | Self::Readlink { id, .. }
+            | Self::Symlink { id, .. }
+            | Self::Unsupported { id, .. } => Some(*id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(super) enum Response {
+    Version {
+        version: u32,
+    },
+    Status {
+        id: u32,
+        code: StatusCode,
+        message: String,
+    },
+    Handle {
+        id: u32,
+        handle: Vec<u8>,
+    },
+    Data {
+        id: u32,
+        data: Vec<u8>,
+    },
+    Name {
+        id: u32,
+        entries: Vec<NameEntry>,
+    },
+    Attrs {
+        id: u32,
+        attrs: Attrs,
+    },
+}
+
+impl Response {
+    pub(super) fn status(id: u32, code: StatusCode) -> Self {
+        let message = match code {
+            StatusCode::Ok => "Success",
+            StatusCode::Eof => "End of file",
+            StatusCode::NoSuchFile => "No such file",
+            StatusCode::PermissionDenied => "Permission denied",
+            StatusCode::Failure => "Failure",
+            StatusCode::BadMessage => "Bad message",
+            StatusCode::OpUnsupported => "Operation unsupported",
+        };
+        Self::Status {
+            id,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub(super) fn error(id: u32, e: &io::Error) -> Self {
+        Self::Status {
+            id,
+            code: e.into(),
+            message: e.to_string(),
+        }
+    }
+
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut buf = Encoder(vec![]);
+        match self {
+            Self::Version { version } => {
+                buf.u8(SSH_FXP_VERSION);
+                buf.u32(*version);
+            }
+            Self::Status { id, code, message } => {
+                buf.u8(SSH_FXP_STATUS);
+                buf.u32(*id);
+                buf.u32(*code as u32);
+                buf.string(message.as_bytes());
+                buf.string(b""); // language tag
+            }
+            Self::Handle { id, handle } => {
+                buf.u8(SSH_FXP_HANDLE);
+                buf.u32(*id);
+                buf.string(handle);
+            }
+            Self::Data { id, data } => {
+                buf.u8(SSH_FXP_DATA);
+                buf.u32(*id);
+                buf.string(data);
+            }
+            Self::Name { id, entries } => {
+                buf.u8(SSH_FXP_NAME);
+                buf.u32(*id);
+                buf.u32(entries.len() as u32);
+                for entry in entries {
+                    buf.string(entry.filename.as_bytes());
+                    buf.string(entry.longname.as_bytes());
+                    buf.attrs(&entry.attrs);
+                }
+            }
+            Self::Attrs { id, attrs } => {
+                buf.u8(SSH_FXP_ATTRS);
+                buf.u32(*id);
+                buf.attrs(attrs);
+            }
+        }
+        buf.0
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected end of packet",
+            ));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn string(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn path(&mut self) -> io::Result<PathBuf> {
+        Ok(OsString::from_vec(self.string()?).into())
+    }
+
+    fn attrs(&mut self) -> io::Result<Attrs> {
+        let flags = self.u32()?;
+        let mut attrs = Attrs::default();
+        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+            attrs.size = Some(self.u64()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            attrs.uid_gid = Some((self.u32()?, self.u32()?));
+        }
+        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(self.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+            attrs.atime_mtime = Some((self.u32()?, self.u32()?));
+        }
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            let count = self.u32()?;
+            for _ in 0..count {
+                let _type = self.string()?;
+                let _data = self.string()?;
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, n: u8) {
+        self.0.push(n);
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.0.extend_from_slice(&n.to_be_bytes());
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.0.extend_from_slice(&n.to_be_bytes());
+    }
+
+    fn string(&mut self, s: &[u8]) {
+        self.u32(s.len() as u32);
+        self.0.extend_from_slice(s);
+    }
+
+    fn attrs(&mut self, attrs: &Attrs) {
+        let mut flags = 0;
+        if attrs.size.is_some() {
+            flags |= SSH_FILEXFER_ATTR_SIZE;
+        }
+        if attrs.uid_gid.is_some() {
+            flags |= SSH_FILEXFER_ATTR_UIDGID;
+        }
+        if attrs.permissions.is_some() {
+            flags |= SSH_FILEXFER_ATTR_PERMISSIONS;
+        }
+        if attrs.atime_mtime.is_some() {
+            flags |= SSH_FILEXFER_ATTR_ACMODTIME;
+        }
+        self.u32(flags);
+        if let Some(size) = attrs.size {
+            self.u64(size);
+        }
+        if let Some((uid, gid)) = attrs.uid_gid {
+            self.u32(uid);
+            self.u32(gid);
+        }
+        if let Some(permissions) = attrs.permissions {
+            self.u32(permissions);
+        }
+        if let Some((atime, mtime)) = attrs.atime_mtime {
+            self.u32(atime);
+            self.u32(mtime);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_open() {
+        let mut buf = Encoder(vec![]);
+        buf.u8(SSH_FXP_OPEN);
+        buf.u32(42);
+        buf.string(b"/tmp/foo");
+        buf.u32(SSH_FXF_READ | SSH_FXF_WRITE);
+        buf.attrs(&Attrs {
+            permissions: Some(0o644),
+            ..Attrs::default()
+        });
+
+        assert_eq!(
+            Request::decode(&buf.0).unwrap(),
+            Request::Open {
+                id: 42,
+                path: "/tmp/foo".into(),
+                pflags: SSH_FXF_READ | SSH_FXF_WRITE,
+                attrs: Attrs {
+                    permissions: Some(0o644),
+                    ..Attrs::default()
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn decode_truncated_packet() {
+        let mut buf = Encoder(vec![]);
+        buf.u8(SSH_FXP_READ);
+        buf.u32(1);
+        buf.string(b"handle");
+        assert_eq!(
+            Request::decode(&buf.0).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn encode_status() {
+        let buf = Response::status(7, StatusCode::Eof).encode();
+        let mut expected = Encoder(vec![]);
+        expected.u8(SSH_FXP_STATUS);
+        expected.u32(7);
+        expected.u32(1);
+        expected.string(b"End of file");
+        expected.string(b"");
+        assert_eq!(buf, expected.0);
+    }
+}
@@ -0,0 +1,363 @@
+use super::packet::{
+    Attrs, NameEntry, Request, Response, StatusCode, SSH_FXF_APPEND, SSH_FXF_CREAT, SSH_FXF_EXCL,
+    SSH_FXF_READ, SSH_FXF_TRUNC, SSH_FXF_WRITE, VERSION,
+};
+use crate::{common::nix2io, prelude::*};
+use nix::{
+    sys::{
+        stat::{self as nix_stat, Mode},
+        time::{TimeSpec, TimeVal, TimeValLike as _},
+    },
+    unistd::{self, Gid, Uid},
+};
+use std::{
+    collections::HashMap,
+    env,
+    ffi::OsString,
+    fs::{DirBuilder, Metadata, Permissions},
+    io::SeekFrom,
+    os::unix::{
+        fs::{
+            DirBuilderExt as _, FileTypeExt as _, MetadataExt as _, OpenOptionsExt as _,
+            PermissionsExt as _,
+        },
+        io::AsRawFd as _,
+    },
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File, ReadDir},
+    task,
+};
+
+/// Maximum number of bytes returned by a single `SSH_FXP_READ` request.
+const MAX_READ_LEN: u32 = 64 * 1024;
+/// Maximum number of entries returned by a single `SSH_FXP_READDIR` request.
+const MAX_READDIR_ENTRIES: usize = 128;
+
+#[derive(Debug)]
+enum Handle {
+    File(File),
+    Dir(ReadDir),
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Server {
+    next_handle: u32,
+    handles: HashMap<Vec<u8>, Handle>,
+}
+
+impl Server {
+    pub(super) async fn handle(&mut self, req: Request) -> Response {
+        let id = match req.id() {
+            Some(id) => id,
+            None => {
+                debug!(?req, "client initialized");
+                return Response::Version { version: VERSION };
+            }
+        };
+
+        match self.dispatch(req).await {
+            Ok(res) => res,
+            Err(e) => {
+                debug!(%id, error = %e, "request failed");
+                Response::error(id, &e)
+            }
+        }
+    }
+
+    async fn dispatch(&mut self, req: Request) -> io::Result<Response> {
+        let res = match req {
+            Request::Init { .. } => unreachable!(),
+            Request::Open {
+                id,
+                path,
+                pflags,
+                attrs,
+            } => {
+                let mut options = std::fs::OpenOptions::new();
+                options
+                    .read(pflags & SSH_FXF_READ != 0)
+                    .write(pflags & SSH_FXF_WRITE != 0)
+                    .append(pflags & SSH_FXF_APPEND != 0)
+                    .truncate(pflags & SSH_FXF_TRUNC != 0)
+                    .mode(attrs.permissions.unwrap_or(0o666));
+                if pflags & SSH_FXF_CREAT != 0 {
+                    if pflags & SSH_FXF_EXCL != 0 {
+                        options.create_new(true);
+                    } else {
+                        options.create(true);
+                    }
+                }
+                let file = fs::OpenOptions::from(options).open(&path).await?;
+                let handle = self.insert_handle(Handle::File(file));
+                Response::Handle { id, handle }
+            }
+            Request::Close { id, handle } => {
+                let _ = self.handles.remove(&handle).ok_or_else(invalid_handle)?;
+                Response::status(id, StatusCode::Ok)
+            }
+            Request::Read {
+                id,
+                handle,
+                offset,
+                len,
+            } => {
+                let file = self.file(&handle)?;
+                let _ = file.seek(SeekFrom::Start(offset)).await?;
+                let mut data = vec![0; len.min(MAX_READ_LEN) as usize];
+                let n = file.read(&mut data).await?;
+                if n == 0 && !data.is_empty() {
+                    return Ok(Response::status(id, StatusCode::Eof));
+                }
+                data.truncate(n);
+                Response::Data { id, data }
+            }
+            Request::Write {
+                id,
+                handle,
+                offset,
+                data,
+            } => {
+                let file = self.file(&handle)?;
+                let _ = file.seek(SeekFrom::Start(offset)).await?;
+                file.write_all(&data).await?;
+                Response::status(id, StatusCode::Ok)
+            }
+            Request::Lstat { id, path } => {
+                let metadata = fs::symlink_metadata(path).await?;
+                Response::Attrs {
+                    id,
+                    attrs: (&metadata).into(),
+                }
+            }
+            Request::Fstat { id, handle } => {
+                let metadata = self.file(&handle)?.metadata().await?;
+                Response::Attrs {
+                    id,
+                    attrs: (&metadata).into(),
+                }
+            }
+            Request::Setstat { id, path, attrs } => {
+                set_path_attrs(&path, &attrs).await?;
+                Response::status(id, StatusCode::Ok)
+            }
+            Request::Fsetstat { id, handle, attrs } => {
+                set_file_attrs(self.file(&handle)?, &attrs).await?;
+                Response::status(id, StatusCode::Ok)
+            }
+            Request::Opendir { id, path } => {
+                let dir = fs::read_dir(path).await?;
+                let handle = self.insert_handle(Handle::Dir(dir));
+                Response::Handle { id, handle }
+            }
+            Request::Readdir { id, handle } => {
+                let dir = match self.handles.get_mut(&handle) {
+                    Some(Handle::Dir(dir)) => dir,
+                    _ => return Err(invalid_handle()),
+                };
+                let mut entries = vec![];
+                while entries.len() < MAX_READDIR_ENTRIES {
+                    let entry = match dir.next_entry().await? {
+                        Some(entry) => entry,
+                        None => break,
+                    };
+                    let metadata = entry.metadata().await?;
+                    let filename = entry.file_name();
+                    entries.push(NameEntry {
+                        longname: longname(&filename, &metadata),
+                        filename,
+                        attrs: (&metadata).into(),
+                    });
+                }
+                if entries.is_empty() {
+                    return Ok(Response::status(id, StatusCode::Eof));
+                }
+                Response::Name { id, entries }
+            }
+            Request::Remove { id, path } => {
+                fs::remove_file(path).await?;
+                Response::status(id, StatusCode::Ok)
+            }
+            Request::Mkdir { id, path, attrs } => {
+                let mode = attrs.permissions.unwrap_or(0o777);
+                task::spawn_blocking(move || DirBuilder::new().mode(mode).create(path))
+                    .await
+                    .map_err(io::Error::other)??;
+                Response::status(id, StatusCode::Ok)
+            }
+            Request::Rmdir { id, path } => {
+                fs::remove_dir(path).await?;
+                Response::status(id, StatusCode::Ok)
+            }
+            Request::Realpath { id, path } => {
+                let path = if path.as_os_str().is_empty() {
+                    env::current_dir()?
+                } else {
+                    fs::canonicalize(path).await?
+                };
+                Response::Name {
+                    id,
+                    entries: vec![name_only(path)],
+                }
+            }
+            Request::Stat { id, path } => {
+                let metadata = fs::metadata(path).await?;
+                Response::Attrs {
+                    id,
+                    attrs: (&metadata).into(),
+                }
+            }
+            Request::Rename {
+                id,
+                old_path,
+                new_path,
+            } => {
+                // SFTP v3 requires renaming to fail if the destination already exists
+                if fs::symlink_metadata(&new_path).await.is_ok() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        "destination already exists",
+                    ));
+                }
+                fs::rename(old_path, new_path).await?;
+                Response::status(id, StatusCode::Ok)
+            }
+            Request::Readlink { id, path } => {
+                let target = fs::read_link(path).await?;
+                Response::Name {
+                    id,
+                    entries: vec![name_only(target)],
+                }
+            }
+            Request::Symlink {
+                id,
+                target_path,
+                link_path,
+            } => {
+                fs::os::unix::symlink(target_path, link_path).await?;
+                Response::status(id, StatusCode::Ok)
+            }
+            Request::Unsupported { id, kind } => {
+                debug!(%id, %kind, "unsupported request");
+                Response::status(id, StatusCode::OpUnsupported)
+            }
+        };
+        Ok(res)
+    }
+
+    fn insert_handle(&mut self, handle: Handle) -> Vec<u8> {
+        let key = self.next_handle.to_be_bytes().to_vec();
+        self.next_handle = self.next_handle.wrapping_add(1);
+        let _ = self.handles.insert(key.clone(), handle);
+        key
+    }
+
+    fn file(&mut self, handle: &[u8]) -> io::Result<&mut File> {
+        match self.handles.get_mut(handle) {
+            Some(Handle::File(file)) => Ok(file),
+            _ => Err(invalid_handle()),
+        }
+    }
+}
+
+fn invalid_handle() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "invalid handle")
+}
+
+fn name_only(path: PathBuf) -> NameEntry {
+    NameEntry {
+        filename: path.clone().into_os_string(),
+        longname: path.into_os_string(),
+        attrs: Attrs::default(),
+    }
+}
+
+/// Formats an entry in the `ls -l` like style, which is displayed by `ls -l` of sftp clients.
+fn longname(filename: &OsString, metadata: &Metadata) -> OsString {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        'd'
+    } else if file_type.is_symlink() {
+        'l'
+    } else if file_type.is_char_device() {
+        'c'
+    } else if file_type.is_block_device() {
+        'b'
+    } else if file_type.is_fifo() {
+        'p'
+    } else if file_type.is_socket() {
+        's'
+    } else {
+        '-'
+    };
+    let mode = metadata.mode();
+    let mut perms = String::with_capacity(10);
+    perms.push(kind);
+    for (i, ch) in "rwxrwxrwx".chars().enumerate() {
+        perms.push(if mode & (0o400 >> i) != 0 { ch } else { '-' });
+    }
+
+    let mut longname = OsString::from(format!(
+        "{} {:>4} {:<8} {:<8} {:>8} ",
+        perms,
+        metadata.nlink(),
+        metadata.uid(),
+        metadata.gid(),
+        metadata.size()
+    ));
+    longname.push(filename);
+    longname
+}
+
+async fn set_path_attrs(path: &Path, attrs: &Attrs) -> io::Result<()> {
+    if let Some(size) = attrs.size {
+        let mut file = fs::OpenOptions::new().write(true).open(path).await?;
+        file.set_len(size).await?;
+    }
+    if let Some(mode) = attrs.permissions {
+        fs::set_permissions(path, Permissions::from_mode(mode)).await?;
+    }
+    let path = path.to_owned();
+    let (uid_gid, atime_mtime) = (attrs.uid_gid, attrs.atime_mtime);
+    task::spawn_blocking(move || {
+        if let Some((uid, gid)) = uid_gid {
+            unistd::chown(&path, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))
+                .map_err(nix2io)?;
+        }
+        if let Some((atime, mtime)) = atime_mtime {
+            let atime = TimeVal::seconds(atime.into());
+            let mtime = TimeVal::seconds(mtime.into());
+            nix_stat::utimes(&path, &atime, &mtime).map_err(nix2io)?;
+        }
+        Ok(())
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+async fn set_file_attrs(file: &mut File, attrs: &Attrs) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    if let Some(size) = attrs.size {
+        file.set_len(size).await?;
+    }
+    if let Some(mode) = attrs.permissions {
+        nix_stat::fchmod(fd, Mode::from_bits_truncate(mode)).map_err(nix2io)?;
+    }
+    // the task is awaited while `file` is borrowed, so `fd` stays open
+    let (uid_gid, atime_mtime) = (attrs.uid_gid, attrs.atime_mtime);
+    task::spawn_blocking(move || {
+        if let Some((uid, gid)) = uid_gid {
+            unistd::fchown(fd, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))
+                .map_err(nix2io)?;
+        }
+        if let Some((atime, mtime)) = atime_mtime {
+            let atime = TimeSpec::seconds(atime.into());
+            let mtime = TimeSpec::seconds(mtime.into());
+            nix_stat::futimens(fd, &atime, &mtime).map_err(nix2io)?;
+        }
+        Ok(())
+    })
+    .await
+    .map_err(io::Error::other)?
+}
@@ -40,10 +40,6 @@ impl RawMode {
         Self { fd, orig: None }
     }
 
-    pub(crate) fn is_raw_mode(&self) -> bool {
-        self.orig.is_some()
-    }
-
     pub(crate) fn enter(&mut self) -> Result<bool> {
         if self.orig.is_none() {
             let orig = Some(enter_raw_mode(self.fd)?);
@@ -66,7 +62,10 @@ impl RawMode {
 
 impl Drop for RawMode {
     fn drop(&mut self) {
-        self.leave().expect("failed to restore terminal mode");
+        // panicking here would abort the process during unwinding
+        if let Err(e) = self.leave() {
+            tracing::warn!(error = %e, "failed to restore terminal mode");
+        }
     }
 }
 
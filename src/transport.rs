@@ -0,0 +1,325 @@
+//! Transports to reach remote hosts.
+//!
+//! * `ssh://[user@]host[:port][/path/to/rsrs]`
+//! * `docker://container`
+//! * `k8s://[namespace/]pod[/container]`
+//! * `exec://[/path/to/rsrs]` (local subprocess)
+//! * `tcp://host:port`
+//!
+//! The peer of the TCP transport must speak the protocol of `rsrs daemon --as-leaf` (for `open`)
+//! or `rsrs remote` (for `login`) on the socket, e.g. `socat TCP-LISTEN:port EXEC:'rsrs remote'`.
+
+use crate::{prelude::*, Result};
+use std::{env, ffi::OsString, net, path::PathBuf};
+use tokio::{net::TcpStream, task};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Transport {
+    Ssh {
+        destination: String,
+        port: Option<u16>,
+        program: Option<String>,
+    },
+    Docker {
+        container: String,
+    },
+    Kubernetes {
+        namespace: Option<String>,
+        pod: String,
+        container: Option<String>,
+    },
+    Exec {
+        program: Option<PathBuf>,
+    },
+    Tcp {
+        addr: String,
+    },
+}
+
+impl Transport {
+    /// Parses a transport URL. Returns `None` if `s` is not a URL of a known transport.
+    pub(crate) fn parse(s: &str) -> Result<Option<Self>> {
+        let (scheme, rest) = match s.split_once("://") {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let transport = match scheme {
+            "ssh" => {
+                let (authority, program) = match rest.find('/') {
+                    Some(idx) => (&rest[..idx], Some(rest[idx..].to_owned())),
+                    None => (rest, None),
+                };
+                let (user, host_port) = match authority.rsplit_once('@') {
+                    Some((user, host_port)) => (Some(user), host_port),
+                    None => (None, authority),
+                };
+                let (host, port) = match host_port.strip_prefix('[') {
+                    // IPv6 address
+                    Some(bracketed) => {
+                        let (host, port) = bracketed
+                            .split_once(']')
+                            .ok_or_else(|| eyre!("invalid host: {}", s))?;
+                        match port {
+                            "" => (host, None),
+                            _ => match port.strip_prefix(':') {
+                                Some(port) => (host, Some(port)),
+                                None => bail!("invalid host: {}", s),
+                            },
+                        }
+                    }
+                    // an IPv6 address without brackets has no port
+                    None if host_port.matches(':').count() > 1 => (host_port, None),
+                    None => match host_port.split_once(':') {
+                        Some((host, port)) => (host, Some(port)),
+                        None => (host_port, None),
+                    },
+                };
+                ensure!(!host.is_empty(), "host is not specified: {}", s);
+                let port = port
+                    .map(|port| {
+                        port.parse()
+                            .wrap_err_with(|| format!("invalid port number: {}", s))
+                    })
+                    .transpose()?;
+                let destination = match user {
+                    Some(user) => format!("{}@{}", user, host),
+                    None => host.to_owned(),
+                };
+                Transport::Ssh {
+                    destination,
+                    port,
+                    program,
+                }
+            }
+            "docker" => {
+                ensure!(!rest.is_empty(), "container is not specified: {}", s);
+                Transport::Docker {
+                    container: rest.to_owned(),
+                }
+            }
+            "k8s" => {
+                let parts = rest.split('/').collect::<Vec<_>>();
+                ensure!(
+                    parts.iter().all(|part| !part.is_empty()),
+                    "invalid pod: {}",
+                    s
+                );
+                let (namespace, pod, container) = match parts[..] {
+                    [pod] => (None, pod, None),
+                    [namespace, pod] => (Some(namespace), pod, None),
+                    [namespace, pod, container] => (Some(namespace), pod, Some(container)),
+                    _ => bail!("invalid pod: {}", s),
+                };
+                Transport::Kubernetes {
+                    namespace: namespace.map(Into::into),
+                    pod: pod.into(),
+                    container: container.map(Into::into),
+                }
+            }
+            "exec" => Transport::Exec {
+                program: Some(rest).filter(|s| !s.is_empty()).map(Into::into),
+            },
+            "tcp" => {
+                ensure!(rest.contains(':'), "port is not specified: {}", s);
+                Transport::Tcp {
+                    addr: rest.to_owned(),
+                }
+            }
+            // not a URL, e.g. a command line containing "://"
+            _ => return Ok(None),
+        };
+        Ok(Some(transport))
+    }
+
+    /// Returns the command line which launches `rsrs <args>` through the transport.
+    ///
+    /// If `bootstrap` is `true`, the command line launches a shell which installs rsrs instead.
+    pub(crate) fn command(&self, args: &[&str], bootstrap: bool) -> Result<Vec<OsString>> {
+        let remote = |program: Option<&str>| -> Vec<OsString> {
+            if bootstrap {
+                vec!["sh".into()]
+            } else {
+                let program = program.unwrap_or("rsrs");
+                std::iter::once(program)
+                    .chain(args.iter().copied())
+                    .map(Into::into)
+                    .collect()
+            }
+        };
+
+        let mut command = Vec::<OsString>::new();
+        match self {
+            Transport::Ssh {
+                destination,
+                port,
+                program,
+            } => {
+                command.extend(["ssh", "-T"].iter().map(Into::into));
+                if let Some(port) = port {
+                    command.extend(vec!["-p".into(), port.to_string().into()]);
+                }
+                command.push(destination.into());
+                command.extend(remote(program.as_deref()));
+            }
+            Transport::Docker { container } => {
+                command.extend(["docker", "exec", "-i"].iter().map(Into::into));
+                command.push(container.into());
+                command.extend(remote(None));
+            }
+            Transport::Kubernetes {
+                namespace,
+                pod,
+                container,
+            } => {
+                command.extend(["kubectl", "exec", "-i"].iter().map(Into::into));
+                if let Some(namespace) = namespace {
+                    command.extend(vec!["-n".into(), namespace.into()]);
+                }
+                command.push(pod.into());
+                if let Some(container) = container {
+                    command.extend(vec!["-c".into(), container.into()]);
+                }
+                command.push("--".into());
+                command.extend(remote(None));
+            }
+            Transport::Exec { program } => {
+                if bootstrap {
+                    command.extend(remote(None));
+                } else {
+                    let program = match program {
+                        Some(program) => program.clone(),
+                        None => env::current_exe()?,
+                    };
+                    command.push(program.into());
+                    command.extend(args.iter().map(Into::into));
+                }
+            }
+            Transport::Tcp { addr } => bail!("tcp transport cannot run commands: {}", addr),
+        }
+        Ok(command)
+    }
+}
+
+/// Opens a TCP connection to `addr`.
+///
+/// The connection is established by `std` on a blocking thread, since the socket address
+/// conversion in mio 0.6 is broken on recent Rust toolchains.
+pub(crate) async fn connect_tcp(addr: &str) -> Result<TcpStream> {
+    let stream = task::spawn_blocking({
+        let addr = addr.to_owned();
+        move || net::TcpStream::connect(addr)
+    })
+    .await?
+    .wrap_err_with(|| format!("failed to connect to {}", addr))?;
+    stream.set_nonblocking(true)?;
+    Ok(TcpStream::from_std(stream)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ssh(destination: &str, port: Option<u16>, program: Option<&str>) -> Option<Transport> {
+        Some(Transport::Ssh {
+            destination: destination.into(),
+            port,
+            program: program.map(Into::into),
+        })
+    }
+
+    #[test]
+    fn parse_ssh() {
+        assert_eq!(
+            Transport::parse("ssh://host").unwrap(),
+            ssh("host", None, None)
+        );
+        assert_eq!(
+            Transport::parse("ssh://user@host:22/path").unwrap(),
+            ssh("user@host", Some(22), Some("/path"))
+        );
+        assert_eq!(
+            Transport::parse("ssh://[::1]").unwrap(),
+            ssh("::1", None, None)
+        );
+        assert_eq!(
+            Transport::parse("ssh://user@[::1]:2222").unwrap(),
+            ssh("user@::1", Some(2222), None)
+        );
+        assert_eq!(
+            Transport::parse("ssh://::1").unwrap(),
+            ssh("::1", None, None)
+        );
+        assert!(Transport::parse("ssh://").is_err());
+        assert!(Transport::parse("ssh://host:port").is_err());
+        assert!(Transport::parse("ssh://[::1").is_err());
+    }
+
+    #[test]
+    fn parse_kubernetes() {
+        let k8s = |namespace: Option<&str>, pod: &str, container: Option<&str>| {
+            Some(Transport::Kubernetes {
+                namespace: namespace.map(Into::into),
+                pod: pod.into(),
+                container: container.map(Into::into),
+            })
+        };
+        assert_eq!(
+            Transport::parse("k8s://pod").unwrap(),
+            k8s(None, "pod", None)
+        );
+        assert_eq!(
+            Transport::parse("k8s://ns/pod/c").unwrap(),
+            k8s(Some("ns"), "pod", Some("c"))
+        );
+        assert!(Transport::parse("k8s://ns//c").is_err());
+        assert!(Transport::parse("k8s://a/b/c/d").is_err());
+    }
+
+    #[test]
+    fn parse_non_url() {
+        assert_eq!(Transport::parse("ssh").unwrap(), None);
+        assert_eq!(Transport::parse("://host").unwrap(), None);
+        assert_eq!(Transport::parse("curl://example.com").unwrap(), None);
+        assert_eq!(Transport::parse("http://example.com").unwrap(), None);
+        assert!(Transport::parse("docker://").is_err());
+        assert!(Transport::parse("tcp://host").is_err());
+    }
+
+    #[test]
+    fn command_line() {
+        let transport = Transport::parse("k8s://ns/pod/c").unwrap().unwrap();
+        assert_eq!(
+            transport.command(&["daemon", "--as-leaf"], false).unwrap(),
+            [
+                "kubectl",
+                "exec",
+                "-i",
+                "-n",
+                "ns",
+                "pod",
+                "-c",
+                "c",
+                "--",
+                "rsrs",
+                "daemon",
+                "--as-leaf"
+            ]
+        );
+        let transport = Transport::parse("ssh://user@host:22/opt/rsrs")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            transport.command(&["remote"], false).unwrap(),
+            ["ssh", "-T", "-p", "22", "user@host", "/opt/rsrs", "remote"]
+        );
+        assert_eq!(
+            transport.command(&["remote"], true).unwrap(),
+            ["ssh", "-T", "-p", "22", "user@host", "sh"]
+        );
+        assert!(Transport::parse("tcp://host:1")
+            .unwrap()
+            .unwrap()
+            .command(&[], false)
+            .is_err());
+    }
+}
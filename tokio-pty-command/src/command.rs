@@ -3,7 +3,7 @@ use nix::{libc, unistd};
 use std::{
     fs::OpenOptions,
     future::Future,
-    os::unix::fs::OpenOptionsExt,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
     pin::Pin,
     process::ExitStatus,
     task::{Context, Poll},
@@ -12,6 +12,12 @@ use tokio::process;
 
 pub trait CommandExt {
     fn spawn_with_pty(&mut self, pty_master: &PtyMaster) -> Result<Child>;
+
+    /// Makes the pseudo-terminal the controlling terminal of the spawned process.
+    ///
+    /// Unlike `spawn_with_pty`, the standard streams of the process are not connected to the
+    /// pseudo-terminal.
+    fn controlling_terminal(&mut self, pty_master: &PtyMaster) -> Result<&mut Self>;
 }
 
 impl CommandExt for process::Command {
@@ -36,6 +42,24 @@ impl CommandExt for process::Command {
 
         Ok(Child(self.spawn()?))
     }
+
+    fn controlling_terminal(&mut self, pty_master: &PtyMaster) -> Result<&mut Self> {
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(pty_master.slave_name())?;
+
+        unsafe {
+            self.pre_exec(move || {
+                let _pid = unistd::setsid().map_err(nix2io)?;
+                ioctl::tiocsctty(slave.as_raw_fd(), 1).map_err(nix2io)?;
+                Ok(())
+            });
+        }
+
+        Ok(self)
+    }
 }
 
 #[must_use = "futures do nothing unless polled"]
@@ -17,7 +17,7 @@ impl PtyMaster {
     }
 
     pub fn slave_name(&self) -> &str {
-        &self.0.get_ref().slave_name()
+        self.0.get_ref().slave_name()
     }
 }
 